@@ -0,0 +1,165 @@
+//! Errors and shared helpers for decoding what comes over the wire.
+//! Everything read from a socket goes through these, a bad frame must never
+//! take the process down.
+use std::{
+    fmt,
+    io::{self, Read},
+};
+
+use byteorder::ReadBytesExt;
+
+use crate::pid::Pid;
+
+/// No sane PID gets anywhere near this deep
+pub const MAX_PID_DEPTH: usize = 128;
+/// Upper bound on element counts announced in a frame, so we don't allocate
+/// whatever a broken peer asks for
+pub const MAX_COUNT: u64 = 1 << 24;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    UnknownTag(u8),
+    /// The frame ended in the middle of a message
+    Truncated,
+    BadUtf8,
+    PidTooDeep(usize),
+    OversizedCount(u64),
+    /// Varint longer than the type it encodes
+    BadVarint,
+}
+
+pub type DecodeResult<T> = Result<T, DecodeError>;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownTag(tag) => write!(f, "unknown message tag {}", tag),
+            DecodeError::Truncated => write!(f, "truncated frame"),
+            DecodeError::BadUtf8 => write!(f, "invalid UTF-8"),
+            DecodeError::PidTooDeep(depth) => {
+                write!(f, "pid depth {} exceeds {}", depth, MAX_PID_DEPTH)
+            }
+            DecodeError::OversizedCount(n) => write!(f, "count {} exceeds {}", n, MAX_COUNT),
+            DecodeError::BadVarint => write!(f, "malformed varint"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::InvalidData => DecodeError::BadVarint,
+            _ => DecodeError::Truncated,
+        }
+    }
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+pub fn check_count(n: u64) -> DecodeResult<usize> {
+    if n > MAX_COUNT {
+        return Err(DecodeError::OversizedCount(n));
+    }
+    Ok(n as usize)
+}
+
+/// Reads an atom's data: u8 length followed by that many bytes of a single UTF-8 char.
+pub fn read_char<R: Read>(reader: &mut R) -> DecodeResult<char> {
+    let data_len = reader.read_u8()? as usize;
+    if data_len == 0 || data_len > 4 {
+        return Err(DecodeError::BadUtf8);
+    }
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes[..data_len])?;
+    let mut chars = std::str::from_utf8(&bytes[..data_len])
+        .map_err(|_| DecodeError::BadUtf8)?
+        .chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(DecodeError::BadUtf8),
+    }
+}
+
+/// Reads a u8 depth followed by the PID's positions.
+pub fn read_pid<R: Read>(reader: &mut R) -> DecodeResult<Pid> {
+    let depth = reader.read_u8()? as usize;
+    Pid::read_bytes(reader, depth)
+}
+
+/// Reads bytes up to a `\n` (dropped) or the end of the frame.
+pub fn read_line<R: io::BufRead>(reader: &mut R) -> DecodeResult<String> {
+    let mut buf = Vec::new();
+    reader.read_until(b'\n', &mut buf)?;
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    String::from_utf8(buf).map_err(|_| DecodeError::BadUtf8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn rejects_pids_deeper_than_the_limit() {
+        let mut buf = vec![(MAX_PID_DEPTH + 1) as u8];
+        buf.extend(std::iter::repeat_n(1, 5 * (MAX_PID_DEPTH + 1)));
+        assert!(matches!(
+            read_pid(&mut Cursor::new(&buf)),
+            Err(DecodeError::PidTooDeep(d)) if d == MAX_PID_DEPTH + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_counts() {
+        assert_eq!(check_count(MAX_COUNT).unwrap(), MAX_COUNT as usize);
+        assert!(matches!(
+            check_count(MAX_COUNT + 1),
+            Err(DecodeError::OversizedCount(n)) if n == MAX_COUNT + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_atoms_that_are_not_one_char() {
+        let bad: [&[u8]; 6] = [
+            // No data
+            &[0],
+            &[5, b'a', b'b', b'c', b'd', b'e'],
+            // Never valid in UTF-8
+            &[1, 0xff],
+            // Lone continuation byte
+            &[1, 0x80],
+            // Surrogate half
+            &[3, 0xed, 0xa0, 0x80],
+            &[2, b'a', b'b'],
+        ];
+        for bytes in bad {
+            let decoded = read_char(&mut Cursor::new(bytes));
+            assert!(matches!(decoded, Err(DecodeError::BadUtf8)), "{:?}", bytes);
+        }
+        assert!(matches!(
+            read_char(&mut Cursor::new(&[2, 0xc5])),
+            Err(DecodeError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn rejects_names_that_are_not_utf8() {
+        assert!(matches!(
+            read_line(&mut Cursor::new(b"no\xfftes\n")),
+            Err(DecodeError::BadUtf8)
+        ));
+        assert_eq!(read_line(&mut Cursor::new("żółw\nrest")).unwrap(), "żółw");
+    }
+}
@@ -10,18 +10,80 @@ use std::{
 
 use crate::{
     LBASE,
-    martree::{MarTree, Measured},
-    pid::{Pid, generate_between_pids},
-    pos::Pos,
+    decode::{DecodeResult, read_char, read_pid},
+    martree::{Dimension, MarTree, Measured, Summarize, Summary},
+    pid::{AllocationStrategy, Pid, Strategy},
+    pos::{DEFAULT_SITE, Pos, SENTINEL_SITE, SiteId, UNLEASED_SITE},
 };
 
 /// A wrapper around char that measures its UTF-8 byte length.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct DocChar(pub char);
 
+/// What the tree keeps per subtree besides the byte count, to answer the
+/// positions editors use: Neovim's (line, byte column) and UTF-16 offsets on
+/// the Kotlin side.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextSummary {
+    pub bytes: usize,
+    pub utf16: usize,
+    pub newlines: usize,
+    pub chars: usize,
+}
+
+impl Summary for TextSummary {
+    fn add(&mut self, other: &Self) {
+        self.bytes += other.bytes;
+        self.utf16 += other.utf16;
+        self.newlines += other.newlines;
+        self.chars += other.chars;
+    }
+    fn sub(&mut self, other: &Self) {
+        self.bytes -= other.bytes;
+        self.utf16 -= other.utf16;
+        self.newlines -= other.newlines;
+        self.chars -= other.chars;
+    }
+}
+
+/// UTF-8 bytes
+pub struct Bytes;
+/// UTF-16 code units
+pub struct Utf16;
+/// Newlines, so the line a position is on
+pub struct Lines;
+/// Chars, the same as the entry count
+pub struct Chars;
+
+impl Dimension<TextSummary> for Bytes {
+    fn measure(summary: &TextSummary) -> usize {
+        summary.bytes
+    }
+}
+impl Dimension<TextSummary> for Utf16 {
+    fn measure(summary: &TextSummary) -> usize {
+        summary.utf16
+    }
+}
+impl Dimension<TextSummary> for Lines {
+    fn measure(summary: &TextSummary) -> usize {
+        summary.newlines
+    }
+}
+impl Dimension<TextSummary> for Chars {
+    fn measure(summary: &TextSummary) -> usize {
+        summary.chars
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Doc {
-    pub content: MarTree<Pid, DocChar>,
+    pub content: MarTree<Pid, DocChar, TextSummary>,
+    /// Site stamped on the PIDs generated by this replica, `UNLEASED_SITE`
+    /// until the server leased one
+    pub site: SiteId,
+    /// How new PIDs are placed between their neighbours, Logoot by default
+    pub strategy: Strategy,
 }
 
 impl Default for Doc {
@@ -36,56 +98,72 @@ impl Measured for DocChar {
     }
 }
 
+impl Summarize<TextSummary> for DocChar {
+    fn summarize(&self) -> TextSummary {
+        TextSummary {
+            bytes: self.0.len_utf8(),
+            utf16: self.0.len_utf16(),
+            newlines: usize::from(self.0 == '\n'),
+            chars: 1,
+        }
+    }
+}
+
 impl Doc {
     pub fn new(content: &str) -> Doc {
-        let beg = (Pid(vec![Pos { ident: 0, site: 0 }]), DocChar('_'));
+        let beg = (
+            Pid(vec![Pos {
+                ident: 0,
+                site: SENTINEL_SITE,
+            }]),
+            DocChar('_'),
+        );
         let end = (
             Pid(vec![Pos {
                 ident: LBASE,
-                site: 0,
+                site: SENTINEL_SITE,
             }]),
             DocChar('_'),
         );
 
-        let mut d = Doc {
-            content: MarTree::from_iter([beg.clone(), end.clone()]),
-        };
-        if content.is_empty() {
-            return d;
-        }
-        let step = (u32::MAX as usize) / content.len();
-        for (i, c) in content.chars().enumerate() {
-            d.content.insert(
+        let step = (u32::MAX as usize) / content.len().max(1);
+        let chars = content.chars().enumerate().map(|(i, c)| {
+            (
                 Pid(vec![Pos {
                     ident: (i * step) as u32,
-                    site: 1,
+                    site: DEFAULT_SITE,
                 }]),
                 DocChar(c),
-            );
+            )
+        });
+        Doc {
+            content: MarTree::from_sorted_iter(
+                std::iter::once(beg)
+                    .chain(chars)
+                    .chain(std::iter::once(end)),
+            ),
+            site: UNLEASED_SITE,
+            strategy: Strategy::default(),
         }
-        d
     }
+    /// The PID `offset` characters right of `pid` (left if negative), None if
+    /// `pid` isn't in the document or the move would leave it.
     pub fn offset(&self, pid: &Pid, offset: isize) -> Option<Pid> {
-        return None;
-        // if offset == 0 {
-        //     return Some(pid.clone());
-        // }
-        //
-        // if offset > 0 {
-        //     // move right
-        //     self.content
-        //         .range(pid..) // start at pid
-        //         .skip(1) // skip self
-        //         .nth((offset - 1) as usize)
-        //         .map(|(k, _)| k.clone())
-        // } else {
-        //     // move left
-        //     self.content
-        //         .range(..pid) // all before pid
-        //         .rev() // backwards
-        //         .nth((-offset - 1) as usize)
-        //         .map(|(k, _)| k.clone())
-        // }
+        let mut cursor = self.content.cursor_at(pid);
+        if cursor.get().map(|(k, _)| k) != Some(pid) {
+            return None;
+        }
+        for _ in 0..offset.unsigned_abs() {
+            let moved = if offset > 0 {
+                cursor.move_next()
+            } else {
+                cursor.move_prev()
+            };
+            if !moved {
+                return None;
+            }
+        }
+        cursor.get().map(|(k, _)| k.clone())
     }
     // pub fn keys(&self) -> Vec<Pid> {
     //     self.content.keys().cloned().collect()
@@ -138,27 +216,36 @@ impl Doc {
         Ok(())
     }
 
-    pub fn from_reader<R: Read>(reader: &mut R, n: usize) -> Self {
-        let mut content = MarTree::default();
+    pub fn from_reader<R: Read>(reader: &mut R, n: usize) -> DecodeResult<Self> {
+        // Atoms are written in PID order, so the tree can be packed directly
+        let mut atoms = Vec::with_capacity(n.min(1 << 20));
 
         for _ in 0..n {
-            let data_len = reader.read_u8().unwrap() as usize;
-            let mut bytes = [0u8; 4];
-            reader.read_exact(&mut bytes[..data_len]).unwrap();
-            let data = std::str::from_utf8(&bytes[..data_len])
-                .unwrap()
-                .chars()
-                .next()
-                .unwrap();
-            let pid_depth = reader.read_u8().unwrap();
-            let pid = Pid::read_bytes(reader, pid_depth.into());
-            content.insert(pid, DocChar(data));
+            let data = read_char(reader)?;
+            let pid = read_pid(reader)?;
+            atoms.push((pid, DocChar(data)));
         }
 
-        Doc { content }
+        Ok(Doc {
+            content: MarTree::from_sorted_iter(atoms),
+            site: UNLEASED_SITE,
+            strategy: Strategy::default(),
+        })
     }
     pub fn from_reader_eof<R: Read>(reader: &mut R) -> Result<Self> {
-        let mut content = MarTree::default();
+        Self::from_reader_eof_with(reader, Pid::read_bytes)
+    }
+
+    /// Like `from_reader_eof`, but for atoms written before sites were widened.
+    pub fn from_reader_eof_v1<R: Read>(reader: &mut R) -> Result<Self> {
+        Self::from_reader_eof_with(reader, Pid::read_bytes_v1)
+    }
+
+    fn from_reader_eof_with<R: Read>(
+        reader: &mut R,
+        read_pid: fn(&mut R, usize) -> DecodeResult<Pid>,
+    ) -> Result<Self> {
+        let mut atoms = Vec::new();
 
         loop {
             let data_len = match reader.read_u8() {
@@ -188,12 +275,16 @@ impl Doc {
                 Err(e) => return Err(e).context("Failed to read pid depth"),
             };
 
-            let pid = Pid::read_bytes(reader, pid_depth.into());
+            let pid = read_pid(reader, pid_depth.into()).context("Failed to read pid")?;
 
-            content.insert(pid, DocChar(data));
+            atoms.push((pid, DocChar(data)));
         }
 
-        Ok(Doc { content })
+        Ok(Doc {
+            content: MarTree::from_sorted_iter(atoms),
+            site: UNLEASED_SITE,
+            strategy: Strategy::default(),
+        })
     }
 
     pub fn insert(&mut self, pid: Pid, c: DocChar) {
@@ -201,21 +292,21 @@ impl Doc {
     }
     pub fn insert_leftof(&mut self, pid: &Pid, c: DocChar) -> Pid {
         let right = &self.content.get_next(pid).unwrap().0;
-        let new = generate_between_pids(pid, &right, 1);
+        let new = self.strategy.generate_between(pid, right, self.site);
         self.content.insert(new.clone(), c);
         return new;
     }
     pub fn insert_at_idx(&mut self, idx: usize, c: DocChar) -> Pid {
         let left = &self.content.get_by_index(idx).unwrap().0;
         let right = &self.content.get_next(left).unwrap().0;
-        let new = generate_between_pids(left, right, 1);
+        let new = self.strategy.generate_between(left, right, self.site);
         self.content.insert(new.clone(), c);
         return new;
     }
     pub fn insert_at_bytepos(&mut self, pos: usize, c: DocChar) -> Pid {
         let left = &self.content.get_by_alt_size(pos).unwrap().0;
         let right = &self.content.get_next(left).unwrap().0;
-        let new = generate_between_pids(left, right, 1);
+        let new = self.strategy.generate_between(left, right, self.site);
         self.content.insert(new.clone(), c);
         return new;
     }
@@ -235,6 +326,149 @@ impl Doc {
         self.content.remove(pid);
     }
 
+    /// Moves every provisional atom to a fresh PID stamped with the current
+    /// site, at the same place in the text. Returns the (old, new) PIDs in
+    /// document order so queued ops can be rewritten.
+    pub fn restamp_provisional(&mut self) -> Vec<(Pid, Pid)> {
+        if self.site == UNLEASED_SITE {
+            return Vec::new();
+        }
+        let provisional: Vec<Pid> = self
+            .content
+            .iter()
+            .filter(|(pid, _)| pid.is_provisional())
+            .map(|(pid, _)| pid.clone())
+            .collect();
+
+        let mut renamed = Vec::with_capacity(provisional.len());
+        for old in provisional {
+            let c = self.content.get(&old).unwrap().1;
+            let left = self.content.get_prev(&old).unwrap().0.clone();
+            let right = self.content.get_next(&old).unwrap().0.clone();
+            self.content.remove(&old);
+            let new = self.strategy.generate_between(&left, &right, self.site);
+            self.content.insert(new.clone(), c);
+            renamed.push((old, new));
+        }
+        renamed
+    }
+
+    // Positions below are in the text as an editor sees it, without the begin
+    // sentinel, which the tree counts as 1 byte, 1 UTF-16 unit and 1 char.
+
+    /// UTF-16 offset of the position at byte offset `byte`.
+    pub fn byte_to_utf16(&self, byte: usize) -> usize {
+        self.content.convert::<Bytes, Utf16>(byte + 1) - 1
+    }
+
+    /// Byte offset of the position at UTF-16 offset `utf16`. One inside a
+    /// surrogate pair maps to the end of the pair.
+    pub fn utf16_to_byte(&self, utf16: usize) -> usize {
+        self.content.convert::<Utf16, Bytes>(utf16 + 1) - 1
+    }
+
+    /// Byte offset at which line `line` (0-based) starts, None past the last line.
+    pub fn line_start(&self, line: usize) -> Option<usize> {
+        if line == 0 {
+            return Some(0);
+        }
+        let (_, prefix) = self.content.seek_by::<Lines>(line);
+        (prefix.newlines == line).then(|| prefix.bytes - 1)
+    }
+
+    /// Line (0-based) and byte column of the position at byte offset `byte`,
+    /// None past the end of the text.
+    pub fn byte_to_line_col(&self, byte: usize) -> Option<(usize, usize)> {
+        if byte + 2 > self.content.size_alt() {
+            return None;
+        }
+        let (_, prefix) = self.content.seek_by::<Bytes>(byte + 1);
+        let line = prefix.newlines;
+        Some((line, byte - self.line_start(line)?))
+    }
+
+    /// Byte offset of `col` bytes into line `line`, None if the line doesn't exist.
+    pub fn line_col_to_byte(&self, line: usize, col: usize) -> Option<usize> {
+        self.line_start(line).map(|start| start + col)
+    }
+
+    /// Number of lines, one more than the number of newlines.
+    pub fn line_count(&self) -> usize {
+        self.content.summary().newlines + 1
+    }
+
+    /// Byte offset of `col` bytes into line `line`, None unless it falls within
+    /// the line, its end included, and on a char boundary.
+    fn checked_line_col_to_byte(&self, line: usize, col: usize) -> Option<usize> {
+        let start = self.line_start(line)?;
+        let end = match self.line_start(line + 1) {
+            // Stop before the newline
+            Some(next) => next - 1,
+            None => self.content.size_alt() - 2,
+        };
+        let pos = start + col;
+        if pos > end {
+            return None;
+        }
+        // Seeking lands after the char covering the position, past it if inside one
+        let (_, prefix) = self.content.seek_by::<Bytes>(pos + 1);
+        (prefix.bytes == pos + 1).then_some(pos)
+    }
+
+    /// Insert `text` at byte column `col` of line `line`, None if there is no such position.
+    pub fn insert_at_line_col(
+        &mut self,
+        line: usize,
+        col: usize,
+        text: &str,
+    ) -> Option<Vec<(Pid, char)>> {
+        let pos = self.checked_line_col_to_byte(line, col)?;
+        Some(self.insert_text_at_bytepos(pos, text))
+    }
+
+    /// Delete the text from (`start_line`, `start_col`) up to (`end_line`, `end_col`),
+    /// the way editors report edits. None if either end is not a position in the text.
+    pub fn delete_line_col_range(
+        &mut self,
+        start_line: usize,
+        start_col: usize,
+        end_line: usize,
+        end_col: usize,
+    ) -> Option<Vec<Pid>> {
+        let start = self.checked_line_col_to_byte(start_line, start_col)?;
+        let end = self.checked_line_col_to_byte(end_line, end_col)?;
+        if end < start {
+            return None;
+        }
+        Some(self.delete_byte_range(start, end - start))
+    }
+
+    /// Line and byte column at which `pid` starts, None for the sentinels or a missing pid.
+    pub fn line_col_of_pid(&self, pid: &Pid) -> Option<(usize, usize)> {
+        let alt = self.content.alt_offset_of(pid)?;
+        if alt == 0 || alt + 1 >= self.content.size_alt() {
+            return None;
+        }
+        self.byte_to_line_col(alt - 1)
+    }
+
+    /// Pid of the char covering byte column `col` of line `line`, newline included.
+    pub fn pid_at_line_col(&self, line: usize, col: usize) -> Option<Pid> {
+        let pos = self.checked_line_col_to_byte(line, col)?;
+        // The end of a line is its newline, except on the last one where it's the end sentinel
+        if pos + 2 >= self.content.size_alt() {
+            return None;
+        }
+        self.content
+            .get_by_alt_size(pos + 1)
+            .map(|(pid, _)| pid.clone())
+    }
+
+    /// Byte offset of `pid` in the text as an editor sees it, without the begin sentinel.
+    pub fn byte_offset_of(&self, pid: &Pid) -> Option<usize> {
+        self.content.alt_offset_of(pid).map(|alt| alt - 1)
+    }
+
     pub fn delete_at_idx(&mut self, idx: usize) -> Pid {
         // TODO Avoid this clone by writing the function from scratch
         let key = self.content.get_by_index(idx + 1).unwrap().0.clone();
@@ -242,13 +476,13 @@ impl Doc {
         key
     }
     pub fn delete_byte_range(&mut self, start_byte: usize, len_byte: usize) -> Vec<Pid> {
-        let start_idx = self.content.alt_to_index(start_byte);
-        let end_idx = self.content.alt_to_index(start_byte+len_byte);
-        let mut deleted = Vec::with_capacity(end_idx-start_idx);
-        for i in (start_idx..end_idx).rev() {
-            deleted.push(self.delete_at_idx(i));
-        }
-        deleted
+        // Shift past the begin sentinel, and stop short of the end one
+        let end = (start_byte + len_byte + 1).min(self.content.size_alt() - 1);
+        self.content
+            .remove_range_by_alt(start_byte + 1..end)
+            .into_iter()
+            .map(|(pid, _)| pid)
+            .collect()
     }
 
     pub fn to_string(&self) -> String {
@@ -274,3 +508,119 @@ impl Doc {
         return self.content.size_alt();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restamping_keeps_the_text_and_leaves_nothing_provisional() {
+        let mut doc = Doc::new("ac");
+        let typed = doc.insert_text_at_bytepos(1, "b€");
+        assert!(typed.iter().all(|(pid, _)| pid.is_provisional()));
+
+        // Nothing to do until there's a lease
+        assert!(doc.restamp_provisional().is_empty());
+
+        doc.site = 5;
+        let renamed = doc.restamp_provisional();
+        assert_eq!(renamed.len(), 2);
+        assert_eq!(doc.to_string(), "ab€c");
+        for ((old, new), (typed, _)) in renamed.iter().zip(&typed) {
+            assert_eq!(old, typed);
+            assert_eq!(new.0.last().unwrap().site, 5);
+            assert!(doc.content.get(old).is_none());
+        }
+        assert!(doc.content.iter().all(|(pid, _)| !pid.is_provisional()));
+    }
+
+    #[test]
+    fn utf16_offsets_count_surrogate_pairs_twice() {
+        // 'a' is 1 byte, the emoji 4 bytes or 2 UTF-16 units, '€' 3 bytes or 1 unit
+        let doc = Doc::new("a😀€b");
+        for (byte, utf16) in [(0, 0), (1, 1), (5, 3), (8, 4), (9, 5)] {
+            assert_eq!(doc.byte_to_utf16(byte), utf16, "byte {}", byte);
+            assert_eq!(doc.utf16_to_byte(utf16), byte, "utf16 {}", utf16);
+        }
+        // Inside the pair maps to the end of it
+        assert_eq!(doc.utf16_to_byte(2), 5);
+    }
+
+    #[test]
+    fn bytes_map_to_lines_and_columns() {
+        let doc = Doc::new("ab\n😀c\n\nd");
+        let expected = [
+            (0, (0, 0)),
+            (2, (0, 2)),
+            (3, (1, 0)),
+            (7, (1, 4)),
+            (8, (1, 5)),
+            (9, (2, 0)),
+            (10, (3, 0)),
+            (11, (3, 1)),
+        ];
+        for (byte, line_col) in expected {
+            assert_eq!(doc.byte_to_line_col(byte), Some(line_col), "byte {}", byte);
+        }
+        assert_eq!(doc.byte_to_line_col(12), None);
+        assert_eq!(Doc::new("").byte_to_line_col(0), Some((0, 0)));
+    }
+
+    #[test]
+    fn line_col_edits_stay_within_lines_and_chars() {
+        let mut empty = Doc::new("");
+        assert_eq!(empty.line_count(), 1);
+        assert!(empty.insert_at_line_col(0, 1, "x").is_none());
+        assert!(empty.insert_at_line_col(1, 0, "x").is_none());
+        assert_eq!(empty.insert_at_line_col(0, 0, "x").unwrap().len(), 1);
+        assert_eq!(empty.to_string(), "x");
+
+        // The last line has no trailing newline, its end is the end of the text
+        let mut doc = Doc::new("ab\n€d");
+        assert_eq!(doc.line_count(), 2);
+        assert!(doc.insert_at_line_col(1, 5, "!").is_none());
+        assert!(doc.insert_at_line_col(1, 4, "!").is_some());
+        assert_eq!(doc.to_string(), "ab\n€d!");
+
+        // The end of a line is right before its newline
+        assert!(doc.insert_at_line_col(0, 3, "?").is_none());
+        assert!(doc.insert_at_line_col(0, 2, "?").is_some());
+        assert_eq!(doc.to_string(), "ab?\n€d!");
+
+        // '€' takes 3 bytes, columns 1 and 2 are inside it
+        for col in [1, 2] {
+            assert!(doc.insert_at_line_col(1, col, "x").is_none(), "col {}", col);
+            assert!(doc.delete_line_col_range(1, 0, 1, col).is_none());
+            assert!(doc.delete_line_col_range(1, col, 1, 4).is_none());
+        }
+        assert_eq!(doc.to_string(), "ab?\n€d!");
+
+        // Inverted ranges are rejected, across lines too
+        assert!(doc.delete_line_col_range(1, 3, 1, 0).is_none());
+        assert!(doc.delete_line_col_range(1, 0, 0, 1).is_none());
+        assert_eq!(doc.to_string(), "ab?\n€d!");
+
+        assert_eq!(doc.delete_line_col_range(0, 3, 1, 3).unwrap().len(), 2);
+        assert_eq!(doc.to_string(), "ab?d!");
+        assert!(doc.delete_line_col_range(0, 2, 0, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pids_map_to_the_line_and_col_they_start_at() {
+        let doc = Doc::new("a\n😀b");
+        let pids: Vec<Pid> = doc.content.iter().map(|(pid, _)| pid.clone()).collect();
+        // The sentinels aren't positions in the text
+        assert_eq!(doc.line_col_of_pid(&pids[0]), None);
+        assert_eq!(doc.line_col_of_pid(pids.last().unwrap()), None);
+
+        let expected = [(0, 0), (0, 1), (1, 0), (1, 4)];
+        for (pid, line_col) in pids[1..pids.len() - 1].iter().zip(expected) {
+            assert_eq!(doc.line_col_of_pid(pid), Some(line_col));
+        }
+
+        let mut other = Doc::new("");
+        other.site = 7;
+        let missing = other.insert_text_at_bytepos(0, "z").remove(0).0;
+        assert_eq!(doc.line_col_of_pid(&missing), None);
+    }
+}
@@ -6,5 +6,11 @@ pub mod session;
 pub mod sync;
 pub mod martree;
 pub mod structure;
+pub mod varint;
+pub mod decode;
 
 const LBASE: u32 = u32::MAX; // maximum identifier value
+
+/// Clients connect to this path on the server, it carries the wire protocol version.
+/// Version 2 widened the site of every PID position to a varint.
+pub const PROTOCOL_PATH: &str = "/v2";
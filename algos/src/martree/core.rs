@@ -1,18 +1,75 @@
+use std::ops::{Bound, RangeBounds};
+
 use crate::martree::node::{Node, T};
 
 pub trait Measured {
     fn measured(&self) -> usize;
 }
 
+/// A quantity aggregated over every subtree next to `size_alt`, so lookups by
+/// it are O(log n) as well. Nodes are patched by adding and subtracting the
+/// summaries of what moves in and out, so it has to be a commutative group.
+pub trait Summary: Clone + Default + PartialEq + std::fmt::Debug {
+    fn add(&mut self, other: &Self);
+    fn sub(&mut self, other: &Self);
+}
+
+/// Trees that need nothing beyond `size_alt` summarize to ().
+impl Summary for () {
+    fn add(&mut self, _: &Self) {}
+    fn sub(&mut self, _: &Self) {}
+}
+
+impl Summary for usize {
+    fn add(&mut self, other: &Self) {
+        *self += other;
+    }
+    fn sub(&mut self, other: &Self) {
+        *self -= other;
+    }
+}
+
+macro_rules! tuple_summary {
+    ($($name:ident $idx:tt),+) => {
+        impl<$($name: Summary),+> Summary for ($($name,)+) {
+            fn add(&mut self, other: &Self) {
+                $(self.$idx.add(&other.$idx);)+
+            }
+            fn sub(&mut self, other: &Self) {
+                $(self.$idx.sub(&other.$idx);)+
+            }
+        }
+    };
+}
+
+tuple_summary!(A 0, B 1);
+tuple_summary!(A 0, B 1, C 2);
+tuple_summary!(A 0, B 1, C 2, D 3);
+
+/// The summary of a single value.
+pub trait Summarize<S: Summary> {
+    fn summarize(&self) -> S;
+}
+
+impl<V> Summarize<()> for V {
+    fn summarize(&self) {}
+}
+
+/// One quantity read out of a summary, e.g. the newlines of a text summary.
+/// Prefixes of the tree must measure non-decreasing along it.
+pub trait Dimension<S> {
+    fn measure(summary: &S) -> usize;
+}
+
 #[derive(Debug, Clone)]
-pub struct MarTree<K, V>
+pub struct MarTree<K, V, S = ()>
 where
     K: Ord,
 {
-    pub(crate) root: Node<K, V>,
+    pub(crate) root: Node<K, V, S>,
 }
 
-impl<K: Ord, V: Measured> Default for MarTree<K, V> {
+impl<K: Ord, V: Measured + Summarize<S>, S: Summary> Default for MarTree<K, V, S> {
     fn default() -> Self {
         MarTree {
             root: Node::default(),
@@ -20,17 +77,19 @@ impl<K: Ord, V: Measured> Default for MarTree<K, V> {
     }
 }
 
-impl<K: Ord, V: Measured> MarTree<K, V> {
+impl<K: Ord, V: Measured + Summarize<S>, S: Summary> MarTree<K, V, S> {
     pub fn insert(&mut self, key: K, value: V) {
         if self.root.keys.len() >= T * 2 - 1 {
             let old_root = std::mem::take(&mut self.root);
             let s = old_root.recompute_size();
             let s_alt = old_root.recompute_size_alt();
+            let summary = old_root.summary.clone();
             let mut new_root = Node {
                 keys: Vec::new(),
                 children: vec![old_root],
                 size: s,
                 size_alt: s_alt,
+                summary,
                 is_leaf: false,
             };
             new_root.split_child(0);
@@ -48,7 +107,109 @@ impl<K: Ord, V: Measured> MarTree<K, V> {
         removed
     }
 
-    // TODO add options to remove by index/by size/by alt
+    /// Removes and returns the entry with the smallest key.
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        if self.root.size == 0 {
+            return None;
+        }
+        let first = self.root.pop_first();
+        if self.root.keys.is_empty() && !self.root.is_leaf {
+            self.root = self.root.children.remove(0);
+        }
+        Some(first)
+    }
+
+    /// Removes the entries whose index falls within `range`, returning them in
+    /// key order. Costs O(log n + k) for k removed entries.
+    pub fn remove_range_by_index<R: RangeBounds<usize>>(&mut self, range: R) -> Vec<(K, V)> {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.root.size,
+        }
+        .min(self.root.size);
+        if start >= end {
+            return Vec::new();
+        }
+
+        let mut tail = self.split_off_at_index(end);
+        let removed = self.split_off_at_index(start);
+        self.append(&mut tail);
+
+        let mut entries = Vec::with_capacity(end - start);
+        removed.root.drain_into(&mut entries);
+        entries
+    }
+
+    /// Removes every entry that covers at least one of the alt offsets within
+    /// `range`, returning them in key order.
+    pub fn remove_range_by_alt<R: RangeBounds<usize>>(&mut self, range: R) -> Vec<(K, V)> {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.root.size_alt,
+        };
+        if start >= end {
+            return Vec::new();
+        }
+        let start = self.alt_to_index(start);
+        let end = self.alt_to_index(end - 1) + 1;
+        self.remove_range_by_index(start..end)
+    }
+
+    /// Splits the tree at index `idx`: self keeps the entries before it and the
+    /// rest are returned, like `BTreeMap::split_off` but by position. O(log n).
+    pub fn split_off_at_index(&mut self, idx: usize) -> Self {
+        let idx = idx.min(self.root.size);
+        let root = std::mem::take(&mut self.root);
+        let height = root.height();
+        let ((left, _), (right, _)) = root.split_at_index(idx, height);
+        self.root = left;
+        MarTree { root: right }
+    }
+
+    /// Moves every entry of `other` to the end of self, leaving `other` empty.
+    /// When all of other's keys are greater than self's, which is what
+    /// `split_off_at_index` hands out, the trees are joined in O(log n).
+    /// Otherwise other's entries are inserted one by one.
+    pub fn append(&mut self, other: &mut Self) {
+        let mut other = std::mem::take(other);
+        if other.root.size == 0 {
+            return;
+        }
+        if self.root.size == 0 {
+            *self = other;
+            return;
+        }
+
+        let last = self.get_by_index(self.root.size - 1).map(|(k, _)| k);
+        let first = other.get_by_index(0).map(|(k, _)| k);
+        if last >= first {
+            let mut entries = Vec::with_capacity(other.root.size);
+            other.root.drain_into(&mut entries);
+            for (k, v) in entries {
+                self.insert(k, v);
+            }
+            return;
+        }
+
+        let mid = other.pop_first().unwrap();
+        let left = std::mem::take(&mut self.root);
+        let left_height = left.height();
+        let right_height = other.root.height();
+        let (root, _) = Node::join(left, left_height, mid, other.root, right_height);
+        self.root = root;
+    }
 
     pub fn get(&self, key: &K) -> Option<&(K, V)> {
         self.root.get(key)
@@ -58,6 +219,10 @@ impl<K: Ord, V: Measured> MarTree<K, V> {
         self.root.get_next(key)
     }
 
+    pub fn get_prev(&self, key: &K) -> Option<&(K, V)> {
+        self.root.get_prev(key)
+    }
+
     pub fn get_by_index(&self, idx: usize) -> Option<&(K, V)> {
         self.root.get_by_index(idx)
     }
@@ -69,18 +234,61 @@ impl<K: Ord, V: Measured> MarTree<K, V> {
         self.root.alt_to_index(alt)
     }
 
+    pub fn alt_offset_of(&self, key: &K) -> Option<usize> {
+        self.root.alt_offset_of(key)
+    }
+
     pub fn size(&self) -> usize {
         return self.root.size;
     }
+
+    /// Summary of every entry in the tree.
+    pub fn summary(&self) -> &S {
+        &self.root.summary
+    }
+
+    /// Summary of the first `idx` entries.
+    pub fn summary_at_index(&self, idx: usize) -> S {
+        self.root.summary_at_index(idx)
+    }
+
+    /// Finds the first position whose prefix measures at least `target` along
+    /// `D`. Positions sit between entries, position i has the first i entries
+    /// before it. Returns the position and the summary of its prefix, or the
+    /// size and the whole summary when no prefix gets there.
+    pub fn seek_by<D: Dimension<S>>(&self, target: usize) -> (usize, S) {
+        self.root.seek_by::<D>(target)
+    }
+
+    /// Translates a position measured along `Src` into the same position
+    /// measured along `Dst`, e.g. a line number into a byte offset.
+    pub fn convert<Src: Dimension<S>, Dst: Dimension<S>>(&self, value: usize) -> usize {
+        Dst::measure(&self.seek_by::<Src>(value).1)
+    }
     pub fn size_alt(&self) -> usize {
         return self.root.size_alt;
     }
 
+    /// Builds the tree bottom-up from entries already in ascending key order,
+    /// in O(n) instead of the O(n log n) of inserting them one by one.
+    /// Input that turns out not to be strictly ascending is inserted one by one.
+    pub fn from_sorted_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let entries: Vec<(K, V)> = iter.into_iter().collect();
+        if !entries.windows(2).all(|w| w[0].0 < w[1].0) {
+            return entries.into_iter().collect();
+        }
+        let n = entries.len();
+        let height = Node::<K, V, S>::bulk_height(n);
+        MarTree {
+            root: Node::bulk_build(&mut entries.into_iter(), n, height, 2),
+        }
+    }
+
     fn validate(&self) {
         self.root.validate(true);
     }
 }
-impl<K, V: Measured> FromIterator<(K, V)> for MarTree<K, V>
+impl<K, V: Measured + Summarize<S>, S: Summary> FromIterator<(K, V)> for MarTree<K, V, S>
 where
     K: Ord,
 {
@@ -869,6 +1077,26 @@ mod tests {
         assert_eq!(tree.root.size_alt, expected_bytes);
     }
 
+    #[test]
+    fn char_tree_alt_offset_of_matches_get_by_alt_size() {
+        let mut tree: MarTree<usize, Char> = MarTree::default();
+        let text = "Hello, 世界! 🌍 ".repeat(20);
+        for (i, ch) in text.chars().enumerate() {
+            tree.insert(i * 2, Char(ch));
+        }
+        tree.validate();
+
+        let mut offset = 0usize;
+        for (i, ch) in text.chars().enumerate() {
+            assert_eq!(tree.alt_offset_of(&(i * 2)), Some(offset));
+            assert_eq!(tree.get_by_alt_size(offset), Some(&(i * 2, Char(ch))));
+            offset += ch.len_utf8();
+        }
+        // Keys that are not in the tree have no offset
+        assert_eq!(tree.alt_offset_of(&1), None);
+        assert_eq!(tree.alt_offset_of(&usize::MAX), None);
+    }
+
     // -------------------------------------------------------
     // get() tests
     // -------------------------------------------------------
@@ -1764,4 +1992,529 @@ mod tests {
         // Past the end
         assert!(tree.root.get_by_alt_size(10).is_none());
     }
+
+    // -------------------------------------------------------
+    // get_prev, range and reverse iteration
+    // -------------------------------------------------------
+
+    fn random_tree(n: usize) -> (MarTree<i64, i64>, BTreeMap<i64, i64>) {
+        let mut tree = new_tree();
+        let mut reference = BTreeMap::new();
+        let mut rng = rng();
+        for _ in 0..n {
+            let key: i64 = rng.random_range(0..(n as i64 * 2));
+            let val: i64 = rng.random_range(1..10);
+            tree.insert(key, val);
+            reference.insert(key, val);
+        }
+        tree.validate();
+        (tree, reference)
+    }
+
+    #[test]
+    fn get_prev_matches_btreemap() {
+        let (tree, reference) = random_tree(500);
+        for key in -1..1002 {
+            let expected = reference.range(..key).next_back().map(|(k, v)| (*k, *v));
+            assert_eq!(tree.get_prev(&key).copied(), expected, "get_prev({})", key);
+        }
+    }
+
+    #[test]
+    fn iter_rev_matches_sorted_order() {
+        let (tree, reference) = random_tree(700);
+        let expected: Vec<(i64, i64)> = reference.iter().rev().map(|(k, v)| (*k, *v)).collect();
+        let got: Vec<(i64, i64)> = tree.iter().rev().copied().collect();
+        assert_eq!(got, expected);
+        assert_eq!(tree.iter().len(), reference.len());
+    }
+
+    #[test]
+    fn iter_both_ends_meet_in_the_middle() {
+        let tree: MarTree<i64, i64> = (0..100).map(|i| (i, 1)).collect();
+        let mut iter = tree.iter();
+        let mut seen = Vec::new();
+        loop {
+            match (iter.next(), iter.next_back()) {
+                (Some(a), Some(b)) => {
+                    seen.push(a.0);
+                    seen.push(b.0);
+                }
+                (Some(a), None) => seen.push(a.0),
+                (None, _) => break,
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, (0..100).collect::<Vec<_>>());
+        assert!(iter.next().is_none());
+        assert!(iter.next_back().is_none());
+    }
+
+    #[test]
+    fn range_matches_btreemap() {
+        use std::ops::Bound::*;
+        let (tree, reference) = random_tree(400);
+        let mut rng = rng();
+        for _ in 0..300 {
+            let a: i64 = rng.random_range(-5..805);
+            let b: i64 = rng.random_range(a..810);
+            let bounds = [
+                (Included(a), Included(b)),
+                (Included(a), Excluded(b)),
+                (Excluded(a), Included(b)),
+                (Unbounded, Excluded(b)),
+                (Excluded(a), Unbounded),
+            ];
+            for bound in bounds {
+                let expected: Vec<i64> = reference.range(bound).map(|(k, _)| *k).collect();
+                let got: Vec<i64> = tree.range(bound).map(|(k, _)| *k).collect();
+                assert_eq!(got, expected, "range {:?}", bound);
+                let got_rev: Vec<i64> = tree.range(bound).rev().map(|(k, _)| *k).collect();
+                assert_eq!(got_rev, expected.into_iter().rev().collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn range_start_after_end_is_empty() {
+        use std::ops::Bound::*;
+        let tree: MarTree<i64, i64> = (0..50).map(|i| (i, 1)).collect();
+        assert_eq!(tree.range((Included(30), Excluded(10))).count(), 0);
+        assert_eq!(tree.range(100..).count(), 0);
+        assert_eq!(tree.range(..).count(), 50);
+    }
+
+    #[test]
+    fn iter_from_index_and_alt() {
+        let (tree, reference) = random_tree(300);
+        let entries: Vec<(i64, i64)> = reference.into_iter().collect();
+        for idx in 0..=entries.len() + 1 {
+            let got: Vec<(i64, i64)> = tree.iter_from_index(idx).copied().collect();
+            assert_eq!(got, entries[idx.min(entries.len())..].to_vec());
+        }
+
+        let mut alt = 0;
+        for (idx, &(_, v)) in entries.iter().enumerate() {
+            for off in [0, v as usize - 1] {
+                let first = tree.iter_from_alt(alt + off).next().copied();
+                assert_eq!(first, Some(entries[idx]), "iter_from_alt({})", alt + off);
+            }
+            alt += v as usize;
+        }
+        assert!(tree.iter_from_alt(alt).next().is_none());
+    }
+
+    #[test]
+    fn iter_empty_tree() {
+        let tree = new_tree();
+        assert!(tree.iter().next().is_none());
+        assert!(tree.iter().next_back().is_none());
+        assert!(tree.range(0..10).next().is_none());
+    }
+
+    // -------------------------------------------------------
+    // Cursor
+    // -------------------------------------------------------
+
+    #[test]
+    fn cursor_walks_forward_and_back() {
+        let (tree, reference) = random_tree(600);
+        let entries: Vec<(i64, i64)> = reference.into_iter().collect();
+        let mut offsets = Vec::with_capacity(entries.len() + 1);
+        let mut alt = 0;
+        for &(_, v) in &entries {
+            offsets.push(alt);
+            alt += v as usize;
+        }
+        offsets.push(alt);
+
+        let mut cursor = tree.cursor_at_index(0);
+        for (idx, entry) in entries.iter().enumerate() {
+            assert_eq!(cursor.get(), Some(entry));
+            assert_eq!(cursor.index(), idx);
+            assert_eq!(cursor.offset(), offsets[idx]);
+            assert!(cursor.move_next());
+        }
+        assert_eq!(cursor.get(), None);
+        assert_eq!(cursor.index(), entries.len());
+        assert_eq!(cursor.offset(), tree.size_alt());
+        assert!(!cursor.move_next());
+
+        for idx in (0..entries.len()).rev() {
+            assert!(cursor.move_prev());
+            assert_eq!(cursor.get(), Some(&entries[idx]));
+            assert_eq!(cursor.index(), idx);
+            assert_eq!(cursor.offset(), offsets[idx]);
+        }
+        assert!(!cursor.move_prev());
+        assert_eq!(cursor.index(), 0);
+        assert_eq!(cursor.get(), Some(&entries[0]));
+    }
+
+    #[test]
+    fn cursor_seeks_by_index_alt_and_key() {
+        let (tree, reference) = random_tree(400);
+        let entries: Vec<(i64, i64)> = reference.into_iter().collect();
+        let mut alt = 0;
+        for (idx, &(k, v)) in entries.iter().enumerate() {
+            let by_index = tree.cursor_at_index(idx);
+            assert_eq!(by_index.get(), Some(&entries[idx]));
+            assert_eq!(by_index.offset(), alt);
+
+            let by_alt = tree.cursor_at_alt(alt + v as usize - 1);
+            assert_eq!(by_alt.index(), idx);
+            assert_eq!(by_alt.offset(), alt);
+
+            let by_key = tree.cursor_at(&k);
+            assert_eq!(by_key.index(), idx);
+            assert_eq!(by_key.offset(), alt);
+            alt += v as usize;
+        }
+        // Missing keys land on the next entry
+        let missing = (0..).find(|k| tree.get(k).is_none()).unwrap();
+        let cursor = tree.cursor_at(&missing);
+        assert_eq!(cursor.get(), tree.get_next(&missing));
+
+        let end = tree.cursor_at_index(entries.len() + 3);
+        assert_eq!(end.get(), None);
+        assert_eq!(end.index(), entries.len());
+        assert_eq!(end.offset(), alt);
+    }
+
+    #[test]
+    fn cursor_on_empty_tree() {
+        let tree = new_tree();
+        let mut cursor = tree.cursor_at_index(0);
+        assert_eq!(cursor.get(), None);
+        assert!(!cursor.move_next());
+        assert!(!cursor.move_prev());
+        assert_eq!(cursor.offset(), 0);
+    }
+
+    // -------------------------------------------------------
+    // Bulk build
+    // -------------------------------------------------------
+
+    #[test]
+    fn from_sorted_iter_every_small_size() {
+        // Covers the leaf-only root and the first few height changes
+        for n in 0..400i64 {
+            let tree = MarTree::from_sorted_iter((0..n).map(|i| (i, i % 4 + 1)));
+            tree.validate();
+            assert_eq!(tree.size(), n as usize);
+            assert_eq!(
+                tree.size_alt(),
+                (0..n).map(|i| (i % 4 + 1) as usize).sum::<usize>()
+            );
+            assert!(tree.iter().map(|(k, _)| *k).eq(0..n));
+        }
+    }
+
+    #[test]
+    fn from_sorted_iter_large() {
+        for n in [1727i64, 1728, 20_735, 20_736, 50_000] {
+            let tree = MarTree::from_sorted_iter((0..n).map(|i| (i * 3, 1)));
+            tree.validate();
+            assert_eq!(tree.size(), n as usize);
+            let mut depths = Vec::new();
+            leaf_depths(&tree.root, 0, &mut depths);
+            assert!(depths.windows(2).all(|w| w[0] == w[1]));
+            assert_eq!(tree.get_by_index(n as usize / 2), Some(&((n / 2) * 3, 1)));
+        }
+    }
+
+    #[test]
+    fn from_sorted_iter_then_insert_and_remove() {
+        let mut tree = MarTree::from_sorted_iter((0..3000i64).map(|i| (i * 2, 1)));
+        let mut reference: BTreeMap<i64, i64> = (0..3000i64).map(|i| (i * 2, 1)).collect();
+        let mut rng = rng();
+        for _ in 0..4000 {
+            let key: i64 = rng.random_range(0..6000);
+            if rng.random_bool(0.5) {
+                tree.insert(key, 2);
+                reference.insert(key, 2);
+            } else {
+                tree.remove(&key);
+                reference.remove(&key);
+            }
+        }
+        tree.validate();
+        assert!(tree.iter().copied().eq(reference.into_iter()));
+    }
+
+    #[test]
+    fn from_sorted_iter_unsorted_input_falls_back() {
+        let tree = MarTree::from_sorted_iter([(5i64, 1i64), (1, 2), (3, 3), (3, 4)]);
+        tree.validate();
+        let entries: Vec<(i64, i64)> = tree.iter().copied().collect();
+        assert_eq!(entries, vec![(1, 2), (3, 4), (5, 1)]);
+    }
+
+    // -------------------------------------------------------
+    // Range removal, split and append
+    // -------------------------------------------------------
+
+    #[test]
+    fn split_off_at_every_index() {
+        for n in [0i64, 1, 11, 12, 80, 500] {
+            for idx in 0..=n as usize {
+                let mut left: MarTree<i64, i64> = (0..n).map(|i| (i, i % 3 + 1)).collect();
+                let right = left.split_off_at_index(idx);
+                left.validate();
+                right.validate();
+                assert!(left.iter().map(|(k, _)| *k).eq(0..idx as i64));
+                assert!(right.iter().map(|(k, _)| *k).eq(idx as i64..n));
+                let left_alt: usize = (0..idx as i64).map(|i| (i % 3 + 1) as usize).sum();
+                assert_eq!(left.size_alt(), left_alt);
+            }
+        }
+    }
+
+    #[test]
+    fn append_trees_of_different_heights() {
+        for (a, b) in [
+            (0i64, 5i64),
+            (5, 0),
+            (3, 3000),
+            (3000, 3),
+            (700, 900),
+            (11, 11),
+        ] {
+            let mut left: MarTree<i64, i64> = (0..a).map(|i| (i, 2)).collect();
+            let mut right: MarTree<i64, i64> = (a..a + b).map(|i| (i, 1)).collect();
+            left.append(&mut right);
+            left.validate();
+            right.validate();
+            assert_eq!(right.size(), 0);
+            assert_eq!(left.size(), (a + b) as usize);
+            assert_eq!(left.size_alt(), (2 * a + b) as usize);
+            assert!(left.iter().map(|(k, _)| *k).eq(0..a + b));
+            let mut depths = Vec::new();
+            leaf_depths(&left.root, 0, &mut depths);
+            assert!(depths.windows(2).all(|w| w[0] == w[1]));
+        }
+    }
+
+    #[test]
+    fn append_overlapping_keys_inserts() {
+        let mut left: MarTree<i64, i64> = (0..100).map(|i| (i * 2, 1)).collect();
+        let mut right: MarTree<i64, i64> = (0..100).map(|i| (i * 2 + 1, 1)).collect();
+        left.append(&mut right);
+        left.validate();
+        assert!(left.iter().map(|(k, _)| *k).eq(0..200));
+    }
+
+    #[test]
+    fn remove_range_by_index_matches_vec() {
+        let mut rng = rng();
+        for _ in 0..200 {
+            let n: i64 = rng.random_range(0..800);
+            let mut tree: MarTree<i64, i64> = (0..n).map(|i| (i, i % 5 + 1)).collect();
+            let mut reference: Vec<(i64, i64)> = (0..n).map(|i| (i, i % 5 + 1)).collect();
+            for _ in 0..5 {
+                let start = rng.random_range(0..=reference.len());
+                let end = rng.random_range(start..=reference.len());
+                let removed = tree.remove_range_by_index(start..end);
+                let expected: Vec<(i64, i64)> = reference.drain(start..end).collect();
+                assert_eq!(removed, expected);
+                tree.validate();
+                assert!(tree.iter().copied().eq(reference.iter().copied()));
+            }
+        }
+    }
+
+    #[test]
+    fn remove_range_by_index_bounds() {
+        let mut tree: MarTree<i64, i64> = (0..50).map(|i| (i, 1)).collect();
+        assert!(tree.remove_range_by_index(40..200).len() == 10);
+        assert_eq!(tree.remove_range_by_index(..=4).len(), 5);
+        assert_eq!(tree.remove_range_by_index(60..).len(), 0);
+        tree.validate();
+        assert!(tree.iter().map(|(k, _)| *k).eq(5..40));
+        assert_eq!(tree.remove_range_by_index(..).len(), 35);
+        tree.validate();
+        assert_eq!(tree.size(), 0);
+    }
+
+    #[test]
+    fn remove_range_by_alt_char_tree() {
+        let text = "aé中🌍b";
+        let mut tree: MarTree<usize, Char> = text
+            .chars()
+            .enumerate()
+            .map(|(i, c)| (i, Char(c)))
+            .collect();
+        // Offsets 2 and 7 fall inside 'é' and '🌍', both go along with '中'
+        let removed: String = tree
+            .remove_range_by_alt(2..=7)
+            .into_iter()
+            .map(|(_, c)| c.0)
+            .collect();
+        assert_eq!(removed, "é中🌍");
+        tree.validate();
+        let rest: String = tree.iter().map(|(_, c)| c.0).collect();
+        assert_eq!(rest, "ab");
+        assert_eq!(tree.size_alt(), 2);
+    }
+
+    #[test]
+    fn remove_range_stress_against_btreemap() {
+        let (mut tree, reference) = random_tree(2000);
+        let mut reference: Vec<(i64, i64)> = reference.into_iter().collect();
+        let mut rng = rng();
+        for round in 0..300 {
+            if round % 2 == 0 && !reference.is_empty() {
+                let start = rng.random_range(0..reference.len());
+                let end = (start + rng.random_range(0..40)).min(reference.len());
+                let alt_start: usize = reference[..start].iter().map(|(_, v)| *v as usize).sum();
+                let alt_end: usize = reference[..end].iter().map(|(_, v)| *v as usize).sum();
+                tree.remove_range_by_alt(alt_start..alt_end);
+                reference.drain(start..end);
+            } else {
+                let key: i64 = rng.random_range(0..4000);
+                tree.insert(key, 3);
+                match reference.binary_search_by_key(&key, |&(k, _)| k) {
+                    Ok(pos) => reference[pos].1 = 3,
+                    Err(pos) => reference.insert(pos, (key, 3)),
+                }
+            }
+        }
+        tree.validate();
+        assert!(tree.iter().copied().eq(reference.into_iter()));
+    }
+
+    // -------------------------------------------------------
+    // Summaries
+    // -------------------------------------------------------
+
+    // (UTF-16 units, newlines), with bytes coming from size_alt
+    type CharSummary = (usize, usize);
+
+    impl Summarize<CharSummary> for Char {
+        fn summarize(&self) -> CharSummary {
+            (self.0.len_utf16(), usize::from(self.0 == '\n'))
+        }
+    }
+
+    struct Utf16Units;
+    struct Newlines;
+
+    impl Dimension<CharSummary> for Utf16Units {
+        fn measure(summary: &CharSummary) -> usize {
+            summary.0
+        }
+    }
+    impl Dimension<CharSummary> for Newlines {
+        fn measure(summary: &CharSummary) -> usize {
+            summary.1
+        }
+    }
+
+    const CHAR_POOL: [char; 7] = ['a', '\n', 'é', '中', '🌍', 'z', '\n'];
+
+    fn random_char_tree(n: usize) -> (MarTree<usize, Char, CharSummary>, BTreeMap<usize, char>) {
+        let mut tree = MarTree::default();
+        let mut reference = BTreeMap::new();
+        let mut rng = rng();
+        for _ in 0..n {
+            let key: usize = rng.random_range(0..n);
+            if rng.random_bool(0.7) {
+                let ch = CHAR_POOL[rng.random_range(0..CHAR_POOL.len())];
+                tree.insert(key, Char(ch));
+                reference.insert(key, ch);
+            } else {
+                tree.remove(&key);
+                reference.remove(&key);
+            }
+        }
+        tree.validate();
+        (tree, reference)
+    }
+
+    fn prefix_summaries(chars: &[char]) -> Vec<CharSummary> {
+        let mut prefixes = vec![(0, 0)];
+        for &ch in chars {
+            let (units, lines) = *prefixes.last().unwrap();
+            prefixes.push((units + ch.len_utf16(), lines + usize::from(ch == '\n')));
+        }
+        prefixes
+    }
+
+    #[test]
+    fn summary_tracks_inserts_and_removes() {
+        let (tree, reference) = random_char_tree(3000);
+        let chars: Vec<char> = reference.values().copied().collect();
+        let total = *prefix_summaries(&chars).last().unwrap();
+        assert_eq!(*tree.summary(), total);
+    }
+
+    #[test]
+    fn summary_at_index_matches_prefix() {
+        let (tree, reference) = random_char_tree(1000);
+        let chars: Vec<char> = reference.values().copied().collect();
+        let prefixes = prefix_summaries(&chars);
+        for (idx, prefix) in prefixes.iter().enumerate() {
+            assert_eq!(tree.summary_at_index(idx), *prefix, "prefix of {}", idx);
+        }
+    }
+
+    #[test]
+    fn seek_by_finds_first_position_reaching_target() {
+        let (tree, reference) = random_char_tree(1500);
+        let chars: Vec<char> = reference.values().copied().collect();
+        let prefixes = prefix_summaries(&chars);
+        let total = *prefixes.last().unwrap();
+
+        for target in 0..=total.1 + 1 {
+            let expected = prefixes
+                .iter()
+                .position(|p| p.1 >= target)
+                .unwrap_or(chars.len());
+            let (idx, prefix) = tree.seek_by::<Newlines>(target);
+            assert_eq!(idx, expected, "line {}", target);
+            assert_eq!(prefix, prefixes[idx]);
+        }
+        for target in 0..=total.0 {
+            let expected = prefixes.iter().position(|p| p.0 >= target).unwrap();
+            assert_eq!(tree.seek_by::<Utf16Units>(target).0, expected);
+        }
+    }
+
+    #[test]
+    fn convert_between_dimensions() {
+        let mut tree: MarTree<usize, Char, CharSummary> = MarTree::default();
+        for (i, ch) in "ab\n🌍\n\nc".chars().enumerate() {
+            tree.insert(i, Char(ch));
+        }
+        // Lines start after the newlines at indexes 2, 4 and 5
+        assert_eq!(tree.convert::<Newlines, Utf16Units>(0), 0);
+        assert_eq!(tree.convert::<Newlines, Utf16Units>(1), 3);
+        assert_eq!(tree.convert::<Newlines, Utf16Units>(2), 6);
+        assert_eq!(tree.convert::<Newlines, Utf16Units>(3), 7);
+        // The emoji takes two UTF-16 units, one inside it lands after it
+        assert_eq!(tree.convert::<Utf16Units, Newlines>(4), 1);
+        assert_eq!(tree.convert::<Utf16Units, Newlines>(6), 2);
+    }
+
+    #[test]
+    fn summary_survives_bulk_build_split_and_append() {
+        let text: Vec<char> = (0..5000).map(|i| CHAR_POOL[i % CHAR_POOL.len()]).collect();
+        let mut tree: MarTree<usize, Char, CharSummary> =
+            MarTree::from_sorted_iter(text.iter().enumerate().map(|(i, &c)| (i, Char(c))));
+        tree.validate();
+        assert_eq!(*tree.summary(), *prefix_summaries(&text).last().unwrap());
+
+        let mut tail = tree.split_off_at_index(1234);
+        tree.validate();
+        tail.validate();
+        assert_eq!(*tree.summary(), prefix_summaries(&text)[1234]);
+
+        let removed = tail.remove_range_by_index(100..900);
+        tail.validate();
+        assert_eq!(removed.len(), 800);
+        tree.append(&mut tail);
+        tree.validate();
+        let rest: Vec<char> = tree.iter().map(|(_, c)| c.0).collect();
+        assert_eq!(*tree.summary(), *prefix_summaries(&rest).last().unwrap());
+    }
 }
@@ -1,50 +1,336 @@
-use crate::martree::{MarTree, node::Node};
+use std::{
+    cmp::Ordering,
+    iter::FusedIterator,
+    ops::{Bound, RangeBounds},
+};
 
-impl<K: Ord, V> MarTree<K, V> {
-    pub fn iter(&self) -> Iter<'_, K, V> {
-        let mut iter = Iter { stack: Vec::new() };
-        iter.push_left(&self.root);
-        iter
+use crate::martree::{MarTree, Measured, Summarize, Summary, node::Node};
+
+impl<K: Ord, V, S> MarTree<K, V, S> {
+    pub fn iter(&self) -> Iter<'_, K, V, S> {
+        Iter::new(&self.root, 0, self.root.size)
+    }
+
+    /// Entries from index `idx` (in key order) to the end.
+    pub fn iter_from_index(&self, idx: usize) -> Iter<'_, K, V, S> {
+        Iter::new(&self.root, idx, self.root.size)
+    }
+}
+
+impl<K: Ord, V: Measured + Summarize<S>, S: Summary> MarTree<K, V, S> {
+    /// Entries whose keys fall within `range`, like `BTreeMap::range`.
+    /// A range whose start comes after its end is empty.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Iter<'_, K, V, S> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.root.rank(key).0,
+            Bound::Excluded(key) => {
+                let (rank, found) = self.root.rank(key);
+                rank + found as usize
+            }
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => {
+                let (rank, found) = self.root.rank(key);
+                rank + found as usize
+            }
+            Bound::Excluded(key) => self.root.rank(key).0,
+            Bound::Unbounded => self.root.size,
+        };
+        Iter::new(&self.root, start, end.max(start))
+    }
+
+    /// Entries from the one covering alt offset `alt` to the end.
+    pub fn iter_from_alt(&self, alt: usize) -> Iter<'_, K, V, S> {
+        self.iter_from_index(self.alt_to_index(alt))
+    }
+
+    /// Cursor on the entry at index `idx`, or past the end if there is none.
+    pub fn cursor_at_index(&self, idx: usize) -> Cursor<'_, K, V, S> {
+        let mut cursor = Cursor {
+            handle: Handle::at_index(&self.root, idx),
+            index: idx.min(self.root.size),
+            offset: self.root.size_alt,
+        };
+        if cursor.index < self.root.size {
+            // The alt offset isn't tracked by the index descent, sum it up the path
+            cursor.offset = cursor.handle.offset_before();
+        }
+        cursor
+    }
+
+    /// Cursor on the entry covering alt offset `alt`, or past the end if there is none.
+    pub fn cursor_at_alt(&self, alt: usize) -> Cursor<'_, K, V, S> {
+        self.cursor_at_index(self.alt_to_index(alt))
+    }
+
+    /// Cursor on `key`, or on the first entry after it if it isn't in the tree.
+    pub fn cursor_at(&self, key: &K) -> Cursor<'_, K, V, S> {
+        self.cursor_at_index(self.root.rank(key).0)
     }
 }
-pub struct Iter<'a, K: Ord, V> {
-    stack: Vec<(&'a Node<K, V>, usize)>,
+
+/// A position in the tree: the path from the root down to an entry.
+/// Every node but the last is paired with the child that was descended into,
+/// the last one with the index of the entry in its keys.
+/// An empty path is the position past the last entry.
+struct Handle<'a, K: Ord, V, S> {
+    root: &'a Node<K, V, S>,
+    path: Vec<(&'a Node<K, V, S>, usize)>,
+}
+
+// Derived Clone would require K: Clone and V: Clone
+impl<'a, K: Ord, V, S> Clone for Handle<'a, K, V, S> {
+    fn clone(&self) -> Self {
+        Handle {
+            root: self.root,
+            path: self.path.clone(),
+        }
+    }
 }
 
-impl<'a, K:Ord, V> Iter<'a, K, V> {
-    fn push_left(&mut self, mut node: &'a Node<K, V>) {
+impl<'a, K: Ord, V, S> Handle<'a, K, V, S> {
+    fn at_index(root: &'a Node<K, V, S>, mut idx: usize) -> Self {
+        let mut handle = Handle {
+            root,
+            path: Vec::new(),
+        };
+        if idx >= root.size {
+            return handle;
+        }
+        let mut node = root;
+        let mut i = 0;
         loop {
-            self.stack.push((node, 0));
+            if node.is_leaf {
+                handle.path.push((node, idx));
+                return handle;
+            }
+
+            match idx.cmp(&node.children[i].size) {
+                Ordering::Less => {
+                    handle.path.push((node, i));
+                    node = &node.children[i];
+                    i = 0;
+                }
+                Ordering::Equal => {
+                    handle.path.push((node, i));
+                    return handle;
+                }
+                Ordering::Greater => {
+                    idx -= node.children[i].size + 1;
+                    i += 1
+                }
+            }
+        }
+    }
+
+    fn get(&self) -> Option<&'a (K, V)> {
+        self.path.last().map(|&(node, i)| &node.keys[i])
+    }
 
+    fn push_leftmost(&mut self, mut node: &'a Node<K, V, S>) {
+        loop {
+            self.path.push((node, 0));
             if node.is_leaf {
                 break;
             }
-
             node = &node.children[0];
         }
     }
+
+    fn push_rightmost(&mut self, mut node: &'a Node<K, V, S>) {
+        loop {
+            if node.is_leaf {
+                self.path.push((node, node.keys.len() - 1));
+                break;
+            }
+            self.path.push((node, node.children.len() - 1));
+            node = node.children.last().unwrap();
+        }
+    }
+
+    /// Step to the following entry, or past the end after the last one.
+    fn move_next(&mut self) {
+        let Some(&(node, i)) = self.path.last() else {
+            return;
+        };
+        let last = self.path.len() - 1;
+        if !node.is_leaf {
+            // The next entry is the leftmost one right of keys[i]
+            self.path[last].1 = i + 1;
+            self.push_leftmost(&node.children[i + 1]);
+        } else if i + 1 < node.keys.len() {
+            self.path[last].1 = i + 1;
+        } else {
+            // Climb till a node still has a key right of the child we came from
+            self.path.pop();
+            while let Some(&(node, c)) = self.path.last() {
+                if c < node.keys.len() {
+                    return;
+                }
+                self.path.pop();
+            }
+        }
+    }
+
+    /// Step to the preceding entry. Returns false, without moving, on the first one.
+    fn move_prev(&mut self) -> bool {
+        let Some(&(node, i)) = self.path.last() else {
+            if self.root.size == 0 {
+                return false;
+            }
+            self.push_rightmost(self.root);
+            return true;
+        };
+        let last = self.path.len() - 1;
+        if !node.is_leaf {
+            // The previous entry is the rightmost one left of keys[i]
+            self.push_rightmost(&node.children[i]);
+        } else if i > 0 {
+            self.path[last].1 = i - 1;
+        } else {
+            // Climb till a node has a key left of the child we came from
+            let Some(level) = self.path[..last].iter().rposition(|&(_, c)| c > 0) else {
+                return false;
+            };
+            self.path.truncate(level + 1);
+            self.path[level].1 -= 1;
+        }
+        true
+    }
+}
+
+impl<'a, K: Ord, V: Measured + Summarize<S>, S: Summary> Handle<'a, K, V, S> {
+    /// Sum of the measured values of every entry before the current one.
+    fn offset_before(&self) -> usize {
+        let last = self.path.len() - 1;
+        let mut alt = 0;
+        for (depth, &(node, i)) in self.path.iter().enumerate() {
+            alt += node.keys[..i]
+                .iter()
+                .map(|(_, v)| v.measured())
+                .sum::<usize>();
+            if !node.is_leaf {
+                // Children left of the one descended into, plus the left subtree
+                // of the current entry when it sits in an internal node
+                let children = if depth == last { i + 1 } else { i };
+                alt += node.children[..children]
+                    .iter()
+                    .map(|c| c.size_alt)
+                    .sum::<usize>();
+            }
+        }
+        alt
+    }
+}
+
+/// In-order iterator over a range of entries, from either end.
+pub struct Iter<'a, K: Ord, V, S = ()> {
+    front: Handle<'a, K, V, S>,
+    back: Handle<'a, K, V, S>,
+    /// Entries between front (inclusive) and back (exclusive)
+    len: usize,
 }
-impl<'a, K: Ord, V> Iterator for Iter<'a, K, V> {
+
+impl<'a, K: Ord, V, S> Iter<'a, K, V, S> {
+    fn new(root: &'a Node<K, V, S>, start: usize, end: usize) -> Self {
+        let end = end.min(root.size);
+        let start = start.min(end);
+        Iter {
+            front: Handle::at_index(root, start),
+            back: Handle::at_index(root, end),
+            len: end - start,
+        }
+    }
+}
+
+impl<'a, K: Ord, V, S> Iterator for Iter<'a, K, V, S> {
     type Item = &'a (K, V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some((node, idx)) = self.stack.pop() {
+        if self.len == 0 {
+            return None;
+        }
+        let entry = self.front.get();
+        self.front.move_next();
+        self.len -= 1;
+        entry
+    }
 
-            if idx < node.keys.len() {
-                // Push back node with incremented index
-                self.stack.push((node, idx + 1));
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
 
-                // If internal node, descend into right child
-                if !node.is_leaf {
-                    self.push_left(&node.children[idx + 1]);
-                }
+impl<'a, K: Ord, V, S> DoubleEndedIterator for Iter<'a, K, V, S> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.len == 0 {
+            return None;
+        }
+        self.back.move_prev();
+        self.len -= 1;
+        self.back.get()
+    }
+}
 
-                return Some(&node.keys[idx]);
-            }
+impl<'a, K: Ord, V, S> ExactSizeIterator for Iter<'a, K, V, S> {}
+
+impl<'a, K: Ord, V, S> FusedIterator for Iter<'a, K, V, S> {}
 
-            // else: node exhausted → continue popping
+/// A position in the tree that can step to either neighbour in O(1) amortized,
+/// keeping track of its index and alt offset as it goes. It rests either on an
+/// entry or past the last one, where `get` returns None.
+pub struct Cursor<'a, K: Ord, V, S = ()> {
+    handle: Handle<'a, K, V, S>,
+    index: usize,
+    /// Alt offset of the start of the current entry
+    offset: usize,
+}
+
+impl<'a, K: Ord, V, S> Clone for Cursor<'a, K, V, S> {
+    fn clone(&self) -> Self {
+        Cursor {
+            handle: self.handle.clone(),
+            index: self.index,
+            offset: self.offset,
         }
+    }
+}
+
+impl<'a, K: Ord, V: Measured + Summarize<S>, S: Summary> Cursor<'a, K, V, S> {
+    pub fn get(&self) -> Option<&'a (K, V)> {
+        self.handle.get()
+    }
+
+    /// Index of the current entry, the number of entries when past the end.
+    pub fn index(&self) -> usize {
+        self.index
+    }
 
-        None
+    /// Alt offset at which the current entry starts, the total alt size when past the end.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Step right. Returns false, without moving, when already past the end.
+    pub fn move_next(&mut self) -> bool {
+        let Some((_, v)) = self.handle.get() else {
+            return false;
+        };
+        self.offset += v.measured();
+        self.index += 1;
+        self.handle.move_next();
+        true
+    }
+
+    /// Step left. Returns false, without moving, on the first entry.
+    pub fn move_prev(&mut self) -> bool {
+        if !self.handle.move_prev() {
+            return false;
+        }
+        let (_, v) = self.handle.get().unwrap();
+        self.offset -= v.measured();
+        self.index -= 1;
+        true
     }
 }
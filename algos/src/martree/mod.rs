@@ -2,4 +2,5 @@ mod core;
 mod node;
 mod iterator;
 
-pub use core::{MarTree,Measured};
+pub use core::{Dimension, MarTree, Measured, Summarize, Summary};
+pub use iterator::{Cursor, Iter};
@@ -1,34 +1,37 @@
 use std::cmp::Ordering;
 
-use crate::martree::core::Measured;
+use crate::martree::core::{Dimension, Measured, Summarize, Summary};
 
 #[derive(Debug, Clone)]
-pub struct Node<K, V>
+pub struct Node<K, V, S = ()>
 where
     K: Ord,
 {
     pub keys: Vec<(K, V)>,
-    pub children: Vec<Node<K, V>>,
+    pub children: Vec<Node<K, V, S>>,
     pub size: usize,
     pub size_alt: usize,
+    /// Sum of the summaries of every entry in the subtree
+    pub summary: S,
     pub is_leaf: bool,
 }
 
 pub const T: usize = 6;
 
-impl<K: Ord, V: Measured> Default for Node<K, V> {
+impl<K: Ord, V: Measured + Summarize<S>, S: Summary> Default for Node<K, V, S> {
     fn default() -> Self {
         Node {
             keys: Vec::new(),
             children: Vec::new(),
             size: 0,
             size_alt: 0,
+            summary: S::default(),
             is_leaf: true,
         }
     }
 }
 
-impl<K: Ord, V: Measured> Node<K, V> {
+impl<K: Ord, V: Measured + Summarize<S>, S: Summary> Node<K, V, S> {
     // The function name is a precondition:
     // Insert key into the subtree rooted at node, assuming node itself is not full
     pub fn insert_non_full(&mut self, key: K, value: V) -> bool {
@@ -37,6 +40,8 @@ impl<K: Ord, V: Measured> Node<K, V> {
             Ok(pos) => {
                 let old_m = self.keys[pos].1.measured();
                 let new_m = value.measured();
+                self.summary.sub(&self.keys[pos].1.summarize());
+                self.summary.add(&value.summarize());
                 self.keys[pos].1 = value;
                 // Value changed — adjust size_alt for the difference
                 self.size_alt = self.size_alt + new_m - old_m;
@@ -45,6 +50,7 @@ impl<K: Ord, V: Measured> Node<K, V> {
             Err(mut idx) => {
                 if self.is_leaf {
                     let m = value.measured();
+                    self.summary.add(&value.summarize());
                     self.keys.insert(idx, (key, value));
                     self.size += 1;
                     self.size_alt += m;
@@ -58,6 +64,8 @@ impl<K: Ord, V: Measured> Node<K, V> {
                         if key == self.keys[idx].0 {
                             let old_m = self.keys[idx].1.measured();
                             let new_m = value.measured();
+                            self.summary.sub(&self.keys[idx].1.summarize());
+                            self.summary.add(&value.summarize());
                             self.keys[idx].1 = value;
                             self.size_alt = self.size_alt + new_m - old_m;
                             return false;
@@ -70,9 +78,11 @@ impl<K: Ord, V: Measured> Node<K, V> {
                     }
 
                     let old_child_alt = self.children[idx].size_alt;
+                    self.summary.sub(&self.children[idx].summary);
                     let inserted = self.children[idx].insert_non_full(key, value);
                     let new_child_alt = self.children[idx].size_alt;
                     self.size_alt = self.size_alt + new_child_alt - old_child_alt;
+                    self.summary.add(&self.children[idx].summary);
                     if inserted {
                         self.size += 1;
                     }
@@ -98,6 +108,17 @@ impl<K: Ord, V: Measured> Node<K, V> {
         }
     }
 
+    pub fn recompute_summary(&self) -> S {
+        let mut summary = S::default();
+        for (_, v) in &self.keys {
+            summary.add(&v.summarize());
+        }
+        for child in &self.children {
+            summary.add(&child.summary);
+        }
+        summary
+    }
+
     pub fn split_child(&mut self, i: usize) {
         // y is the full child to be split
         let is_leaf = self.children[i].is_leaf;
@@ -119,6 +140,7 @@ impl<K: Ord, V: Measured> Node<K, V> {
             children: right_children,
             size: 0,
             size_alt: 0,
+            summary: S::default(),
             is_leaf,
         };
         // Insert median key and new child into parent
@@ -128,7 +150,327 @@ impl<K: Ord, V: Measured> Node<K, V> {
         self.children[i].size_alt = self.children[i].recompute_size_alt();
         self.children[i + 1].size = self.children[i + 1].recompute_size();
         self.children[i + 1].size_alt = self.children[i + 1].recompute_size_alt();
+        self.children[i].summary = self.children[i].recompute_summary();
+        self.children[i + 1].summary = self.children[i + 1].recompute_summary();
+    }
+    // Builds a subtree of the given height out of the next n entries, which
+    // must be sorted. A subtree of height h holding n keys has n + 1 "slots"
+    // (a key and the gap right of it, plus one), between T^(h+1) and (2T)^(h+1)
+    // unless it's the root. Slots are spread evenly over the children, so every
+    // child ends up within those bounds for its own height.
+    pub fn bulk_build(
+        entries: &mut impl Iterator<Item = (K, V)>,
+        n: usize,
+        height: usize,
+        min_children: usize,
+    ) -> Self {
+        if height == 0 {
+            return Node::leaf(entries.take(n).collect());
+        }
+
+        let slots = n + 1;
+        let child_max_slots = (2 * T).saturating_pow(height as u32);
+        let count = slots.div_ceil(child_max_slots).max(min_children);
+        let mut node = Node {
+            keys: Vec::with_capacity(count - 1),
+            children: Vec::with_capacity(count),
+            size: n,
+            size_alt: 0,
+            summary: S::default(),
+            is_leaf: false,
+        };
+        for i in 0..count {
+            let child_slots = slots / count + usize::from(i < slots % count);
+            node.children
+                .push(Node::bulk_build(entries, child_slots - 1, height - 1, T));
+            if i + 1 < count {
+                node.keys.push(entries.next().unwrap());
+            }
+        }
+        node.size_alt = node.recompute_size_alt();
+        node.summary = node.recompute_summary();
+        node
+    }
+
+    // Height of the shallowest tree that can hold n keys
+    pub fn bulk_height(n: usize) -> usize {
+        let mut height = 0;
+        let mut max_slots = 2 * T;
+        while n + 1 > max_slots {
+            height += 1;
+            max_slots = max_slots.saturating_mul(2 * T);
+        }
+        height
     }
+
+    fn leaf(keys: Vec<(K, V)>) -> Self {
+        let mut node = Node {
+            size: keys.len(),
+            size_alt: 0,
+            summary: S::default(),
+            keys,
+            children: Vec::new(),
+            is_leaf: true,
+        };
+        node.size_alt = node.recompute_size_alt();
+        node.summary = node.recompute_summary();
+        node
+    }
+
+    // Root of a tree of the given height, collapsed into its only child when
+    // it has no keys. Returns the tree and its height.
+    fn internal(
+        keys: Vec<(K, V)>,
+        mut children: Vec<Node<K, V, S>>,
+        height: usize,
+    ) -> (Self, usize) {
+        if keys.is_empty() {
+            return (children.pop().unwrap(), height - 1);
+        }
+        let mut node = Node {
+            keys,
+            children,
+            size: 0,
+            size_alt: 0,
+            summary: S::default(),
+            is_leaf: false,
+        };
+        node.size = node.recompute_size();
+        node.size_alt = node.recompute_size_alt();
+        node.summary = node.recompute_summary();
+        (node, height)
+    }
+
+    // Number of levels below this node, 0 for a leaf
+    pub fn height(&self) -> usize {
+        let mut node = self;
+        let mut height = 0;
+        while !node.is_leaf {
+            node = &node.children[0];
+            height += 1;
+        }
+        height
+    }
+
+    // Moves every entry of the subtree into out, in key order
+    pub fn drain_into(self, out: &mut Vec<(K, V)>) {
+        if self.is_leaf {
+            out.extend(self.keys);
+            return;
+        }
+        let mut children = self.children.into_iter();
+        for key in self.keys {
+            children.next().unwrap().drain_into(out);
+            out.push(key);
+        }
+        children.next().unwrap().drain_into(out);
+    }
+
+    // Removes the smallest entry of a non-empty subtree. Like pop_successor,
+    // children are topped up on the way down so the leaf never underflows.
+    pub fn pop_first(&mut self) -> (K, V) {
+        let mut first = &*self;
+        while !first.is_leaf {
+            first = &first.children[0];
+        }
+        let first_m = first.keys[0].1.measured();
+        let first_s = first.keys[0].1.summarize();
+
+        let mut node = self;
+        while !node.is_leaf {
+            node.size -= 1;
+            node.size_alt -= first_m;
+            node.summary.sub(&first_s);
+            if node.children[0].keys.len() == T - 1 {
+                if node.children[1].keys.len() >= T {
+                    node.borrow_right(0);
+                } else {
+                    node.merge_children(0);
+                }
+            }
+            node = &mut node.children[0];
+        }
+        node.size -= 1;
+        node.size_alt -= first_m;
+        node.summary.sub(&first_s);
+        node.keys.remove(0)
+    }
+
+    // Brings children[i] back to at least T - 1 keys after a join put a
+    // smaller tree's root there, by merging it with a sibling when both fit
+    // in one node and by borrowing from that sibling otherwise.
+    fn fill_child(&mut self, i: usize) {
+        if self.children[i].keys.len() >= T - 1 {
+            return;
+        }
+        let sibling = if i > 0 { i - 1 } else { i + 1 };
+        let (l, r) = (i.min(sibling), i.max(sibling));
+        if self.children[l].keys.len() + self.children[r].keys.len() < 2 * T - 1 {
+            self.merge_children(l);
+            return;
+        }
+        while self.children[i].keys.len() < T - 1 {
+            if i > 0 {
+                self.borrow_left(i);
+            } else {
+                self.borrow_right(i);
+            }
+        }
+    }
+
+    // Hangs right, depth levels down the right spine, with mid as its separator.
+    // Nodes on the way may end up with 2T keys, the caller splits them.
+    fn join_at_right(&mut self, depth: usize, mid: (K, V), right: Node<K, V, S>) {
+        let last = self.children.len() - 1;
+        if depth == 1 {
+            self.size += right.size + 1;
+            self.size_alt += right.size_alt + mid.1.measured();
+            self.summary.add(&right.summary);
+            self.summary.add(&mid.1.summarize());
+            self.keys.push(mid);
+            self.children.push(right);
+            self.fill_child(last + 1);
+        } else {
+            let child_size = self.children[last].size;
+            let child_alt = self.children[last].size_alt;
+            self.summary.sub(&self.children[last].summary);
+            self.children[last].join_at_right(depth - 1, mid, right);
+            self.size = self.size + self.children[last].size - child_size;
+            self.size_alt = self.size_alt + self.children[last].size_alt - child_alt;
+            self.summary.add(&self.children[last].summary);
+            if self.children[last].keys.len() > 2 * T - 1 {
+                self.split_child(last);
+            }
+        }
+    }
+
+    // Mirror of join_at_right, hangs left down the left spine
+    fn join_at_left(&mut self, depth: usize, left: Node<K, V, S>, mid: (K, V)) {
+        if depth == 1 {
+            self.size += left.size + 1;
+            self.size_alt += left.size_alt + mid.1.measured();
+            self.summary.add(&left.summary);
+            self.summary.add(&mid.1.summarize());
+            self.keys.insert(0, mid);
+            self.children.insert(0, left);
+            self.fill_child(0);
+        } else {
+            let child_size = self.children[0].size;
+            let child_alt = self.children[0].size_alt;
+            self.summary.sub(&self.children[0].summary);
+            self.children[0].join_at_left(depth - 1, left, mid);
+            self.size = self.size + self.children[0].size - child_size;
+            self.size_alt = self.size_alt + self.children[0].size_alt - child_alt;
+            self.summary.add(&self.children[0].summary);
+            if self.children[0].keys.len() > 2 * T - 1 {
+                self.split_child(0);
+            }
+        }
+    }
+
+    // Joins two whole trees and the entry between them into one. Every key of
+    // left must be smaller than mid and every key of right greater. Their roots
+    // may hold fewer than T - 1 keys, like any root. Costs O(height difference).
+    // Returns the joined tree and its height.
+    pub fn join(
+        mut left: Node<K, V, S>,
+        left_height: usize,
+        mid: (K, V),
+        mut right: Node<K, V, S>,
+        right_height: usize,
+    ) -> (Self, usize) {
+        let (mut root, height) = match left_height.cmp(&right_height) {
+            Ordering::Equal => {
+                if left.keys.len() + right.keys.len() < 2 * T - 1 {
+                    left.size += right.size + 1;
+                    left.size_alt += right.size_alt + mid.1.measured();
+                    left.summary.add(&right.summary);
+                    left.summary.add(&mid.1.summarize());
+                    left.keys.push(mid);
+                    left.keys.extend(right.keys);
+                    left.children.extend(right.children);
+                    return (left, left_height);
+                }
+                let mut root = Node {
+                    size: left.size + right.size + 1,
+                    size_alt: left.size_alt + right.size_alt + mid.1.measured(),
+                    summary: S::default(),
+                    keys: vec![mid],
+                    children: vec![left, right],
+                    is_leaf: false,
+                };
+                root.summary = root.recompute_summary();
+                root.fill_child(0);
+                root.fill_child(1);
+                return (root, left_height + 1);
+            }
+            Ordering::Greater => {
+                left.join_at_right(left_height - right_height, mid, right);
+                (left, left_height)
+            }
+            Ordering::Less => {
+                right.join_at_left(right_height - left_height, left, mid);
+                (right, right_height)
+            }
+        };
+        if root.keys.len() > 2 * T - 1 {
+            root = Node {
+                size: root.size,
+                size_alt: root.size_alt,
+                summary: root.summary.clone(),
+                keys: Vec::new(),
+                children: vec![root],
+                is_leaf: false,
+            };
+            root.split_child(0);
+            return (root, height + 1);
+        }
+        (root, height)
+    }
+
+    // Splits a whole tree of the given height into the entries before index
+    // idx and the rest. The parts left and right of the path down to idx are
+    // joined back up level by level, so it costs O(log n) overall.
+    // Returns both trees with their heights.
+    pub fn split_at_index(
+        mut self,
+        mut idx: usize,
+        height: usize,
+    ) -> ((Self, usize), (Self, usize)) {
+        if self.is_leaf {
+            let right = self.keys.split_off(idx);
+            return ((Node::leaf(self.keys), 0), (Node::leaf(right), 0));
+        }
+
+        let mut i = 0;
+        while idx > self.children[i].size {
+            idx -= self.children[i].size + 1;
+            i += 1;
+        }
+
+        let right_children = self.children.split_off(i + 1);
+        let mut right_keys = self.keys.split_off(i);
+        let child = self.children.pop().unwrap();
+        let ((child_left, cl_height), (child_right, cr_height)) =
+            child.split_at_index(idx, height - 1);
+
+        let left = match self.keys.pop() {
+            None => (child_left, cl_height),
+            Some(mid) => {
+                let (prefix, p_height) = Node::internal(self.keys, self.children, height);
+                Node::join(prefix, p_height, mid, child_left, cl_height)
+            }
+        };
+        let right = if right_keys.is_empty() {
+            (child_right, cr_height)
+        } else {
+            let mid = right_keys.remove(0);
+            let (suffix, s_height) = Node::internal(right_keys, right_children, height);
+            Node::join(child_right, cr_height, mid, suffix, s_height)
+        };
+        (left, right)
+    }
+
     pub fn total_keys(&self) -> usize {
         let mut sum = self.keys.len();
         for child in &self.children {
@@ -151,6 +493,7 @@ impl<K: Ord, V: Measured> Node<K, V> {
                 let removed = self.keys.remove(idx);
                 self.size -= 1;
                 self.size_alt -= removed.1.measured();
+                self.summary.sub(&removed.1.summarize());
                 return true;
             }
 
@@ -162,6 +505,8 @@ impl<K: Ord, V: Measured> Node<K, V> {
                 // The child loses pred_m from its subtree.
                 // So this node's size_alt changes by: (pred_m - old_m) - pred_m = -old_m.
                 let old_m = self.keys[idx].1.measured();
+                // Same reasoning for the summary, only the removed key goes
+                self.summary.sub(&self.keys[idx].1.summarize());
                 let old_child_alt = self.children[idx].size_alt;
                 let pred = self.pop_predecessor(idx);
                 let new_child_alt = self.children[idx].size_alt;
@@ -174,6 +519,7 @@ impl<K: Ord, V: Measured> Node<K, V> {
                 return true;
             } else if self.children[idx + 1].keys.len() >= T {
                 let old_m = self.keys[idx].1.measured();
+                self.summary.sub(&self.keys[idx].1.summarize());
                 let old_child_alt = self.children[idx + 1].size_alt;
                 let succ = self.pop_successor(idx);
                 let new_child_alt = self.children[idx + 1].size_alt;
@@ -190,7 +536,9 @@ impl<K: Ord, V: Measured> Node<K, V> {
                 let old_m = self.keys[idx].1.measured();
                 self.merge_children(idx);
                 let old_child_alt = self.children[idx].size_alt;
+                self.summary.sub(&self.children[idx].summary);
                 let deleted = self.children[idx].remove(key);
+                self.summary.add(&self.children[idx].summary);
                 let new_child_alt = self.children[idx].size_alt;
                 if deleted {
                     self.size -= 1;
@@ -228,7 +576,9 @@ impl<K: Ord, V: Measured> Node<K, V> {
         }
 
         let old_child_alt = self.children[idx].size_alt;
+        self.summary.sub(&self.children[idx].summary);
         let deleted = self.children[idx].remove(key);
+        self.summary.add(&self.children[idx].summary);
         let new_child_alt = self.children[idx].size_alt;
         if deleted {
             self.size -= 1;
@@ -257,13 +607,17 @@ impl<K: Ord, V: Measured> Node<K, V> {
         // Size adjustments for key movement
         left.size -= 1;
         left.size_alt -= borrowed_m;
+        left.summary.sub(&self.keys[idx - 1].1.summarize());
         right.size += 1;
         right.size_alt += parent_m;
+        right.summary.add(&right.keys[0].1.summarize());
 
         if !left.is_leaf {
             let moved_child = left.children.pop().unwrap();
             let moved_size = moved_child.size;
             let moved_alt = moved_child.size_alt;
+            left.summary.sub(&moved_child.summary);
+            right.summary.add(&moved_child.summary);
 
             right.children.insert(0, moved_child);
 
@@ -293,13 +647,18 @@ impl<K: Ord, V: Measured> Node<K, V> {
 
         left.size += 1;
         left.size_alt += parent_m;
+        left.summary
+            .add(&left.keys[left.keys.len() - 1].1.summarize());
         right.size -= 1;
         right.size_alt -= borrowed_m;
+        right.summary.sub(&self.keys[idx].1.summarize());
 
         if !right.is_leaf {
             let moved_child = right.children.remove(0);
             let moved_size = moved_child.size;
             let moved_alt = moved_child.size_alt;
+            right.summary.sub(&moved_child.summary);
+            left.summary.add(&moved_child.summary);
 
             left.children.push(moved_child);
 
@@ -321,6 +680,8 @@ impl<K: Ord, V: Measured> Node<K, V> {
 
         let separator = self.keys.remove(idx);
         let sep_m = separator.1.measured();
+        child.summary.add(&separator.1.summarize());
+        child.summary.add(&right.summary);
         child.keys.push(separator);
         child.keys.extend(right.keys);
 
@@ -347,12 +708,14 @@ impl<K: Ord, V: Measured> Node<K, V> {
     fn pop_predecessor(&mut self, idx: usize) -> (K, V) {
         // Pre-read the measured value of the predecessor we're about to pop
         let pred_m = self.get_predecessor(idx).1.measured();
+        let pred_s = self.get_predecessor(idx).1.summarize();
 
         let mut child = &mut self.children[idx];
 
         while !child.is_leaf {
             child.size -= 1;
             child.size_alt -= pred_m;
+            child.summary.sub(&pred_s);
             let last = child.children.len() - 1;
 
             if child.children[last].keys.len() == T - 1 {
@@ -367,6 +730,7 @@ impl<K: Ord, V: Measured> Node<K, V> {
         }
         child.size -= 1;
         child.size_alt -= pred_m;
+        child.summary.sub(&pred_s);
         return child.keys.pop().unwrap();
     }
     fn get_successor(&self, idx: usize) -> &(K, V) {
@@ -379,12 +743,14 @@ impl<K: Ord, V: Measured> Node<K, V> {
     fn pop_successor(&mut self, idx: usize) -> (K, V) {
         // Pre-read the measured value of the successor we're about to pop
         let succ_m = self.get_successor(idx).1.measured();
+        let succ_s = self.get_successor(idx).1.summarize();
 
         let mut child = &mut self.children[idx + 1];
 
         while !child.is_leaf {
             child.size -= 1;
             child.size_alt -= succ_m;
+            child.summary.sub(&succ_s);
             if child.children[0].keys.len() == T - 1 {
                 if child.children.len() > 1 && child.children[1].keys.len() >= T {
                     child.borrow_right(0);
@@ -396,6 +762,7 @@ impl<K: Ord, V: Measured> Node<K, V> {
         }
         child.size -= 1;
         child.size_alt -= succ_m;
+        child.summary.sub(&succ_s);
         return child.keys.remove(0);
     }
 
@@ -447,6 +814,72 @@ impl<K: Ord, V: Measured> Node<K, V> {
         }
     }
 
+    pub fn get_prev(&self, key: &K) -> Option<&(K, V)> {
+        let mut node = self;
+        let mut candidate = None;
+
+        loop {
+            match node.keys.binary_search_by(|(k, _)| k.cmp(key)) {
+                Ok(pos) => {
+                    if !node.is_leaf {
+                        return Some(node.get_predecessor(pos));
+                    }
+
+                    if pos > 0 {
+                        return Some(&node.keys[pos - 1]);
+                    }
+
+                    return candidate;
+                }
+
+                Err(pos) => {
+                    if pos > 0 {
+                        candidate = Some(&node.keys[pos - 1]);
+                    }
+
+                    if node.is_leaf {
+                        return candidate;
+                    }
+
+                    node = &node.children[pos];
+                }
+            }
+        }
+    }
+
+    // Number of entries with a key smaller than key, and whether key itself is present
+    pub fn rank(&self, key: &K) -> (usize, bool) {
+        let mut node = self;
+        let mut idx = 0;
+        loop {
+            let pos = node.keys.binary_search_by(|(k, _)| k.cmp(key));
+            let before = match pos {
+                Ok(pos) => pos,
+                Err(pos) => pos,
+            };
+            idx += before;
+            if !node.is_leaf {
+                let children = match pos {
+                    Ok(pos) => pos + 1,
+                    Err(pos) => pos,
+                };
+                idx += node.children[..children]
+                    .iter()
+                    .map(|c| c.size)
+                    .sum::<usize>();
+            }
+            match pos {
+                Ok(_) => return (idx, true),
+                Err(pos) => {
+                    if node.is_leaf {
+                        return (idx, false);
+                    }
+                    node = &node.children[pos];
+                }
+            }
+        }
+    }
+
     pub fn alt_to_index(&self, mut alt: usize) -> usize {
         let mut node = self;
         let mut i = 0;
@@ -548,6 +981,115 @@ impl<K: Ord, V: Measured> Node<K, V> {
         }
     }
 
+    // Inverse of get_by_alt_size: sum of measured values of every entry before key
+    pub fn alt_offset_of(&self, key: &K) -> Option<usize> {
+        let mut node = self;
+        let mut alt = 0;
+        loop {
+            let pos = node.keys.binary_search_by(|(k, _)| k.cmp(key));
+            let before = match pos {
+                Ok(pos) => pos,
+                Err(pos) => pos,
+            };
+            alt += node.keys[..before]
+                .iter()
+                .map(|(_, v)| v.measured())
+                .sum::<usize>();
+            if !node.is_leaf {
+                let children = match pos {
+                    // Everything in the left subtree of keys[pos] comes before it too
+                    Ok(pos) => pos + 1,
+                    Err(pos) => pos,
+                };
+                alt += node.children[..children]
+                    .iter()
+                    .map(|c| c.size_alt)
+                    .sum::<usize>();
+            }
+            match pos {
+                Ok(_) => return Some(alt),
+                Err(pos) => {
+                    if node.is_leaf {
+                        return None;
+                    }
+                    node = &node.children[pos];
+                }
+            }
+        }
+    }
+
+    // Summary of the first idx entries
+    pub fn summary_at_index(&self, mut idx: usize) -> S {
+        let mut node = self;
+        let mut prefix = S::default();
+        idx = idx.min(self.size);
+        loop {
+            if node.is_leaf {
+                for (_, v) in &node.keys[..idx] {
+                    prefix.add(&v.summarize());
+                }
+                return prefix;
+            }
+
+            let mut i = 0;
+            while idx > node.children[i].size {
+                prefix.add(&node.children[i].summary);
+                prefix.add(&node.keys[i].1.summarize());
+                idx -= node.children[i].size + 1;
+                i += 1;
+            }
+            node = &node.children[i];
+        }
+    }
+
+    // The first position (0 to size, a position being the number of entries
+    // before it) whose prefix measures at least target along D, with the
+    // summary of that prefix. Past the end gives size and the whole summary.
+    pub fn seek_by<D: Dimension<S>>(&self, target: usize) -> (usize, S) {
+        let mut node = self;
+        let mut idx = 0;
+        let mut prefix = S::default();
+        if D::measure(&prefix) >= target {
+            return (0, prefix);
+        }
+        loop {
+            if node.is_leaf {
+                for (_, v) in &node.keys {
+                    prefix.add(&v.summarize());
+                    idx += 1;
+                    if D::measure(&prefix) >= target {
+                        return (idx, prefix);
+                    }
+                }
+                return (idx, prefix);
+            }
+
+            let mut next = None;
+            for (i, child) in node.children.iter().enumerate() {
+                let mut with_child = prefix.clone();
+                with_child.add(&child.summary);
+                if D::measure(&with_child) >= target {
+                    // Target is inside this child
+                    next = Some(child);
+                    break;
+                }
+                prefix = with_child;
+                idx += child.size;
+                if let Some((_, v)) = node.keys.get(i) {
+                    prefix.add(&v.summarize());
+                    idx += 1;
+                    if D::measure(&prefix) >= target {
+                        return (idx, prefix);
+                    }
+                }
+            }
+            match next {
+                Some(child) => node = child,
+                None => return (idx, prefix),
+            }
+        }
+    }
+
     pub fn validate(&self, is_root: bool) {
         // Check key count bounds
         if !is_root {
@@ -647,5 +1189,14 @@ impl<K: Ord, V: Measured> Node<K, V> {
             self.size,
             self.total_keys()
         );
+
+        // Summary must equal the keys' summaries plus the children's
+        assert_eq!(
+            self.summary,
+            self.recompute_summary(),
+            "summary {:?} != expected {:?}",
+            self.summary,
+            self.recompute_summary()
+        );
     }
 }
@@ -1,16 +1,22 @@
-use std::io::{Cursor, Read};
+use std::io::Cursor;
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
-use crate::{doc::Doc, pid::Pid};
+use crate::{
+    decode::{DecodeError, DecodeResult, check_count, read_char, read_pid},
+    doc::Doc,
+    pid::Pid,
+    pos::SiteId,
+    varint::{read_varint_u32, write_varint_buf},
+};
 
 
 #[derive(Debug, Clone)]
 pub enum PeerMessage {
     Greet,
-    Insert { site: u8, pid: Pid, c: char },
-    Delete { site: u8, pid: Pid },
-    NewSession { site: u8, doc: Doc },
+    Insert { site: SiteId, pid: Pid, c: char },
+    Delete { site: SiteId, pid: Pid },
+    NewSession { site: SiteId, doc: Doc },
 }
 
 impl PeerMessage {
@@ -21,7 +27,7 @@ impl PeerMessage {
                 // put header i.e. PeerMessage enum it is
                 let mut buf = vec![1u8];
                 // put site_id
-                buf.push(*site);
+                write_varint_buf(&mut buf, *site as u64);
                 // put numberofatoms
                 buf.extend((doc.char_len() as u64).to_le_bytes());
                 doc.write_bytes_tobuf(&mut buf);
@@ -29,7 +35,7 @@ impl PeerMessage {
             }
             PeerMessage::Insert { site, pid, c } => {
                 let mut buf = vec![2u8];
-                buf.push(*site);
+                write_varint_buf(&mut buf, *site as u64);
                 let mut cbuf = [0u8; 4];
                 let encoded = c.encode_utf8(&mut cbuf);
                 // put atom's data length
@@ -41,46 +47,37 @@ impl PeerMessage {
             }
             PeerMessage::Delete { site, pid } => {
                 let mut buf = vec![3u8];
-                buf.push(*site);
+                write_varint_buf(&mut buf, *site as u64);
                 buf.push(pid.depth() as u8);
                 pid.write_bytes(&mut buf);
                 buf
             }
         }
     }
-    pub fn deserialize(buf: &[u8]) -> PeerMessage {
+    pub fn deserialize(buf: &[u8]) -> DecodeResult<PeerMessage> {
         let mut cur = Cursor::new(buf);
-        match cur.read_u8().unwrap() {
+        Ok(match cur.read_u8()? {
             0u8 => PeerMessage::Greet,
             1u8 => {
-                let site = cur.read_u8().unwrap();
-                let number_of_atoms = cur.read_u64::<LittleEndian>().unwrap() as usize;
+                let site = read_varint_u32(&mut cur)?;
+                let number_of_atoms = check_count(cur.read_u64::<LittleEndian>()?)?;
                 PeerMessage::NewSession {
                     site: site,
-                    doc: Doc::from_reader(&mut cur, number_of_atoms),
+                    doc: Doc::from_reader(&mut cur, number_of_atoms)?,
                 }
             }
             2u8 => {
-                let site = cur.read_u8().unwrap();
-                let data_len = cur.read_u8().unwrap() as usize;
-                let mut bytes = [0u8; 4];
-                cur.read_exact(&mut bytes[..data_len]).unwrap();
-                let data = std::str::from_utf8(&bytes[..data_len])
-                    .unwrap()
-                    .chars()
-                    .next()
-                    .unwrap();
-                let pid_depth = cur.read_u8().unwrap();
-                let pid = Pid::read_bytes(&mut cur, pid_depth as usize);
+                let site = read_varint_u32(&mut cur)?;
+                let data = read_char(&mut cur)?;
+                let pid = read_pid(&mut cur)?;
                 PeerMessage::Insert { site: site, pid: pid, c: data }
             }
             3u8 => {
-                let site = cur.read_u8().unwrap();
-                let pid_depth = cur.read_u8().unwrap();
-                let pid = Pid::read_bytes(&mut cur, pid_depth as usize);
+                let site = read_varint_u32(&mut cur)?;
+                let pid = read_pid(&mut cur)?;
                 PeerMessage::Delete { site: site, pid: pid }
             }
-            _ => panic!(),
-        }
+            tag => return Err(DecodeError::UnknownTag(tag)),
+        })
     }
 }
@@ -2,11 +2,18 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
+    fmt,
     io::{Read, Write},
+    str::FromStr,
 };
 use anyhow::{anyhow, Context, Result};
 
-use crate::{pos::Pos, LBASE};
+use crate::{
+    decode::{DecodeError, DecodeResult, MAX_PID_DEPTH},
+    pos::{Pos, SiteId, UNLEASED_SITE},
+    varint::read_varint_u32,
+    LBASE,
+};
 
 /// A PID is a vector of positions
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -31,21 +38,49 @@ impl Pid {
     pub fn depth(&self) -> usize {
         self.0.len()
     }
+    /// Whether this PID was generated before its replica had a site leased,
+    /// the site of the last position is the one that tells replicas apart.
+    pub fn is_provisional(&self) -> bool {
+        self.0.last().is_some_and(|pos| pos.site == UNLEASED_SITE)
+    }
+
+    pub fn read_bytes<R: Read>(reader: &mut R, depth: usize) -> DecodeResult<Self> {
+        if depth > MAX_PID_DEPTH {
+            return Err(DecodeError::PidTooDeep(depth));
+        }
+        let mut positions = Vec::with_capacity(depth);
+
+        for _ in 0..depth {
+            let mut ident_bytes = [0u8; 4];
+            reader.read_exact(&mut ident_bytes)?;
+            let ident = u32::from_le_bytes(ident_bytes);
+
+            let site = read_varint_u32(reader)?;
+
+            positions.push(Pos::new(ident, site));
+        }
+        Ok(Pid(positions))
+    }
 
-    pub fn read_bytes<R: Read>(reader: &mut R, depth: usize) -> Self {
+    /// Read a PID written before sites were widened, i.e. with a single byte site.
+    /// Only old `.md.structure` files contain those.
+    pub fn read_bytes_v1<R: Read>(reader: &mut R, depth: usize) -> DecodeResult<Self> {
+        if depth > MAX_PID_DEPTH {
+            return Err(DecodeError::PidTooDeep(depth));
+        }
         let mut positions = Vec::with_capacity(depth);
 
         for _ in 0..depth {
             let mut ident_bytes = [0u8; 4];
-            reader.read_exact(&mut ident_bytes).unwrap();
+            reader.read_exact(&mut ident_bytes)?;
             let ident = u32::from_le_bytes(ident_bytes);
 
             let mut site = [0u8; 1];
-            reader.read_exact(&mut site).unwrap();
+            reader.read_exact(&mut site)?;
 
-            positions.push(Pos::new(ident, site[0]));
+            positions.push(Pos::new(ident, site[0] as SiteId));
         }
-        Pid(positions)
+        Ok(Pid(positions))
     }
 }
 
@@ -68,45 +103,182 @@ impl Ord for Pid {
     }
 }
 
+/// Decides where new PIDs go in the gap between two existing ones.
+pub trait AllocationStrategy: fmt::Debug + Send + Sync {
+    fn generate_between(&self, lp: &Pid, rp: &Pid, site_id: SiteId) -> Pid;
+}
+
+/// Plain Logoot: a uniformly random ident anywhere in the gap, see `generate_between_pids`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Logoot;
+
+impl AllocationStrategy for Logoot {
+    fn generate_between(&self, lp: &Pid, rp: &Pid, site_id: SiteId) -> Pid {
+        generate_between_pids(lp, rp, site_id)
+    }
+}
+
+/// LSEQ allocation.
+///
+/// Idents are picked at most `boundary` away from one end of the gap. Even
+/// depths stick close to the left neighbour (boundary+), which keeps typing
+/// left to right at the current depth; odd depths stick close to the right one
+/// (boundary-), which does the same for prepending. Each level down has twice
+/// the ident space of the one above, so the rare descent buys a lot of room.
+#[derive(Debug, Clone, Copy)]
+pub struct Lseq {
+    pub boundary: u32,
+    /// Depth 0 has `2^base_bits` idents
+    pub base_bits: u32,
+}
+
+impl Default for Lseq {
+    fn default() -> Self {
+        Lseq {
+            boundary: 10,
+            base_bits: 4,
+        }
+    }
+}
+
+impl Lseq {
+    /// Exclusive upper ident of the given depth
+    fn base(&self, depth: usize) -> u32 {
+        let bits = self.base_bits as usize + depth;
+        if bits >= 32 { LBASE } else { 1 << bits }
+    }
+}
+
+impl AllocationStrategy for Lseq {
+    fn generate_between(&self, lp: &Pid, rp: &Pid, site_id: SiteId) -> Pid {
+        let mut p = Vec::new();
+        let mut rng = rand::rng();
+        // Whether what we built so far still equals the prefix of `rp`,
+        // once it's smaller anything goes at deeper levels
+        let mut bounded_right = true;
+
+        for depth in 0.. {
+            let l = lp.0.get(depth).cloned().unwrap_or(Pos { ident: 0, site: 0 });
+            let upper = match rp.0.get(depth) {
+                Some(r) if bounded_right => r.ident,
+                // An `rp` shorter than the built prefix would be smaller than `lp`
+                None if bounded_right => LBASE,
+                // PIDs from other strategies may go past this depth's base
+                _ if l.ident >= self.base(depth) => LBASE,
+                _ => self.base(depth),
+            };
+
+            let gap = upper.saturating_sub(l.ident).saturating_sub(1);
+            if gap > 0 {
+                let offset = rng.random_range(1..=gap.min(self.boundary));
+                let ident = if depth % 2 == 0 {
+                    l.ident + offset
+                } else {
+                    upper - offset
+                };
+                p.push(Pos { ident, site: site_id });
+                break;
+            }
+
+            bounded_right = bounded_right && rp.0.get(depth) == Some(&l);
+            p.push(l);
+        }
+
+        Pid(p)
+    }
+}
+
+/// The allocation strategy a document uses, kept in its structure file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    #[default]
+    Logoot,
+    Lseq,
+}
+
+impl Strategy {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Strategy::Logoot => 0,
+            Strategy::Lseq => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Strategy::Logoot),
+            1 => Some(Strategy::Lseq),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "logoot" => Ok(Strategy::Logoot),
+            "lseq" => Ok(Strategy::Lseq),
+            _ => Err(format!("unknown allocation strategy {:?}, expected logoot or lseq", s)),
+        }
+    }
+}
+
+impl AllocationStrategy for Strategy {
+    fn generate_between(&self, lp: &Pid, rp: &Pid, site_id: SiteId) -> Pid {
+        match self {
+            Strategy::Logoot => Logoot.generate_between(lp, rp, site_id),
+            Strategy::Lseq => Lseq::default().generate_between(lp, rp, site_id),
+        }
+    }
+}
+
 /// Generate a PID between two existing PIDs
-pub fn generate_between_pids(lp: &Pid, rp: &Pid, site_id: u8) -> Pid {
+pub fn generate_between_pids(lp: &Pid, rp: &Pid, site_id: SiteId) -> Pid {
     let mut p = Vec::new();
-
-    let max_depth = lp.0.len().max(rp.0.len());
     let mut rng = rand::rng();
+    // Whether what we built so far still equals the prefix of `rp`, once it's
+    // smaller only `lp` bounds the deeper levels
+    let mut bounded_right = true;
 
-    for i in 0..max_depth {
+    for i in 0..lp.0.len().max(rp.0.len()) {
         let l = lp.0.get(i).cloned().unwrap_or(Pos { ident: 0, site: 0 });
-        let r = rp.0.get(i).cloned().unwrap_or(Pos {
-            ident: LBASE,
-            site: u8::MAX,
-        });
+        let r = match rp.0.get(i) {
+            Some(r) if bounded_right => r.clone(),
+            _ => Pos {
+                ident: LBASE,
+                site: SiteId::MAX,
+            },
+        };
 
         if l == r {
             p.push(l);
             continue;
         }
 
-        if l.ident == r.ident {
-            // same ident, different site → site_id tie-breaker
+        if r.ident.saturating_sub(l.ident) > 1 {
+            let new_ident = rng.random_range(l.ident + 1..r.ident);
             p.push(Pos {
-                ident: l.ident,
+                ident: new_ident,
                 site: site_id,
             });
             return Pid(p);
         }
 
-        let d = r.ident.saturating_sub(l.ident);
-        if d > 1 {
-            let new_ident = rng.random_range(l.ident + 1..r.ident);
+        if l.ident == r.ident && l.site < site_id && site_id < r.site {
+            // same ident, our site falls between theirs → site_id tie-breaker
             p.push(Pos {
-                ident: new_ident,
+                ident: l.ident,
                 site: site_id,
             });
             return Pid(p);
-        } else {
-            p.push(l);
         }
+
+        // No room at this depth, anything deeper under `l` is below `r`
+        bounded_right = false;
+        p.push(l);
     }
 
     // If no gap found, extend depth
@@ -118,3 +290,67 @@ pub fn generate_between_pids(lp: &Pid, rp: &Pid, site_id: u8) -> Pid {
     Pid(p)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SITE: SiteId = 7;
+
+    fn pid(positions: &[(u32, SiteId)]) -> Pid {
+        Pid(positions.iter().map(|&(ident, site)| Pos::new(ident, site)).collect())
+    }
+
+    /// Generates between `l` and `r` a bunch of times with every strategy.
+    fn assert_between(l: &[(u32, SiteId)], r: &[(u32, SiteId)]) {
+        let (l, r) = (pid(l), pid(r));
+        for strategy in [Strategy::Logoot, Strategy::Lseq] {
+            for _ in 0..200 {
+                let p = strategy.generate_between(&l, &r, SITE);
+                assert!(l < p && p < r, "{:?}: {:?} < {:?} < {:?}", strategy, l, p, r);
+                assert_eq!(p.0.last().unwrap().site, SITE);
+            }
+        }
+    }
+
+    #[test]
+    fn between_sentinels() {
+        assert_between(&[(0, 0)], &[(LBASE, 0)]);
+    }
+
+    #[test]
+    fn between_adjacent_idents() {
+        assert_between(&[(5, 2)], &[(6, 2)]);
+        assert_between(&[(0, 0)], &[(1, 2)]);
+        assert_between(&[(LBASE - 1, 2)], &[(LBASE, 0)]);
+    }
+
+    #[test]
+    fn between_same_ident_on_different_sites() {
+        // Our site falls between theirs
+        assert_between(&[(5, 2)], &[(5, 9)]);
+        // It doesn't, so it can't be the tie-breaker
+        assert_between(&[(5, 2)], &[(5, 4)]);
+        assert_between(&[(5, 8)], &[(5, 9)]);
+        assert_between(&[(5, 7)], &[(5, 8)]);
+    }
+
+    #[test]
+    fn between_deep_pids() {
+        assert_between(&[(5, 2), (LBASE - 1, 3)], &[(6, 2)]);
+        assert_between(&[(5, 2)], &[(5, 2), (0, 3)]);
+        assert_between(&[(5, 2)], &[(5, 2), (1, 3)]);
+        assert_between(&[(5, 2), (9, 3)], &[(5, 2), (10, 3)]);
+        assert_between(&[(5, 2), (9, 3), (4, 8)], &[(5, 2), (9, 3), (5, 2)]);
+        assert_between(&[(5, 2), (LBASE, 9)], &[(6, 2), (0, 0)]);
+    }
+
+    #[test]
+    fn strategy_names_and_bytes_round_trip() {
+        for strategy in [Strategy::Logoot, Strategy::Lseq] {
+            assert_eq!(Strategy::from_byte(strategy.to_byte()), Some(strategy));
+        }
+        assert_eq!("lseq".parse(), Ok(Strategy::Lseq));
+        assert!("treedoc".parse::<Strategy>().is_err());
+        assert_eq!(Strategy::from_byte(2), None);
+    }
+}
@@ -4,23 +4,44 @@ use std::{
 };
 use anyhow::{anyhow, Context, Result};
 
+use crate::varint::{write_varint, write_varint_buf};
+
+/// Identifies a replica (a device editing a given document)
+pub type SiteId = u32;
+
+/// Site of the begin/end sentinel atoms of every document.
+pub const SENTINEL_SITE: SiteId = 0;
+/// Site used for the initial import of a note.
+pub const DEFAULT_SITE: SiteId = 1;
+/// Sites from this one upwards are handed out by the server.
+pub const FIRST_LEASED_SITE: SiteId = 2;
+/// Site stamped on local edits made before the server leased a site to this
+/// replica. Every replica shares it, so such PIDs are provisional and get
+/// re-stamped once a lease arrives. Never handed out by the server.
+pub const UNLEASED_SITE: SiteId = SiteId::MAX;
+
+/// Whether `site` is one the server hands out.
+pub fn is_leased(site: SiteId) -> bool {
+    (FIRST_LEASED_SITE..UNLEASED_SITE).contains(&site)
+}
+
 /// A single position in a PID
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
 pub struct Pos {
     pub ident: u32,
-    pub site: u8,
+    pub site: SiteId,
 }
 impl Pos {
-    pub fn new(ident: u32, site: u8) -> Pos {
+    pub fn new(ident: u32, site: SiteId) -> Pos {
         Pos { ident, site }
     }
     pub fn write_bytes_tobuf(&self, buf: &mut Vec<u8>) {
         buf.extend(&self.ident.to_le_bytes());
-        buf.push(self.site);
+        write_varint_buf(buf, self.site as u64);
     }
     pub fn write_bytes<W: Write>(&self, writer: &mut W) -> Result<()> {
         writer.write_all(&self.ident.to_le_bytes())?;
-        writer.write(&[self.site])?;
+        write_varint(writer, self.site as u64)?;
         Ok(())
     }
 }
@@ -1,32 +1,52 @@
-use std::{
-    io::{self, BufRead, Cursor, Read},
-    path::PathBuf,
-};
+use std::{io::Cursor, path::PathBuf};
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use serde::{Deserialize, Serialize};
 
-use crate::{doc::Doc, pid::Pid};
+use crate::{
+    decode::{DecodeError, DecodeResult, read_char, read_line, read_pid},
+    pid::Pid,
+    pos::SiteId,
+    varint::{read_varint, read_varint_u32, write_varint_buf},
+};
+
+/// Reason of the close frame that ends a session because its document got deleted.
+pub const DELETED_REASON: &str = "Document was deleted";
 
 #[derive(Debug, Clone)]
 pub enum SessionMessage {
     Start {
         document_id: u128,
         last_sync_time: u64,
+        // Site the server leased to us earlier for this document, 0 if none
+        site: SiteId,
         name: Option<PathBuf>,
+        // Identifies the installation the leases get bound to, 0 if anonymous
+        replica: u128,
     },
+    // `seq` numbers the ops a client sends in its session, the server acks
+    // them. Ops relayed from other participants carry 0.
     Insert {
-        site: u8,
+        site: SiteId,
+        seq: u64,
         pid: Pid,
         c: char,
     },
     Delete {
-        site: u8,
+        site: SiteId,
+        seq: u64,
         pid: Pid,
     },
     ChangeName {
         name: PathBuf,
     },
+    Started {
+        document_id: u128,
+        site: SiteId,
+    },
+    /// Every op of the session up to and including `seq` got applied
+    Ack {
+        seq: u64,
+    },
 }
 
 impl SessionMessage {
@@ -35,23 +55,30 @@ impl SessionMessage {
             SessionMessage::Start {
                 document_id,
                 last_sync_time,
+                site,
                 name,
+                replica,
             } => {
                 let mut buf = vec![64u8];
                 buf.extend(last_sync_time.to_le_bytes());
                 buf.extend(document_id.to_le_bytes());
+                write_varint_buf(&mut buf, *site as u64);
                 if let Some(name) = name {
                     buf.extend_from_slice(name.to_string_lossy().as_bytes());
                 }
                 buf.push(b'\n');
+                if *replica != 0 {
+                    buf.extend(replica.to_le_bytes());
+                }
                 buf
             }
 
-            SessionMessage::Insert { site, pid, c } => {
+            SessionMessage::Insert { site, seq, pid, c } => {
                 let mut buf = vec![65u8];
 
                 // site
-                buf.push(*site);
+                write_varint_buf(&mut buf, *site as u64);
+                write_varint_buf(&mut buf, *seq);
 
                 // encode character
                 let mut tmp = [0u8; 4];
@@ -72,10 +99,11 @@ impl SessionMessage {
                 buf
             }
 
-            SessionMessage::Delete { site, pid } => {
+            SessionMessage::Delete { site, seq, pid } => {
                 let mut buf = vec![66u8];
 
-                buf.push(*site);
+                write_varint_buf(&mut buf, *site as u64);
+                write_varint_buf(&mut buf, *seq);
 
                 buf.push(pid.depth() as u8);
 
@@ -93,63 +121,175 @@ impl SessionMessage {
 
                 buf
             }
+
+            SessionMessage::Started { document_id, site } => {
+                let mut buf = vec![68u8];
+                buf.extend(document_id.to_le_bytes());
+                write_varint_buf(&mut buf, *site as u64);
+                buf
+            }
+
+            SessionMessage::Ack { seq } => {
+                let mut buf = vec![69u8];
+                write_varint_buf(&mut buf, *seq);
+                buf
+            }
         }
     }
-    pub fn deserialize(buf: &[u8]) -> SessionMessage {
+    pub fn deserialize(buf: &[u8]) -> DecodeResult<SessionMessage> {
         let mut cur = Cursor::new(buf);
-        match cur.read_u8().unwrap() {
+        Ok(match cur.read_u8()? {
             64u8 => {
-                let last_sync_time = cur.read_u64::<LittleEndian>().unwrap();
-                let document_id = cur.read_u128::<LittleEndian>().unwrap();
-                let mut name_buf = Vec::new();
-                cur.read_until(b'\n', &mut name_buf).unwrap();
-
-                let name = (!name_buf.is_empty() && name_buf != b"\n").then(|| {
-                    name_buf.pop();
-                    PathBuf::from(String::from_utf8(name_buf).unwrap())
-                });
+                let last_sync_time = cur.read_u64::<LittleEndian>()?;
+                let document_id = cur.read_u128::<LittleEndian>()?;
+                let site = read_varint_u32(&mut cur)?;
+                let name = read_line(&mut cur)?;
+                // Clients that don't identify themselves end the message with the name
+                let replica = if cur.position() < buf.len() as u64 {
+                    cur.read_u128::<LittleEndian>()?
+                } else {
+                    0
+                };
 
                 SessionMessage::Start {
                     document_id,
                     last_sync_time,
-                    name,
+                    site,
+                    name: (!name.is_empty()).then(|| PathBuf::from(name)),
+                    replica,
                 }
             }
             65u8 => {
-                let site = cur.read_u8().unwrap();
-                let data_len = cur.read_u8().unwrap() as usize;
-                let mut bytes = [0u8; 4];
-                cur.read_exact(&mut bytes[..data_len]).unwrap();
-                let data = std::str::from_utf8(&bytes[..data_len])
-                    .unwrap()
-                    .chars()
-                    .next()
-                    .unwrap();
-                let pid_depth = cur.read_u8().unwrap();
-                let pid = Pid::read_bytes(&mut cur, pid_depth as usize);
+                let site = read_varint_u32(&mut cur)?;
+                let seq = read_varint(&mut cur)?;
+                let data = read_char(&mut cur)?;
+                let pid = read_pid(&mut cur)?;
                 SessionMessage::Insert {
                     site: site,
+                    seq,
                     pid: pid,
                     c: data,
                 }
             }
             66u8 => {
-                let site = cur.read_u8().unwrap();
-                let pid_depth = cur.read_u8().unwrap();
-                let pid = Pid::read_bytes(&mut cur, pid_depth as usize);
+                let site = read_varint_u32(&mut cur)?;
+                let seq = read_varint(&mut cur)?;
+                let pid = read_pid(&mut cur)?;
                 SessionMessage::Delete {
                     site: site,
+                    seq,
                     pid: pid,
                 }
             }
-            67u8 => {
-                let mut document_name = Vec::new();
-                cur.read_until(b'\n', &mut document_name);
-                SessionMessage::ChangeName {
-                    name: PathBuf::from(String::from_utf8(document_name).unwrap()),
-                }
+            67u8 => SessionMessage::ChangeName {
+                name: PathBuf::from(read_line(&mut cur)?),
+            },
+            68u8 => {
+                let document_id = cur.read_u128::<LittleEndian>()?;
+                let site = read_varint_u32(&mut cur)?;
+                SessionMessage::Started { document_id, site }
             }
-            _ => panic!(),
+            69u8 => SessionMessage::Ack {
+                seq: read_varint(&mut cur)?,
+            },
+            tag => return Err(DecodeError::UnknownTag(tag)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pos::Pos;
+
+    fn round_trip(msg: &SessionMessage) -> SessionMessage {
+        SessionMessage::deserialize(&msg.serialize()).unwrap()
+    }
+
+    #[test]
+    fn start_carries_the_replica_when_there_is_one() {
+        for replica in [0, 0xfeed_u128 << 64] {
+            let msg = SessionMessage::Start {
+                document_id: 42,
+                last_sync_time: 7,
+                site: 3,
+                name: Some(PathBuf::from("notes/a.md")),
+                replica,
+            };
+            let SessionMessage::Start {
+                document_id,
+                site,
+                name,
+                replica: decoded,
+                ..
+            } = round_trip(&msg)
+            else {
+                panic!("not a start");
+            };
+            assert_eq!((document_id, site, decoded), (42, 3, replica));
+            assert_eq!(name, Some(PathBuf::from("notes/a.md")));
+        }
+    }
+
+    #[test]
+    fn start_rejects_a_torn_replica() {
+        let mut buf = SessionMessage::Start {
+            document_id: 1,
+            last_sync_time: 0,
+            site: 0,
+            name: None,
+            replica: 9,
+        }
+        .serialize();
+        buf.truncate(buf.len() - 3);
+        assert!(SessionMessage::deserialize(&buf).is_err());
+    }
+
+    #[test]
+    fn ops_fail_on_every_truncation() {
+        let pid = Pid(vec![Pos::new(5, 2), Pos::new(7, 70_000)]);
+        let messages = [
+            SessionMessage::Insert {
+                site: 300,
+                seq: 1 << 20,
+                pid: pid.clone(),
+                c: '😀',
+            },
+            SessionMessage::Delete {
+                site: 300,
+                seq: 1 << 20,
+                pid,
+            },
+            SessionMessage::Started {
+                document_id: 3,
+                site: 300,
+            },
+            SessionMessage::Ack { seq: 1 << 20 },
+        ];
+        for msg in messages {
+            let buf = msg.serialize();
+            for len in 0..buf.len() {
+                let decoded = SessionMessage::deserialize(&buf[..len]);
+                assert!(decoded.is_err(), "{:?} cut at {}: {:?}", msg, len, decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn start_fails_when_cut_before_the_name() {
+        let buf = SessionMessage::Start {
+            document_id: 1,
+            last_sync_time: 0,
+            site: 300,
+            name: None,
+            replica: 0,
+        }
+        .serialize();
+        // The name runs till the end of the frame, so only what comes before it is checked
+        let name_at = 1 + 8 + 16 + 2;
+        for len in 0..name_at {
+            let decoded = SessionMessage::deserialize(&buf[..len]);
+            assert!(decoded.is_err(), "cut at {}", len);
         }
     }
 }
@@ -1,20 +1,158 @@
 use std::{
     fs::{self, File},
-    io::{BufReader, BufWriter, Write},
+    io::{Read, Write},
     path::{Path, PathBuf},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
 use uuid::Uuid;
 
 use crate::{
+    decode::check_count,
     doc::{Doc, DocChar},
-    pid::Pid,
+    pid::{Pid, Strategy},
+    pos::{SiteId, UNLEASED_SITE, is_leased},
     sync::DocOp,
+    varint::{read_varint, read_varint_u32, write_varint_buf},
 };
 
+/// Start of every versioned `.md.structure` file. Files from before the format
+/// got a version start straight with the document id instead.
+const STRUCTURE_MAGIC: [u8; 4] = *b"NTKS";
+/// 2 - sites of PIDs are varints, the header carries the local replica's site
+/// 3 - the header carries the atom count and a CRC32 of the whole file trails it
+/// 4 - the header carries the allocation strategy of the document
+const STRUCTURE_VERSION: u8 = 4;
+
+struct StructureHeader {
+    version: u8,
+    id: u128,
+    last_modified: u64,
+    site: SiteId,
+    /// Logoot for versions before 4
+    strategy: Strategy,
+    /// Number of atoms following the header, versions before 3 read until EOF
+    atoms: Option<usize>,
+}
+
+impl StructureHeader {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        if magic != STRUCTURE_MAGIC {
+            // Unversioned file, what we just read was the beginning of the id
+            let mut id_bytes = [0u8; 16];
+            id_bytes[..4].copy_from_slice(&magic);
+            reader.read_exact(&mut id_bytes[4..])?;
+            return Ok(StructureHeader {
+                version: 1,
+                id: u128::from_le_bytes(id_bytes),
+                last_modified: reader.read_u64::<LittleEndian>()?,
+                site: UNLEASED_SITE,
+                strategy: Strategy::Logoot,
+                atoms: None,
+            });
+        }
+
+        let version = reader.read_u8()?;
+        if !(2..=STRUCTURE_VERSION).contains(&version) {
+            return Err(anyhow!("Unsupported structure file version {}", version));
+        }
+        Ok(StructureHeader {
+            version,
+            id: reader.read_u128::<LittleEndian>()?,
+            last_modified: reader.read_u64::<LittleEndian>()?,
+            site: read_varint_u32(reader)?,
+            strategy: if version >= 4 {
+                let byte = reader.read_u8()?;
+                Strategy::from_byte(byte)
+                    .ok_or_else(|| anyhow!("Unknown allocation strategy {}", byte))?
+            } else {
+                Strategy::Logoot
+            },
+            atoms: if version >= 3 {
+                Some(check_count(read_varint(reader)?)?)
+            } else {
+                None
+            },
+        })
+    }
+
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&STRUCTURE_MAGIC);
+        buf.push(STRUCTURE_VERSION);
+        buf.extend_from_slice(&self.id.to_le_bytes());
+        buf.extend_from_slice(&self.last_modified.to_le_bytes());
+        write_varint_buf(buf, self.site as u64);
+        buf.push(self.strategy.to_byte());
+        write_varint_buf(buf, self.atoms.unwrap_or(0) as u64);
+    }
+
+    /// Reads the atoms following the header, old files get upgraded on their next flush.
+    fn read_doc<R: Read>(&self, reader: &mut R) -> Result<Doc> {
+        let mut doc = match (self.version, self.atoms) {
+            (1, _) => Doc::from_reader_eof_v1(reader)?,
+            (_, Some(atoms)) => Doc::from_reader(reader, atoms)?,
+            (_, None) => Doc::from_reader_eof(reader)?,
+        };
+        // Files from before leases were tracked say DEFAULT_SITE when none was leased
+        doc.site = if is_leased(self.site) { self.site } else { UNLEASED_SITE };
+        doc.strategy = self.strategy;
+        Ok(doc)
+    }
+}
+
+/// Reads a whole structure file. Version 3 files have their checksum and atom
+/// count verified, anything torn or flipped is an error rather than half a document.
+fn read_snapshot(path: &Path) -> Result<(StructureHeader, Doc)> {
+    let bytes = fs::read(path)?;
+
+    let versioned = bytes.len() > 4 && bytes[..4] == STRUCTURE_MAGIC;
+    let body = if versioned && bytes[4] >= 3 {
+        if bytes.len() < 9 {
+            return Err(anyhow!("Structure file {:?} is truncated", path));
+        }
+        let (body, trailer) = bytes.split_at(bytes.len() - 4);
+        let expected = u32::from_le_bytes(trailer.try_into().unwrap());
+        if crc32fast::hash(body) != expected {
+            return Err(anyhow!("Structure file {:?} failed its checksum", path));
+        }
+        body
+    } else {
+        &bytes[..]
+    };
+
+    let mut reader = body;
+    let header = StructureHeader::read_from(&mut reader)?;
+    let doc = header.read_doc(&mut reader)?;
+    if header.atoms.is_some() && !reader.is_empty() {
+        return Err(anyhow!("Structure file {:?} has trailing bytes", path));
+    }
+    Ok((header, doc))
+}
+
+/// Reads the structure file, falling back to the snapshot it replaced when the
+/// latest one is missing or corrupt.
+fn read_snapshot_or_backup(structure_path: &Path) -> Result<(StructureHeader, Doc)> {
+    let err = match read_snapshot(structure_path) {
+        Ok(snapshot) => return Ok(snapshot),
+        Err(e) => e,
+    };
+    match read_snapshot(&backup_path(structure_path)) {
+        Ok(snapshot) => {
+            eprintln!(
+                "Failed to read {:?} ({}), using the previous snapshot",
+                structure_path, err
+            );
+            Ok(snapshot)
+        }
+        Err(_) => Err(err),
+    }
+}
+
 /// Given a base name like `school/math/note.md`, returns `school/math/.note.md.structure`.
 fn hidden_structure_path(name: &Path) -> PathBuf {
     let parent = name.parent().unwrap_or(Path::new(""));
@@ -23,22 +161,66 @@ fn hidden_structure_path(name: &Path) -> PathBuf {
     parent.join(hidden_name)
 }
 
+/// The snapshot a flush replaced, kept next to the structure file.
+fn backup_path(structure_path: &Path) -> PathBuf {
+    structure_path.with_extension("structure.bak")
+}
+
+/// Moves the structure file of a document and its backup, if there is one.
+fn move_structure(from: &Path, to: &Path) -> Result<()> {
+    if from.exists() {
+        fs::rename(from, to)?;
+    }
+    if backup_path(from).exists() {
+        fs::rename(backup_path(from), backup_path(to))?;
+    }
+    Ok(())
+}
+
+/// Given a base name like `school/math/note.md`, returns `school/math/.note.md.tmp`,
+/// where flush writes the plaintext before moving it over the `.md`.
+fn plaintext_tmp_path(name: &Path) -> PathBuf {
+    let parent = name.parent().unwrap_or(Path::new(""));
+    let stem = name.file_stem().unwrap_or_default();
+    let hidden_name = format!(".{}.md.tmp", stem.to_string_lossy());
+    parent.join(hidden_name)
+}
+
+/// Whether `path` is a temporary file flush renames over a `.md`, so watchers
+/// can tell the regenerated plaintext apart from edits made by someone else.
+pub fn is_plaintext_tmp(path: &Path) -> bool {
+    let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    file_name.starts_with('.') && file_name.ends_with(".md.tmp")
+}
+
+/// Makes the renames in a directory durable, best effort since not every
+/// filesystem lets a directory be opened for it.
+fn sync_parent_dir(path: &Path) {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+}
+
 #[derive(Debug)]
 pub struct DocStructure {
     pub id: u128,
     pub last_modified: u64,
+    /// Path of the `.md` relative to `base_dir`, what the document is known by
     pub name: PathBuf,
+    /// Data directory the document's files live in
+    pub base_dir: PathBuf,
     pub state: DocState,
 }
 
 impl DocStructure {
     pub fn load_state(&mut self) -> Result<()> {
-        let structure_path = self.get_structure_path();
-
-        let file = File::open(structure_path)?;
-        let mut reader = BufReader::new(file);
-        reader.seek_relative(24)?;
-        let doc = Doc::from_reader_eof(&mut reader)?;
+        let (_, doc) = read_snapshot_or_backup(&self.get_structure_path())?;
         self.state = DocState::Cached(doc);
         Ok(())
     }
@@ -66,6 +248,21 @@ impl DocStructure {
         }
     }
 
+    /// Takes the site the server leased and moves the atoms generated before it
+    /// onto it, returning the (old, new) PIDs.
+    pub fn lease_site(&mut self, site: SiteId) -> Vec<(Pid, Pid)> {
+        if matches!(self.state, DocState::Missing) && self.load_state().is_err() {
+            return Vec::new();
+        }
+        match &mut self.state {
+            DocState::Missing => Vec::new(),
+            DocState::Cached(doc) => {
+                doc.site = site;
+                doc.restamp_provisional()
+            }
+        }
+    }
+
     pub fn insert_text_at_bytepos(&mut self, pos: usize, text: &str) -> Vec<(Pid, char)> {
         match &mut self.state {
             DocState::Missing => todo!(),
@@ -86,6 +283,64 @@ impl DocStructure {
         }
     }
 
+    pub fn insert_at_line_col(
+        &mut self,
+        line: usize,
+        col: usize,
+        text: &str,
+    ) -> Option<Vec<(Pid, char)>> {
+        match &mut self.state {
+            DocState::Missing => None,
+            DocState::Cached(doc) => doc.insert_at_line_col(line, col, text),
+        }
+    }
+
+    pub fn delete_line_col_range(
+        &mut self,
+        start_line: usize,
+        start_col: usize,
+        end_line: usize,
+        end_col: usize,
+    ) -> Option<Vec<Pid>> {
+        match &mut self.state {
+            DocState::Missing => None,
+            DocState::Cached(doc) => {
+                doc.delete_line_col_range(start_line, start_col, end_line, end_col)
+            }
+        }
+    }
+
+    /// Applies an insert made by another replica, returning the byte offset the
+    /// character landed at or None if it was already there or the document
+    /// isn't loaded.
+    pub fn apply_remote_insert(&mut self, pid: Pid, c: char) -> Option<usize> {
+        match &mut self.state {
+            DocState::Missing => None,
+            DocState::Cached(doc) => {
+                if doc.content.get(&pid).is_some() {
+                    return None;
+                }
+                doc.insert(pid.clone(), DocChar(c));
+                doc.byte_offset_of(&pid)
+            }
+        }
+    }
+
+    /// Applies a delete made by another replica, returning the byte offset and
+    /// byte length of the removed character or None if it was already gone or the
+    /// document isn't loaded.
+    pub fn apply_remote_delete(&mut self, pid: &Pid) -> Option<(usize, usize)> {
+        match &mut self.state {
+            DocState::Missing => None,
+            DocState::Cached(doc) => {
+                let offset = doc.byte_offset_of(pid)?;
+                let len = doc.content.get(pid)?.1.0.len_utf8();
+                doc.delete(pid);
+                Some((offset, len))
+            }
+        }
+    }
+
     pub fn applyOp(&mut self, op: DocOp) {
         match &mut self.state {
             DocState::Missing => todo!(),
@@ -95,24 +350,33 @@ impl DocStructure {
             },
         }
     }
-    pub fn create_new(name: &Path, doc_id: u128) -> Result<Self> {
+    /// New PIDs of the document get placed by `strategy`.
+    pub fn create_new(
+        base_dir: &Path,
+        name: &Path,
+        doc_id: u128,
+        strategy: Strategy,
+    ) -> Result<Self> {
         let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
 
-        let plaintext_path = name.with_extension("md");
+        let plaintext_path = base_dir.join(name).with_extension("md");
 
         let mut contents = String::new();
         if plaintext_path.exists() {
             contents = fs::read_to_string(&plaintext_path)?;
         }
 
+        let mut doc = Doc::new(&contents);
+        doc.strategy = strategy;
         let ds = DocStructure {
             id: doc_id,
             name: name.to_path_buf(),
+            base_dir: base_dir.to_path_buf(),
             last_modified: timestamp_ms,
-            state: DocState::Cached(Doc::new(&contents)),
+            state: DocState::Cached(doc),
         };
 
-        if let Some(parent) = name.parent() {
+        if let Some(parent) = plaintext_path.parent() {
             fs::create_dir_all(parent)?;
         }
 
@@ -120,71 +384,145 @@ impl DocStructure {
         Ok(ds)
     }
 
+    /// Writes the structure file as a whole to a temp file and renames it into place
+    /// once it's on disk. The snapshot it replaces is kept as `.bak` to fall back to.
     pub fn flush(&self) -> Result<()> {
         let structure_path = self.get_structure_path();
-        let file = File::create(&structure_path)?;
-        let mut writer = BufWriter::new(file);
 
-        writer.write_all(&self.id.to_le_bytes())?;
-        writer.write_all(&self.last_modified.to_le_bytes())?;
+        let (site, strategy, atoms) = match &self.state {
+            DocState::Cached(doc) => (doc.site, doc.strategy, doc.char_len()),
+            DocState::Missing => (UNLEASED_SITE, Strategy::default(), 0),
+        };
+        let header = StructureHeader {
+            version: STRUCTURE_VERSION,
+            id: self.id,
+            last_modified: self.last_modified,
+            site,
+            strategy,
+            atoms: Some(atoms),
+        };
+        let mut buf = Vec::new();
+        header.write_to(&mut buf);
+        if let DocState::Cached(doc) = &self.state {
+            doc.write_bytes_tobuf(&mut buf);
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+
+        let tmp_path = structure_path.with_extension("structure.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+
+        if structure_path.exists() {
+            fs::rename(&structure_path, backup_path(&structure_path))?;
+        }
+        fs::rename(&tmp_path, &structure_path)?;
+        sync_parent_dir(&structure_path);
 
         if let DocState::Cached(doc) = &self.state {
-            doc.write_bytes(&mut writer);
-            writer.flush()?;
-            // let human_readable_path = self.get_plainmd_path();
-            // let human_readable = doc.to_string();
-            // fs::write(human_readable_path, human_readable)?;
+            self.write_plaintext(&doc.to_string())?;
         }
 
         Ok(())
     }
 
+    /// Regenerate the human readable `.md`. Like the structure file it's written next
+    /// to it and renamed over it once it's on disk, so readers never see half a file.
+    /// Left alone when it's already up to date.
+    fn write_plaintext(&self, text: &str) -> Result<()> {
+        let plaintext_path = self.get_plainmd_path();
+        if fs::read_to_string(&plaintext_path).is_ok_and(|current| current == text) {
+            return Ok(());
+        }
+
+        let tmp_path = self.base_dir.join(plaintext_tmp_path(&self.name));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &plaintext_path)?;
+        sync_parent_dir(&plaintext_path);
+        Ok(())
+    }
+
     pub fn delete_files(&self) -> Result<()> {
-        fs::remove_file(self.get_structure_path())?;
+        let structure_path = self.get_structure_path();
+        fs::remove_file(&structure_path)?;
+        // A leftover backup would bring the document back under the same name
+        if backup_path(&structure_path).exists() {
+            fs::remove_file(backup_path(&structure_path))?;
+        }
         fs::remove_file(self.get_plainmd_path())?;
         Ok(())
     }
 
+    /// Moves the text of a document deleted elsewhere aside to `<name>.md.deleted`
+    /// and removes its structure files. Returns where the text went.
+    pub fn retire(&self) -> Result<PathBuf> {
+        let kept = self.get_plainmd_path().with_extension("md.deleted");
+        fs::rename(self.get_plainmd_path(), &kept)?;
+        let structure_path = self.get_structure_path();
+        fs::remove_file(&structure_path)?;
+        if backup_path(&structure_path).exists() {
+            fs::remove_file(backup_path(&structure_path))?;
+        }
+        Ok(kept)
+    }
+
     fn get_structure_path(&self) -> PathBuf {
-        hidden_structure_path(&self.name)
+        self.base_dir.join(hidden_structure_path(&self.name))
     }
 
     fn get_plainmd_path(&self) -> PathBuf {
-        self.name.with_extension("md")
+        self.base_dir.join(&self.name).with_extension("md")
     }
 
-    pub fn read_existing(structure_path: &Path, name: &Path) -> Result<Self> {
-        let file = File::open(structure_path)?;
-        let mut reader = BufReader::new(file);
-
-        let id = reader.read_u128::<LittleEndian>()?;
-        let last_modified = reader.read_u64::<LittleEndian>()?;
-        let doc = Doc::from_reader_eof(&mut reader)?;
+    /// Reads the document from the structure file at `structure_path`, its
+    /// other files are looked for as `name` in `base_dir`.
+    pub fn read_existing(structure_path: &Path, base_dir: &Path, name: &Path) -> Result<Self> {
+        let (header, doc) = read_snapshot_or_backup(structure_path)?;
 
         Ok(DocStructure {
-            id,
+            id: header.id,
             name: name.to_path_buf(),
-            last_modified,
+            base_dir: base_dir.to_path_buf(),
+            last_modified: header.last_modified,
             state: DocState::Cached(doc),
         })
     }
 
-    pub fn load_or_create(name: &Path, upsertid: Option<u128>) -> Result<Self> {
-        let structure_path = hidden_structure_path(name);
-
-        if structure_path.exists() {
-            Self::read_existing(&structure_path, name)
+    /// Existing documents keep the strategy they were created with.
+    pub fn load_or_create(
+        base_dir: &Path,
+        name: &Path,
+        upsertid: Option<u128>,
+        strategy: Strategy,
+    ) -> Result<Self> {
+        let structure_path = base_dir.join(hidden_structure_path(name));
+
+        // A crash between the renames of a flush leaves only the backup
+        if structure_path.exists() || backup_path(&structure_path).exists() {
+            Self::read_existing(&structure_path, base_dir, name)
         } else {
             let id = upsertid.unwrap_or(Uuid::new_v4().as_u128());
-            Self::create_new(name, id)
+            Self::create_new(base_dir, name, id, strategy)
         }
     }
 
+    /// Move the document's files to `name`, creating the folders it needs.
     pub fn set_name(&mut self, name: &Path) -> Result<()> {
         println!("old {:?} new {:?}", self.name, name);
 
-        fs::rename(self.get_structure_path(), hidden_structure_path(name))?;
-        fs::rename(self.get_plainmd_path(), name.with_extension("md"))?;
+        let plaintext_path = self.base_dir.join(name).with_extension("md");
+        if let Some(parent) = plaintext_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let structure_path = self.base_dir.join(hidden_structure_path(name));
+        move_structure(&self.get_structure_path(), &structure_path)?;
+        // Not there yet if the document was never flushed
+        if self.get_plainmd_path().exists() {
+            fs::rename(self.get_plainmd_path(), plaintext_path)?;
+        }
 
         self.name = name.to_path_buf();
         Ok(())
@@ -195,16 +533,14 @@ impl DocStructure {
     /// renamed by the OS / another process).
     pub fn update_name_after_external_rename(&mut self, new_name: &Path) -> Result<()> {
         let old_structure = self.get_structure_path();
-        let new_structure = hidden_structure_path(new_name);
+        let new_structure = self.base_dir.join(hidden_structure_path(new_name));
 
         // Ensure parent directories exist (e.g. moving note.md -> school/math/note.md)
         if let Some(parent) = new_structure.parent() {
             fs::create_dir_all(parent)?;
         }
 
-        if old_structure.exists() {
-            fs::rename(&old_structure, &new_structure)?;
-        }
+        move_structure(&old_structure, &new_structure)?;
 
         self.name = new_name.to_path_buf();
         Ok(())
@@ -216,3 +552,112 @@ enum DocState {
     Missing,
     Cached(Doc),
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("notek-structure-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn strategy_survives_a_reload() {
+        let dir = scratch_dir();
+        let name = Path::new("note.md");
+        fs::write(dir.join(name), "abc").unwrap();
+        DocStructure::create_new(&dir, name, 1, Strategy::Lseq).unwrap();
+
+        let structure_path = dir.join(hidden_structure_path(name));
+        let ds = DocStructure::read_existing(&structure_path, &dir, name).unwrap();
+        assert_eq!(ds.get_doc().strategy, Strategy::Lseq);
+        assert_eq!(ds.get_doc().to_string(), "abc");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A document flushed as "abc" and then as "abcd", so the backup holds "abc".
+    /// Returns the path of its structure file.
+    fn flushed_twice(dir: &Path) -> PathBuf {
+        let name = Path::new("note.md");
+        fs::write(dir.join(name), "abc").unwrap();
+        let mut ds = DocStructure::create_new(dir, name, 1, Strategy::default()).unwrap();
+        ds.insert_text_at_bytepos(3, "d");
+        ds.flush().unwrap();
+        dir.join(hidden_structure_path(name))
+    }
+
+    #[test]
+    fn a_corrupted_checksum_falls_back_to_the_backup() {
+        let dir = scratch_dir();
+        let structure_path = flushed_twice(&dir);
+        let name = Path::new("note.md");
+        let mut bytes = fs::read(&structure_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&structure_path, bytes).unwrap();
+
+        let ds = DocStructure::read_existing(&structure_path, &dir, name).unwrap();
+        assert_eq!(ds.get_doc().to_string(), "abc");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_truncated_file_falls_back_to_the_backup() {
+        let dir = scratch_dir();
+        let structure_path = flushed_twice(&dir);
+        let name = Path::new("note.md");
+        let len = fs::metadata(&structure_path).unwrap().len();
+        let file = File::options().write(true).open(&structure_path).unwrap();
+        file.set_len(len - 6).unwrap();
+
+        let ds = DocStructure::read_existing(&structure_path, &dir, name).unwrap();
+        assert_eq!(ds.get_doc().to_string(), "abc");
+
+        // Without a backup there's nothing to fall back to
+        fs::remove_file(backup_path(&structure_path)).unwrap();
+        assert!(DocStructure::read_existing(&structure_path, &dir, name).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_version_2_files() {
+        let dir = scratch_dir();
+        let name = Path::new("note.md");
+        let mut doc = Doc::new("zółw");
+        doc.site = 5;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&STRUCTURE_MAGIC);
+        buf.push(2);
+        buf.extend_from_slice(&7u128.to_le_bytes());
+        buf.extend_from_slice(&42u64.to_le_bytes());
+        write_varint_buf(&mut buf, 5);
+        doc.write_bytes_tobuf(&mut buf);
+        fs::write(dir.join(hidden_structure_path(name)), buf).unwrap();
+
+        let ds = DocStructure::load_or_create(&dir, name, None, Strategy::Lseq).unwrap();
+        assert_eq!((ds.id, ds.last_modified), (7, 42));
+        assert_eq!(ds.get_doc().to_string(), "zółw");
+        assert_eq!(ds.get_doc().site, 5);
+        assert_eq!(ds.get_doc().strategy, Strategy::Logoot);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retiring_keeps_the_text_and_drops_the_structure() {
+        let dir = scratch_dir();
+        let name = Path::new("note.md");
+        fs::write(dir.join(name), "abc").unwrap();
+        let ds = DocStructure::create_new(&dir, name, 1, Strategy::default()).unwrap();
+
+        let kept = ds.retire().unwrap();
+        assert_eq!(kept, dir.join("note.md.deleted"));
+        assert_eq!(fs::read_to_string(&kept).unwrap(), "abc");
+        assert!(!dir.join(name).exists());
+        assert!(!dir.join(hidden_structure_path(name)).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,13 +1,18 @@
 use std::{
-    io::{self, BufRead, Cursor, Read, Write},
-    panic,
+    io::{self, Cursor, Read, Write},
     path::PathBuf,
 };
 
 use anyhow::Result;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::{doc::Doc, pid::Pid};
+use crate::{
+    decode::{DecodeError, DecodeResult, check_count, read_char, read_line, read_pid},
+    doc::Doc,
+    pid::Pid,
+    pos::SiteId,
+    varint::{read_varint_u32, write_varint},
+};
 
 #[derive(Debug)]
 pub enum SyncRequests {
@@ -31,6 +36,11 @@ pub enum SyncRequests {
     },
     DeleteDoc {
         document_id: u128,
+        site: SiteId,
+    },
+    /// Undo a deletion, so that the document can be upserted again
+    RestoreDoc {
+        document_id: u128,
     },
 }
 
@@ -79,8 +89,10 @@ impl SyncRequests {
 
                 w.write_u64::<LittleEndian>(inserts.len() as u64)?;
                 for (pid, ch) in inserts {
-                    w.write_u8(1)?; // data_len
-                    w.write_u8(*ch as u8)?;
+                    let mut cbuf = [0u8; 4];
+                    let encoded = ch.encode_utf8(&mut cbuf);
+                    w.write_u8(encoded.len() as u8)?; // data_len
+                    w.write_all(encoded.as_bytes())?;
 
                     w.write_u8(pid.0.len() as u8)?;
                     pid.write_bytes(&mut w);
@@ -100,15 +112,21 @@ impl SyncRequests {
                 w.write_all(b"\n")?;
             }
 
-            SyncRequests::DeleteDoc { document_id } => {
+            SyncRequests::DeleteDoc { document_id, site } => {
                 w.write_u8(4)?;
                 w.write_u128::<LittleEndian>(*document_id)?;
+                write_varint(&mut w, *site as u64)?;
+            }
+
+            SyncRequests::RestoreDoc { document_id } => {
+                w.write_u8(5)?;
+                w.write_u128::<LittleEndian>(*document_id)?;
             }
         }
 
         Ok(())
     }
-    pub fn deserialize<R: Read>(reader: R) -> io::Result<Self> {
+    pub fn deserialize<R: Read>(reader: R) -> DecodeResult<Self> {
         let mut reader = io::BufReader::new(reader);
 
         let tag = reader.read_u8()?;
@@ -132,43 +150,26 @@ impl SyncRequests {
             2 => {
                 let document_id = reader.read_u128::<LittleEndian>()?;
 
-                let mut name_buf = Vec::new();
-                reader.read_until(b'\n', &mut name_buf)?;
-
-                let name = if name_buf.len() > 1 {
-                    name_buf.pop();
-                    Some(PathBuf::from(String::from_utf8(name_buf).map_err(
-                        |_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8"),
-                    )?))
-                } else {
-                    None
-                };
+                let name = read_line(&mut reader)?;
+                let name = (!name.is_empty()).then(|| PathBuf::from(name));
 
                 let last_sync_time = reader.read_u64::<LittleEndian>()?;
 
-                let insert_count = reader.read_u64::<LittleEndian>()?;
-                let mut inserts = Vec::with_capacity(insert_count as usize);
+                let insert_count = check_count(reader.read_u64::<LittleEndian>()?)?;
+                let mut inserts = Vec::with_capacity(insert_count);
 
                 for _ in 0..insert_count {
-                    let data_len = reader.read_u8()?;
-                    let mut data = vec![0u8; data_len as usize];
-                    reader.read_exact(&mut data)?;
-
-                    let ch = data[0] as char;
-
-                    let depth = reader.read_u8()?;
-                    let pid = Pid::read_bytes(&mut reader, depth as usize);
+                    let ch = read_char(&mut reader)?;
+                    let pid = read_pid(&mut reader)?;
 
                     inserts.push((pid, ch));
                 }
 
-                let delete_count = reader.read_u64::<LittleEndian>()?;
-                let mut deletes = Vec::with_capacity(delete_count as usize);
+                let delete_count = check_count(reader.read_u64::<LittleEndian>()?)?;
+                let mut deletes = Vec::with_capacity(delete_count);
 
                 for _ in 0..delete_count {
-                    let depth = reader.read_u8()?;
-                    let pid = Pid::read_bytes(&mut reader, depth as usize);
-                    deletes.push(pid);
+                    deletes.push(read_pid(&mut reader)?);
                 }
 
                 SyncRequests::SyncDocUpsert {
@@ -182,30 +183,23 @@ impl SyncRequests {
 
             3 => {
                 let document_id = reader.read_u128::<LittleEndian>()?;
-
-                let mut name_buf = Vec::new();
-                reader.read_until(b'\n', &mut name_buf)?;
-                name_buf.pop();
-
-                let name =
-                    PathBuf::from(String::from_utf8(name_buf).map_err(|_| {
-                        io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8")
-                    })?);
+                let name = PathBuf::from(read_line(&mut reader)?);
 
                 SyncRequests::DocNameChange { document_id, name }
             }
 
             4 => {
                 let document_id = reader.read_u128::<LittleEndian>()?;
-                SyncRequests::DeleteDoc { document_id }
+                let site = read_varint_u32(&mut reader)?;
+                SyncRequests::DeleteDoc { document_id, site }
             }
 
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Unknown request type",
-                ))
+            5 => {
+                let document_id = reader.read_u128::<LittleEndian>()?;
+                SyncRequests::RestoreDoc { document_id }
             }
+
+            tag => return Err(DecodeError::UnknownTag(tag)),
         })
     }
 }
@@ -216,13 +210,64 @@ pub enum SyncResponses<'a> {
         document_id: u128,
         name: PathBuf,
         doc: &'a Doc,
+        // When the document last changed, the client syncs from here next time
+        last_modified: u64,
+    },
+    /// Only what changed in the document since the client's last sync
+    SyncDocDelta {
+        document_id: u128,
+        name: PathBuf,
+        inserts: Vec<(&'a Pid, char)>,
+        deletes: Vec<&'a Pid>,
+        last_modified: u64,
+    },
+    /// The upserted document got deleted, it needs a RestoreDoc first
+    Tombstoned {
+        document_id: u128,
+        deleted_at: u64,
+    },
+    /// Everything in the upsert got applied and stored
+    UpsertAcked {
+        document_id: u128,
+    },
+    /// The server doesn't know the document, nor that it ever got deleted
+    NoSuchDoc {
+        document_id: u128,
+    },
+}
+
+/// A `SyncResponses` the way a client reads it.
+#[derive(Debug)]
+pub enum SyncReply {
+    SyncList(Vec<DocSyncInfo>),
+    SyncDoc {
+        document_id: u128,
+        name: PathBuf,
+        /// The whole document rather than what changed since the last sync,
+        /// atoms missing from it are gone
+        full: bool,
+        inserts: Vec<(Pid, char)>,
+        deletes: Vec<Pid>,
+        last_modified: u64,
+    },
+    Tombstoned {
+        document_id: u128,
+        deleted_at: u64,
+    },
+    UpsertAcked {
+        document_id: u128,
+    },
+    NoSuchDoc {
+        document_id: u128,
     },
 }
 
 #[derive(Debug)]
 pub struct DocSyncInfo {
-    last_mod_time: u64,
-    document_id: u128,
+    pub last_mod_time: u64,
+    pub document_id: u128,
+    /// The document is gone, `last_mod_time` is when it got deleted
+    pub deleted: bool,
 }
 
 impl DocSyncInfo {
@@ -230,6 +275,15 @@ impl DocSyncInfo {
         Self {
             last_mod_time,
             document_id,
+            deleted: false,
+        }
+    }
+
+    pub fn deleted(deleted_at: u64, document_id: u128) -> Self {
+        Self {
+            last_mod_time: deleted_at,
+            document_id,
+            deleted: true,
         }
     }
 }
@@ -243,35 +297,144 @@ impl SyncResponses<'_> {
                 for doc in doc_sync_infos {
                     w.write_all(&doc.last_mod_time.to_le_bytes())?;
                     w.write_all(&doc.document_id.to_le_bytes())?;
+                    w.write_all(&[doc.deleted as u8])?;
                 }
             }
             SyncResponses::SyncDoc {
                 document_id,
                 name,
                 doc,
+                last_modified,
             } => {
                 w.write_all(&[33u8])?;
                 w.write_all(&document_id.to_le_bytes())?;
-                w.write_all(name.to_string_lossy().as_bytes());
-                w.write_all(&[b'\n']);
+                w.write_all(name.to_string_lossy().as_bytes())?;
+                w.write_all(&[b'\n'])?;
                 // Number of insert atoms:
                 w.write_all(&(doc.char_len() as u64).to_le_bytes())?;
                 doc.write_bytes(&mut w)?;
                 // Number of delete atoms:
                 w.write_all(&(0 as u64).to_le_bytes())?;
+                w.write_all(&last_modified.to_le_bytes())?;
+            }
+            SyncResponses::SyncDocDelta {
+                document_id,
+                name,
+                inserts,
+                deletes,
+                last_modified,
+            } => {
+                w.write_all(&[34u8])?;
+                w.write_all(&document_id.to_le_bytes())?;
+                w.write_all(name.to_string_lossy().as_bytes())?;
+                w.write_all(&[b'\n'])?;
+                w.write_all(&(inserts.len() as u64).to_le_bytes())?;
+                for (pid, c) in inserts {
+                    let mut cbuf = [0u8; 4];
+                    let encoded = c.encode_utf8(&mut cbuf);
+                    w.write_all(&[encoded.len() as u8])?;
+                    w.write_all(encoded.as_bytes())?;
+                    w.write_all(&[pid.depth() as u8])?;
+                    pid.write_bytes(&mut w)?;
+                }
+                w.write_all(&(deletes.len() as u64).to_le_bytes())?;
+                for pid in deletes {
+                    w.write_all(&[pid.depth() as u8])?;
+                    pid.write_bytes(&mut w)?;
+                }
+                w.write_all(&last_modified.to_le_bytes())?;
+            }
+            SyncResponses::Tombstoned {
+                document_id,
+                deleted_at,
+            } => {
+                w.write_all(&[35u8])?;
+                w.write_all(&document_id.to_le_bytes())?;
+                w.write_all(&deleted_at.to_le_bytes())?;
+            }
+            SyncResponses::UpsertAcked { document_id } => {
+                w.write_all(&[36u8])?;
+                w.write_all(&document_id.to_le_bytes())?;
+            }
+            SyncResponses::NoSuchDoc { document_id } => {
+                w.write_all(&[37u8])?;
+                w.write_all(&document_id.to_le_bytes())?;
             }
         }
         Ok(())
     }
+
+    pub fn deserialize(buf: &[u8]) -> DecodeResult<SyncReply> {
+        let mut cur = Cursor::new(buf);
+        Ok(match cur.read_u8()? {
+            32 => {
+                let count = check_count(cur.read_u64::<LittleEndian>()?)?;
+                let mut docs = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let last_mod_time = cur.read_u64::<LittleEndian>()?;
+                    let document_id = cur.read_u128::<LittleEndian>()?;
+                    docs.push(match cur.read_u8()? {
+                        0 => DocSyncInfo::new(last_mod_time, document_id),
+                        _ => DocSyncInfo::deleted(last_mod_time, document_id),
+                    });
+                }
+                SyncReply::SyncList(docs)
+            }
+            tag @ (33 | 34) => {
+                let document_id = cur.read_u128::<LittleEndian>()?;
+                let name = PathBuf::from(read_line(&mut cur)?);
+
+                let insert_count = check_count(cur.read_u64::<LittleEndian>()?)?;
+                let mut inserts = Vec::with_capacity(insert_count);
+                for _ in 0..insert_count {
+                    let c = read_char(&mut cur)?;
+                    inserts.push((read_pid(&mut cur)?, c));
+                }
+
+                let delete_count = check_count(cur.read_u64::<LittleEndian>()?)?;
+                let mut deletes = Vec::with_capacity(delete_count);
+                for _ in 0..delete_count {
+                    deletes.push(read_pid(&mut cur)?);
+                }
+
+                SyncReply::SyncDoc {
+                    document_id,
+                    name,
+                    full: tag == 33,
+                    inserts,
+                    deletes,
+                    last_modified: cur.read_u64::<LittleEndian>()?,
+                }
+            }
+            35 => SyncReply::Tombstoned {
+                document_id: cur.read_u128::<LittleEndian>()?,
+                deleted_at: cur.read_u64::<LittleEndian>()?,
+            },
+            36 => SyncReply::UpsertAcked {
+                document_id: cur.read_u128::<LittleEndian>()?,
+            },
+            37 => SyncReply::NoSuchDoc {
+                document_id: cur.read_u128::<LittleEndian>()?,
+            },
+            tag => return Err(DecodeError::UnknownTag(tag)),
+        })
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum DocOp {
     Insert(Pid, char),
     Delete(Pid),
 }
 
 impl DocOp {
+    /// The atom the op inserts or deletes.
+    pub fn pid(&self) -> &Pid {
+        match self {
+            DocOp::Insert(pid, _) | DocOp::Delete(pid) => pid,
+        }
+    }
+
     pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
         let mut tag = [0u8; 1];
         reader.read_exact(&mut tag)?;
@@ -297,14 +460,14 @@ impl DocOp {
 
                 let mut depth_buf = [0u8; 1];
                 reader.read_exact(&mut depth_buf)?;
-                let pid = Pid::read_bytes(reader, depth_buf[0] as usize);
+                let pid = Pid::read_bytes(reader, depth_buf[0] as usize)?;
 
                 Ok(DocOp::Insert(pid, ch))
             }
             1 => {
                 let mut depth_buf = [0u8; 1];
                 reader.read_exact(&mut depth_buf)?;
-                let pid = Pid::read_bytes(reader, depth_buf[0] as usize);
+                let pid = Pid::read_bytes(reader, depth_buf[0] as usize)?;
 
                 Ok(DocOp::Delete(pid))
             }
@@ -382,3 +545,223 @@ impl DocOp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::decode::{MAX_COUNT, MAX_PID_DEPTH};
+    use crate::pos::Pos;
+
+    fn pid(ident: u32, site: SiteId) -> Pid {
+        Pid(vec![Pos::new(ident, site)])
+    }
+
+    fn roundtrip(inserts: Vec<(Pid, char)>, deletes: Vec<Pid>) {
+        let req = SyncRequests::SyncDocUpsert {
+            document_id: 42,
+            name: Some(PathBuf::from("notes/zażółć.md")),
+            last_sync_time: 7,
+            inserts: inserts.clone(),
+            deletes: deletes.clone(),
+        };
+        let buf = req.serialize();
+        match SyncRequests::deserialize(Cursor::new(&buf)).unwrap() {
+            SyncRequests::SyncDocUpsert {
+                document_id,
+                name,
+                last_sync_time,
+                inserts: got_inserts,
+                deletes: got_deletes,
+            } => {
+                assert_eq!(document_id, 42);
+                assert_eq!(name, Some(PathBuf::from("notes/zażółć.md")));
+                assert_eq!(last_sync_time, 7);
+                assert_eq!(got_inserts, inserts);
+                assert_eq!(got_deletes, deletes);
+            }
+            other => panic!("Decoded into {:?}", other),
+        }
+    }
+
+    #[test]
+    fn upsert_roundtrip_ascii() {
+        let inserts = "hello".chars().enumerate().map(|(i, c)| (pid(i as u32 + 1, 2), c)).collect();
+        roundtrip(inserts, vec![pid(9, 3)]);
+    }
+
+    #[test]
+    fn upsert_roundtrip_multilingual() {
+        let text = "łódź é ß 東京 😀 🇵🇱";
+        let inserts = text.chars().enumerate().map(|(i, c)| (pid(i as u32 + 1, 300), c)).collect();
+        roundtrip(inserts, Vec::new());
+    }
+
+    #[test]
+    fn upsert_roundtrip_utf8_length_boundaries() {
+        let chars = [
+            '\u{0}', '\u{7f}', '\u{80}', '\u{7ff}', '\u{800}', '\u{d7ff}', '\u{e000}',
+            '\u{fffd}', '\u{ffff}', '\u{10000}', '\u{10ffff}',
+        ];
+        let inserts = chars.iter().enumerate().map(|(i, &c)| (pid(i as u32, 2), c)).collect();
+        roundtrip(inserts, Vec::new());
+    }
+
+    #[test]
+    fn upsert_roundtrip_whole_unicode_range() {
+        let inserts = (0..=char::MAX as u32)
+            .filter_map(char::from_u32)
+            .enumerate()
+            .map(|(i, c)| (pid(i as u32, (i % 1000) as SiteId), c))
+            .collect();
+        roundtrip(inserts, Vec::new());
+    }
+
+    #[test]
+    fn upsert_rejects_multiple_chars_in_one_atom() {
+        let mut buf = SyncRequests::SyncDocUpsert {
+            document_id: 1,
+            name: None,
+            last_sync_time: 0,
+            inserts: vec![(pid(1, 2), 'a')],
+            deletes: Vec::new(),
+        }
+        .serialize();
+        // u8 tag, u128 id, empty name, u64 time, u64 count, then the atom's data_len
+        let data_len_at = 1 + 16 + 1 + 8 + 8;
+        buf[data_len_at] = 2;
+        buf.insert(data_len_at + 1, b'b');
+        assert!(matches!(
+            SyncRequests::deserialize(Cursor::new(&buf)),
+            Err(DecodeError::BadUtf8)
+        ));
+    }
+
+    fn deep_pid() -> Pid {
+        Pid(vec![
+            Pos::new(5, 2),
+            Pos::new(u32::MAX - 1, 300),
+            Pos::new(7, 70_000),
+        ])
+    }
+
+    /// Cutting a frame anywhere short of its end has to fail, not panic or
+    /// decode into something else.
+    fn assert_every_prefix_fails<T: std::fmt::Debug>(
+        buf: &[u8],
+        decode: impl Fn(&[u8]) -> DecodeResult<T>,
+    ) {
+        decode(buf).unwrap();
+        for len in 0..buf.len() {
+            let decoded = decode(&buf[..len]);
+            assert!(decoded.is_err(), "cut at {}: {:?}", len, decoded);
+        }
+    }
+
+    #[test]
+    fn requests_fail_on_every_truncation() {
+        let requests = [
+            SyncRequests::SyncList { last_sync_time: 9 },
+            SyncRequests::SyncDoc {
+                document_id: 3,
+                last_sync_time: 9,
+            },
+            SyncRequests::SyncDocUpsert {
+                document_id: 3,
+                name: Some(PathBuf::from("a/żółw.md")),
+                last_sync_time: 9,
+                inserts: vec![(pid(1, 2), 'ł'), (deep_pid(), '😀')],
+                deletes: vec![deep_pid()],
+            },
+            SyncRequests::DeleteDoc {
+                document_id: 3,
+                site: 300,
+            },
+            SyncRequests::RestoreDoc { document_id: 3 },
+        ];
+        for req in requests {
+            assert_every_prefix_fails(&req.serialize(), |b| {
+                SyncRequests::deserialize(Cursor::new(b))
+            });
+        }
+    }
+
+    #[test]
+    fn responses_fail_on_every_truncation() {
+        let doc = Doc::new("zażółć 😀");
+        let (a, b) = (pid(1, 2), deep_pid());
+        let responses = [
+            SyncResponses::SyncList(vec![DocSyncInfo::new(4, 1), DocSyncInfo::deleted(5, 2)]),
+            SyncResponses::SyncDoc {
+                document_id: 3,
+                name: PathBuf::from("a/żółw.md"),
+                doc: &doc,
+                last_modified: 8,
+            },
+            SyncResponses::SyncDocDelta {
+                document_id: 3,
+                name: PathBuf::from("a/żółw.md"),
+                inserts: vec![(&a, 'ł'), (&b, '😀')],
+                deletes: vec![&b],
+                last_modified: 8,
+            },
+            SyncResponses::Tombstoned {
+                document_id: 3,
+                deleted_at: 8,
+            },
+            SyncResponses::UpsertAcked { document_id: 3 },
+            SyncResponses::NoSuchDoc { document_id: 3 },
+        ];
+        for r in responses {
+            let mut buf = Vec::new();
+            r.serialize_into(&mut buf).unwrap();
+            assert_every_prefix_fails(&buf, SyncResponses::deserialize);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_counts() {
+        let mut buf = SyncRequests::SyncDocUpsert {
+            document_id: 1,
+            name: None,
+            last_sync_time: 0,
+            inserts: Vec::new(),
+            deletes: Vec::new(),
+        }
+        .serialize();
+        // u8 tag, u128 id, empty name, u64 time, then the insert count
+        let count_at = 1 + 16 + 1 + 8;
+        buf[count_at..count_at + 8].copy_from_slice(&(MAX_COUNT + 1).to_le_bytes());
+        assert!(matches!(
+            SyncRequests::deserialize(Cursor::new(&buf)),
+            Err(DecodeError::OversizedCount(_))
+        ));
+
+        let mut buf = vec![32];
+        buf.extend(u64::MAX.to_le_bytes());
+        assert!(matches!(
+            SyncResponses::deserialize(&buf),
+            Err(DecodeError::OversizedCount(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_pids_deeper_than_the_limit() {
+        let too_deep = Pid(vec![Pos::new(1, 2); MAX_PID_DEPTH + 1]);
+        let mut buf = Vec::new();
+        SyncResponses::SyncDocDelta {
+            document_id: 3,
+            name: PathBuf::new(),
+            inserts: Vec::new(),
+            deletes: vec![&too_deep],
+            last_modified: 8,
+        }
+        .serialize_into(&mut buf)
+        .unwrap();
+        assert!(matches!(
+            SyncResponses::deserialize(&buf),
+            Err(DecodeError::PidTooDeep(_))
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+//! LEB128 encoded unsigned integers: 7 bits per byte, high bit set on every
+//! byte but the last. Small numbers (most site ids) take a single byte.
+use std::io::{self, Read, Write};
+
+use byteorder::ReadBytesExt;
+
+pub fn write_varint_buf(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn write_varint<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(10);
+    write_varint_buf(&mut buf, value);
+    writer.write_all(&buf)
+}
+
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = reader.read_u8()?;
+        // The 10th byte only has room for the top bit
+        if shift == 63 && byte > 1 {
+            break;
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "Varint too long"))
+}
+
+pub fn read_varint_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    u32::try_from(read_varint(reader)?)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Varint overflows u32"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, ErrorKind};
+
+    use super::*;
+
+    fn read(bytes: &[u8]) -> io::Result<u64> {
+        read_varint(&mut Cursor::new(bytes))
+    }
+
+    #[test]
+    fn round_trips_at_every_length() {
+        for value in [0, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint_buf(&mut buf, value);
+            assert_eq!(read(&buf).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn rejects_overlong_varints() {
+        let mut too_long = vec![0x80; 10];
+        too_long.push(0);
+        // Ten bytes, but the last one carries bits past u64
+        let mut overflowing = vec![0xff; 9];
+        overflowing.push(0x02);
+        for bytes in [too_long, overflowing] {
+            let e = read(&bytes).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn rejects_sites_past_u32() {
+        let mut buf = Vec::new();
+        write_varint_buf(&mut buf, u32::MAX as u64 + 1);
+        let e = read_varint_u32(&mut Cursor::new(&buf)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn torn_varints_are_eof() {
+        let e = read(&[0x80, 0x80]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
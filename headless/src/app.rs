@@ -1,22 +1,150 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
 
+use algos::pid::Pid;
+use algos::pos::{SENTINEL_SITE, is_leased};
 use algos::session::SessionMessage;
-use algos::sync::SyncRequests;
+use algos::structure::DocStructure;
+use algos::sync::{DocOp, SyncReply, SyncRequests};
 
-use crate::editor_message::EditorMessage;
+use crate::diff;
+use crate::editor_message::{DaemonMessage, EditorMessage};
 use crate::oplog::OplogMsg;
 use crate::state::{ConnectionStatus, State};
+use crate::sync_times::SyncTimes;
+
+/// How long remote changes have to settle before the current document gets flushed.
+const REMOTE_FLUSH_DELAY: Duration = Duration::from_millis(500);
 
 pub enum AppEvent {
     FileCreated(PathBuf),
     FileRenamed { from: PathBuf, to: PathBuf },
+    FileModified(PathBuf),
     EditorMsg(EditorMessage),
+    ClientConnected(UnixStream),
     ClientDisconnected,
     SyncConnected,
     SyncDisconnected,
     SessionConnected,
     SessionDisconnected,
+    SessionMsg(SessionMessage),
+    SyncMsg(SyncReply),
+    /// The server closed the session because the document got deleted
+    DocDeleted(u128),
+}
+
+/// The connected editor, if there is one
+#[derive(Default)]
+struct Editor {
+    stream: Option<UnixStream>,
+    /// Edits of the editor applied to the current document, pushes carry it
+    /// so the editor can tell which of its own edits they haven't seen
+    rev: u32,
+}
+
+impl Editor {
+    /// Writes a message to the editor, forgetting it if the socket is gone.
+    fn push(&mut self, msg: DaemonMessage) {
+        if let Some(stream) = &mut self.stream
+            && let Err(e) = stream.write_all(&msg.serialize(self.rev))
+        {
+            eprintln!("Failed to push to editor: {}", e);
+            self.stream = None;
+        }
+    }
+}
+
+/// Applies what the server sent for a document, returning how its text
+/// changed, in the order the editor has to make the changes.
+fn apply_sync_doc(
+    ds: &mut DocStructure,
+    full: bool,
+    inserts: Vec<(Pid, char)>,
+    mut deletes: Vec<Pid>,
+) -> Vec<DaemonMessage> {
+    if full {
+        // Atoms missing from the snapshot got deleted, unless they were made
+        // here and haven't reached the server yet
+        let site = ds.get_doc().site;
+        let snapshot: HashSet<&Pid> = inserts.iter().map(|(pid, _)| pid).collect();
+        let gone = ds
+            .get_doc()
+            .content
+            .iter()
+            .map(|(pid, _)| pid)
+            .filter(|pid| {
+                let made_by = pid.0.last().map_or(SENTINEL_SITE, |pos| pos.site);
+                !snapshot.contains(pid)
+                    && made_by != site
+                    && made_by != SENTINEL_SITE
+                    && !pid.is_provisional()
+            });
+        deletes.extend(gone.cloned());
+    }
+
+    let mut changes = Vec::new();
+    for (pid, c) in inserts {
+        if let Some(offset) = ds.apply_remote_insert(pid, c) {
+            changes.push(DaemonMessage::RemoteInsert(offset as u32, c.to_string()));
+        }
+    }
+    for pid in &deletes {
+        if let Some((offset, len)) = ds.apply_remote_delete(pid) {
+            changes.push(DaemonMessage::RemoteDelete(offset as u32, len as u32));
+        }
+    }
+    changes
+}
+
+/// Sends the whole document along with its name, creating it on the server if it's not there.
+fn upsert_whole_doc(ds: &DocStructure, sync_times: &SyncTimes, sync_tx: &Sender<SyncRequests>) {
+    let inserts: Vec<_> = ds
+        .get_doc()
+        .content
+        .iter()
+        .map(|(k, v)| (k.clone(), v.0))
+        .collect();
+    let msg = SyncRequests::SyncDocUpsert {
+        document_id: ds.id,
+        name: Some(ds.name.clone()),
+        last_sync_time: sync_times.doc(ds.id),
+        inserts,
+        deletes: Vec::new(),
+    };
+    let _ = sync_tx.send(msg);
+}
+
+/// Sets aside a document that got deleted on the server, it only comes back
+/// through a restore. The text stays next to it as `<name>.md.deleted`, its
+/// queued ops go since the server would refuse them. Returns whether it was
+/// the current document.
+fn retire_deleted_doc(
+    state: &mut State,
+    editor: &mut Editor,
+    oplog_tx: &Sender<OplogMsg>,
+    document_id: u128,
+) -> bool {
+    let was_current =
+        state.current_doc != usize::MAX && state.docs[state.current_doc].id == document_id;
+    let Some(ds) = state.remove_doc(document_id) else {
+        return false;
+    };
+    println!("Document {:?} got deleted on the server", ds.name);
+    let _ = oplog_tx.send(OplogMsg::DocDeleted { document_id });
+    state.sync_times.forget_doc(document_id);
+    match ds.retire() {
+        Ok(kept) if was_current => {
+            editor.push(DaemonMessage::Renamed(kept));
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to set aside deleted doc {:?}: {}", ds.name, e),
+    }
+    was_current
 }
 
 pub fn run_app(
@@ -25,80 +153,311 @@ pub fn run_app(
     oplog_tx: Sender<OplogMsg>,
     sync_tx: Sender<SyncRequests>,
 ) {
+    let mut editor = Editor::default();
+    // Remote changes applied to the current document since it was last flushed
+    let mut remote_changes = false;
     // Main event loop — State stays here, single-threaded mutations
-    while let Ok(event) = rx.recv() {
+    loop {
+        let event = match rx.recv_timeout(REMOTE_FLUSH_DELAY) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                if remote_changes {
+                    if let Err(e) = state.flush_current_doc() {
+                        eprintln!("Failed to flush remote changes: {}", e);
+                    }
+                    remote_changes = false;
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
         match event {
             AppEvent::FileCreated(path) => {
                 println!("Adding new document: {:?}", path);
-                let doc = match state.add_doc(path.clone(), None) {
-                    Ok(doc) => doc,
+                let document_id = match state.add_doc(path.clone(), None) {
+                    Ok(doc) => doc.id,
                     Err(e) => {
                         eprintln!("Failed to add document: {}", e);
                         continue;
                     }
                 };
-                let inserts: Vec<_> = doc
-                    .get_doc()
-                    .content
-                    .iter()
-                    .map(|(k, v)| (k.clone(), v.0))
-                    .collect();
-                let msg = SyncRequests::SyncDocUpsert {
-                    document_id: doc.id,
-                    name: Some(path),
-                    last_sync_time: 0,
-                    inserts,
-                    deletes: Vec::new(),
-                };
-                let _ = sync_tx.send(msg);
+                let _ = oplog_tx.send(OplogMsg::DocAdded {
+                    document_id,
+                    name: path,
+                });
+                let doc = &state.docs[state.by_id[&document_id]];
+                upsert_whole_doc(doc, &state.sync_times, &sync_tx);
             }
             AppEvent::FileRenamed { from, to } => {
                 state.move_doc(from, to.clone());
+                if state.is_current_doc(&to) {
+                    let msg = DaemonMessage::Renamed(state.base_dir.join(&to));
+                    editor.push(msg);
+                }
                 let doc_id = state.get_doc_by_name(&to).id;
+                let _ = oplog_tx.send(OplogMsg::DocRenamed {
+                    document_id: doc_id,
+                    name: to.clone(),
+                });
                 let msg = SyncRequests::DocNameChange {
                     document_id: doc_id,
                     name: to,
                 };
                 let _ = sync_tx.send(msg);
             }
+            AppEvent::FileModified(path) => {
+                // Written by something other than the editor, merge it in like any edit
+                let Some(&idx) = state.by_name.get(&path) else {
+                    continue;
+                };
+                let text = match fs::read_to_string(state.base_dir.join(&path)) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        eprintln!("Failed to read modified file {:?}: {}", path, e);
+                        continue;
+                    }
+                };
+                let hunks = diff::hunks(&state.docs[idx].get_doc().to_string(), &text);
+                if hunks.is_empty() {
+                    continue;
+                }
+                println!("Merging {} external changes into {:?}", hunks.len(), path);
+                let ops = state.apply_hunks(idx, &hunks);
+                if let Err(e) = state.docs[idx].flush() {
+                    eprintln!("Failed to flush {:?}: {}", path, e);
+                }
+
+                if idx != state.current_doc {
+                    let document_id = state.docs[idx].id;
+                    let _ = oplog_tx.send(OplogMsg::DocOps { document_id, ops });
+                    continue;
+                }
+                // The editor has the old text in its buffer
+                for hunk in hunks.iter().rev() {
+                    if hunk.delete_len > 0 {
+                        let msg = DaemonMessage::RemoteDelete(
+                            hunk.start_byte as u32,
+                            hunk.delete_len as u32,
+                        );
+                        editor.push(msg);
+                    }
+                    if !hunk.insert.is_empty() {
+                        let msg = DaemonMessage::RemoteInsert(
+                            hunk.start_byte as u32,
+                            hunk.insert.clone(),
+                        );
+                        editor.push(msg);
+                    }
+                }
+                let site = state.get_current_doc_site();
+                for op in ops {
+                    let msg = match op {
+                        DocOp::Insert(pid, c) => SessionMessage::Insert {
+                            site,
+                            seq: 0,
+                            pid,
+                            c,
+                        },
+                        DocOp::Delete(pid) => SessionMessage::Delete { site, seq: 0, pid },
+                    };
+                    let _ = oplog_tx.send(OplogMsg::SessionMessage(msg));
+                }
+            }
+            AppEvent::EditorMsg(msg)
+                if state.current_doc == usize::MAX
+                    && !matches!(msg, EditorMessage::ChooseDocument(_)) =>
+            {
+                println!("No document chosen, ignoring {:?}", msg);
+            }
             AppEvent::EditorMsg(msg) => match msg {
                 EditorMessage::ChooseDocument(doc_name) => {
+                    // Switching flushes the document being left
+                    remote_changes = false;
+                    editor.rev = 0;
                     state.set_current_doc(&doc_name);
+                    // The buffer was read from the .md file, which can lag behind the CRDT
+                    let text = state.get_current_doc_crdt().to_string();
+                    if fs::read_to_string(&doc_name).ok().as_ref() != Some(&text) {
+                        editor.push(DaemonMessage::Replace(text));
+                    }
+                    let site = state.get_current_doc_site();
+                    let document_id = state.get_current_doc_id();
                     let msg = SessionMessage::Start {
-                        document_id: state.get_current_doc_id(),
-                        last_sync_time: 0,
+                        document_id,
+                        last_sync_time: state.sync_times.doc(document_id),
+                        site: if is_leased(site) { site } else { 0 },
                         name: None,
+                        replica: 0,
                     };
                     let _ = oplog_tx.send(OplogMsg::SessionMessage(msg));
                 }
                 EditorMessage::Insert(pos, text) => {
                     println!("Text received {} {}", pos, text);
+                    editor.rev += 1;
                     let inserted = state.insert_in_current_doc(pos, &text);
+                    let site = state.get_current_doc_site();
                     for (pid, c) in inserted {
-                        let msg = SessionMessage::Insert { site: 0, pid, c };
+                        let msg = SessionMessage::Insert { site, seq: 0, pid, c };
                         let _ = oplog_tx.send(OplogMsg::SessionMessage(msg));
                     }
                 }
                 EditorMessage::Delete(start, len) => {
                     println!("Text deleted from range: {} {}", start, len);
+                    editor.rev += 1;
                     let deleted = state.delete_in_current_doc(start, len);
+                    let site = state.get_current_doc_site();
                     for pid in deleted {
-                        let msg = SessionMessage::Delete { site: 0, pid };
+                        let msg = SessionMessage::Delete { site, seq: 0, pid };
                         let _ = oplog_tx.send(OplogMsg::SessionMessage(msg));
                     }
                 }
                 EditorMessage::Flush => {
                     let _ = state.flush_current_doc();
+                    let _ = oplog_tx.send(OplogMsg::Flush);
+                }
+                EditorMessage::Resync => {
+                    let text = state.get_current_doc_crdt().to_string();
+                    editor.push(DaemonMessage::Replace(text));
+                }
+                EditorMessage::InsertAt(line, col, text) => {
+                    println!("Text received {}:{} {}", line, col, text);
+                    editor.rev += 1;
+                    let Some(inserted) = state.insert_at_line_col_in_current_doc(line, col, &text)
+                    else {
+                        println!("Insert position {}:{} is outside the document", line, col);
+                        continue;
+                    };
+                    let site = state.get_current_doc_site();
+                    for (pid, c) in inserted {
+                        let msg = SessionMessage::Insert {
+                            site,
+                            seq: 0,
+                            pid,
+                            c,
+                        };
+                        let _ = oplog_tx.send(OplogMsg::SessionMessage(msg));
+                    }
+                }
+                EditorMessage::DeleteRange(start_line, start_col, end_line, end_col) => {
+                    println!(
+                        "Text deleted from range: {}:{} {}:{}",
+                        start_line, start_col, end_line, end_col
+                    );
+                    editor.rev += 1;
+                    let Some(deleted) = state.delete_line_col_range_in_current_doc(
+                        (start_line, start_col),
+                        (end_line, end_col),
+                    ) else {
+                        println!("Delete range is outside the document");
+                        continue;
+                    };
+                    let site = state.get_current_doc_site();
+                    for pid in deleted {
+                        let msg = SessionMessage::Delete { site, seq: 0, pid };
+                        let _ = oplog_tx.send(OplogMsg::SessionMessage(msg));
+                    }
                 }
             },
+            AppEvent::ClientConnected(stream) => {
+                editor = Editor {
+                    stream: Some(stream),
+                    rev: 0,
+                };
+            }
             AppEvent::ClientDisconnected => {
                 println!("Client disconnected");
+                editor = Editor::default();
             }
             AppEvent::SyncConnected => {
                 println!("Connected to sync server");
+                let _ = oplog_tx.send(OplogMsg::SyncAvailable);
+                // Pull whatever changed on the server while we were away
+                let msg = SyncRequests::SyncList {
+                    last_sync_time: state.sync_times.list(),
+                };
+                let _ = sync_tx.send(msg);
             }
             AppEvent::SyncDisconnected => {
                 println!("Disconnected from sync server");
+                let _ = oplog_tx.send(OplogMsg::SyncDown);
+            }
+            AppEvent::SyncMsg(reply) => match reply {
+                SyncReply::SyncList(docs) => {
+                    for info in &docs {
+                        let document_id = info.document_id;
+                        if info.deleted {
+                            if retire_deleted_doc(state, &mut editor, &oplog_tx, document_id) {
+                                remote_changes = false;
+                            }
+                            continue;
+                        }
+                        if !state.by_id.contains_key(&document_id) {
+                            println!("Not pulling document {} that isn't here", document_id);
+                            continue;
+                        }
+                        let msg = SyncRequests::SyncDoc {
+                            document_id,
+                            last_sync_time: state.sync_times.doc(document_id),
+                        };
+                        let _ = sync_tx.send(msg);
+                    }
+                    if let Some(newest) = docs.iter().map(|info| info.last_mod_time).max() {
+                        state.sync_times.set_list(newest);
+                    }
+                }
+                SyncReply::SyncDoc {
+                    document_id,
+                    full,
+                    inserts,
+                    deletes,
+                    last_modified,
+                    ..
+                } => {
+                    let Some(&idx) = state.by_id.get(&document_id) else {
+                        continue;
+                    };
+                    let changes = apply_sync_doc(&mut state.docs[idx], full, inserts, deletes);
+                    if changes.is_empty() {
+                        state.sync_times.set_doc(document_id, last_modified);
+                        continue;
+                    }
+                    println!(
+                        "Pulled {} changes into {:?}",
+                        changes.len(),
+                        state.docs[idx].name
+                    );
+                    if idx == state.current_doc {
+                        for msg in changes {
+                            editor.push(msg);
+                        }
+                    }
+                    // Only once they're on disk they don't have to be pulled again
+                    match state.docs[idx].flush() {
+                        Ok(()) => state.sync_times.set_doc(document_id, last_modified),
+                        Err(e) => eprintln!("Failed to flush pulled changes: {}", e),
+                    }
+                }
+                SyncReply::UpsertAcked { document_id } => {
+                    let _ = oplog_tx.send(OplogMsg::UpsertAcked { document_id });
+                }
+                SyncReply::Tombstoned { document_id, .. } => {
+                    if retire_deleted_doc(state, &mut editor, &oplog_tx, document_id) {
+                        remote_changes = false;
+                    }
+                }
+                SyncReply::NoSuchDoc { document_id } => {
+                    // Its creation never made it, the queued ops follow once it's there
+                    let Some(&idx) = state.by_id.get(&document_id) else {
+                        continue;
+                    };
+                    println!("Creating {:?} on the server again", state.docs[idx].name);
+                    upsert_whole_doc(&state.docs[idx], &state.sync_times, &sync_tx);
+                }
+            },
+            AppEvent::DocDeleted(document_id) => {
+                if retire_deleted_doc(state, &mut editor, &oplog_tx, document_id) {
+                    remote_changes = false;
+                }
             }
             AppEvent::SessionConnected => {
                 oplog_tx.send(OplogMsg::SessionAvailable);
@@ -106,6 +465,37 @@ pub fn run_app(
             AppEvent::SessionDisconnected => {
                 oplog_tx.send(OplogMsg::SessionDown);
             },
+            AppEvent::SessionMsg(msg) => match msg {
+                SessionMessage::Started { document_id, site } => {
+                    println!("Session started with site {}", site);
+                    let renamed = state.lease_doc_site(document_id, site);
+                    if !renamed.is_empty() {
+                        println!("Re-stamped {} atoms made before the lease", renamed.len());
+                    }
+                    let _ = oplog_tx.send(OplogMsg::SiteLeased {
+                        document_id,
+                        renamed,
+                    });
+                }
+                SessionMessage::Ack { seq } => {
+                    let _ = oplog_tx.send(OplogMsg::Ack { seq });
+                }
+                SessionMessage::Insert { pid, c, .. } => {
+                    if let Some(offset) = state.apply_remote_insert(pid, c) {
+                        remote_changes = true;
+                        let msg = DaemonMessage::RemoteInsert(offset as u32, c.to_string());
+                        editor.push(msg);
+                    }
+                }
+                SessionMessage::Delete { pid, .. } => {
+                    if let Some((offset, len)) = state.apply_remote_delete(&pid) {
+                        remote_changes = true;
+                        let msg = DaemonMessage::RemoteDelete(offset as u32, len as u32);
+                        editor.push(msg);
+                    }
+                }
+                _ => {}
+            },
         }
     }
 }
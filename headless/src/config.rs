@@ -0,0 +1,171 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use algos::pid::Strategy;
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde::Deserialize;
+use tungstenite::protocol::WebSocketConfig;
+
+/// Settings are looked up in order of precedence: command line flags,
+/// `NOTEK_*` environment variables, the TOML config file, built-in defaults.
+#[derive(Parser, Debug)]
+#[command(name = "headless", about = "Headless notek client the editor plugins talk to")]
+struct Cli {
+    /// Print the document stored in a .md.structure file and exit
+    #[arg(short, long, value_name = "STRUCTURE")]
+    read: Option<PathBuf>,
+    /// TOML file with any of the settings below, keys spelled with underscores
+    #[arg(short, long, env = "NOTEK_CONFIG")]
+    config: Option<PathBuf>,
+    /// Directory with the notes to keep in sync [default: ./]
+    #[arg(long, env = "NOTEK_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Server to sync with [default: ws://127.0.0.1:9001]
+    #[arg(long, env = "NOTEK_SERVER_URL")]
+    server_url: Option<String>,
+    /// Unix socket the editor connects to [default: /tmp/editor_socket.sock]
+    #[arg(long, env = "NOTEK_SOCKET_PATH")]
+    socket_path: Option<PathBuf>,
+    /// Time between attempts to reach the server, in milliseconds [default: 5000]
+    #[arg(long, env = "NOTEK_RETRY_INTERVAL")]
+    retry_interval: Option<u64>,
+    /// Largest WebSocket message accepted from the server, in bytes [default: 64 MiB]
+    #[arg(long, env = "NOTEK_MAX_MESSAGE_SIZE")]
+    max_message_size: Option<usize>,
+    /// Largest WebSocket frame accepted from the server, in bytes [default: 16 MiB]
+    #[arg(long, env = "NOTEK_MAX_FRAME_SIZE")]
+    max_frame_size: Option<usize>,
+    /// How new documents place the PIDs of inserts, logoot or lseq [default: logoot]
+    #[arg(long, env = "NOTEK_STRATEGY")]
+    strategy: Option<Strategy>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    data_dir: Option<PathBuf>,
+    server_url: Option<String>,
+    socket_path: Option<PathBuf>,
+    retry_interval: Option<u64>,
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    strategy: Option<Strategy>,
+}
+
+impl FileConfig {
+    fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {:?}", path))
+    }
+}
+
+/// How the sync and session threads reach the server.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub url: String,
+    pub retry_interval: Duration,
+    pub websocket: WebSocketConfig,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub read: Option<PathBuf>,
+    pub data_dir: PathBuf,
+    pub socket_path: PathBuf,
+    pub server: ServerConfig,
+    /// Allocation strategy of documents created by this replica, existing
+    /// ones keep the one in their structure file
+    pub strategy: Strategy,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        Self::from_cli(Cli::parse())
+    }
+
+    fn from_cli(cli: Cli) -> Result<Self> {
+        let file = match &cli.config {
+            Some(path) => FileConfig::read(path)?,
+            None => FileConfig::default(),
+        };
+
+        let max_message_size = cli
+            .max_message_size
+            .or(file.max_message_size)
+            .unwrap_or(64 << 20);
+        let max_frame_size = cli
+            .max_frame_size
+            .or(file.max_frame_size)
+            .unwrap_or(16 << 20);
+
+        Ok(Config {
+            read: cli.read,
+            data_dir: cli
+                .data_dir
+                .or(file.data_dir)
+                .unwrap_or_else(|| PathBuf::from("./")),
+            socket_path: cli
+                .socket_path
+                .or(file.socket_path)
+                .unwrap_or_else(|| PathBuf::from("/tmp/editor_socket.sock")),
+            server: ServerConfig {
+                url: cli
+                    .server_url
+                    .or(file.server_url)
+                    .unwrap_or_else(|| "ws://127.0.0.1:9001".to_string()),
+                retry_interval: Duration::from_millis(
+                    cli.retry_interval.or(file.retry_interval).unwrap_or(5000),
+                ),
+                websocket: WebSocketConfig::default()
+                    .max_message_size(Some(max_message_size))
+                    .max_frame_size(Some(max_frame_size)),
+            },
+            strategy: cli.strategy.or(file.strategy).unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn flags_beat_env_beats_file_beats_defaults() {
+        let path = env::temp_dir().join(format!("notek-headless-{}.toml", Uuid::new_v4()));
+        let toml = r#"
+data_dir = "/file"
+server_url = "ws://file"
+socket_path = "/file.sock"
+"#;
+        fs::write(&path, toml).unwrap();
+        // SAFETY: no other test in this crate reads NOTEK_* variables
+        unsafe {
+            env::set_var("NOTEK_SERVER_URL", "ws://env");
+            env::set_var("NOTEK_SOCKET_PATH", "/env.sock");
+        }
+        let file = path.to_str().unwrap();
+        let cli = Cli::try_parse_from(["headless", "-c", file, "--socket-path", "/cli.sock"]);
+        let cli = cli.unwrap();
+        unsafe {
+            env::remove_var("NOTEK_SERVER_URL");
+            env::remove_var("NOTEK_SOCKET_PATH");
+        }
+
+        let config = Config::from_cli(cli).unwrap();
+        assert_eq!(config.socket_path, PathBuf::from("/cli.sock"));
+        assert_eq!(config.server.url, "ws://env");
+        assert_eq!(config.data_dir, PathBuf::from("/file"));
+        assert_eq!(config.server.retry_interval, Duration::from_millis(5000));
+        assert_eq!(config.strategy, Strategy::default());
+        fs::remove_file(path).unwrap();
+    }
+}
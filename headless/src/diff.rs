@@ -0,0 +1,230 @@
+//! Character level Myers diff, a port of `myers_diff.lua` that runs in linear space.
+
+/// One change turning the old text into the new one: remove `delete_len` bytes at
+/// `start_byte`, then insert `insert` at the same place.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Hunk {
+    pub start_byte: usize,
+    pub delete_len: usize,
+    pub insert: String,
+}
+
+enum Edit {
+    /// Remove the character at `old` in the old text
+    Delete { old: usize },
+    /// Insert the character at `new` of the new text before `old` in the old text
+    Insert { old: usize, new: usize },
+}
+
+/// Minimal set of hunks turning `old` into `new`, ordered by position. Byte offsets
+/// are into `old`, so apply them back to front to keep them valid.
+pub fn hunks(old: &str, new: &str) -> Vec<Hunk> {
+    let e: Vec<char> = old.chars().collect();
+    let f: Vec<char> = new.chars().collect();
+
+    let mut edits = Vec::new();
+    diff(&e, &f, 0, 0, &mut edits);
+
+    // Byte offset of every character of the old text, plus the end
+    let mut offsets = Vec::with_capacity(e.len() + 1);
+    let mut byte = 0;
+    for c in &e {
+        offsets.push(byte);
+        byte += c.len_utf8();
+    }
+    offsets.push(byte);
+
+    // (first deleted char, one past the last deleted char, inserted text)
+    let mut ranges: Vec<(usize, usize, String)> = Vec::new();
+    for edit in edits {
+        match edit {
+            Edit::Delete { old } => match ranges.last_mut() {
+                Some((_, end, _)) if *end == old => *end += 1,
+                _ => ranges.push((old, old + 1, String::new())),
+            },
+            Edit::Insert { old, new } => match ranges.last_mut() {
+                Some((_, end, text)) if *end == old => text.push(f[new]),
+                _ => ranges.push((old, old, f[new].to_string())),
+            },
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(start, end, insert)| Hunk {
+            start_byte: offsets[start],
+            delete_len: offsets[end] - offsets[start],
+            insert,
+        })
+        .collect()
+}
+
+/// Finds the middle snake of `e` and `f` searching from both ends, then recurses on
+/// the halves around it. `i` and `j` are where `e` and `f` start in the full texts.
+fn diff(e: &[char], f: &[char], i: usize, j: usize, edits: &mut Vec<Edit>) {
+    let (n, m) = (e.len() as isize, f.len() as isize);
+    let l = n + m;
+    let z = 2 * n.min(m) + 2;
+
+    if n > 0 && m > 0 {
+        let w = n - m;
+        let mut g = vec![0isize; z as usize];
+        let mut p = vec![0isize; z as usize];
+        let at = |k: isize| k.rem_euclid(z) as usize;
+
+        for h in 0..=(l / 2 + l % 2) {
+            for r in 0..2 {
+                // Forward search on the first pass, backward on the second
+                let (c, d, o, sign) = if r == 0 {
+                    (&mut g, &p, 1, 1)
+                } else {
+                    (&mut p, &g, 0, -1)
+                };
+
+                let mut k = -(h - 2 * 0.max(h - m));
+                let end_k = h - 2 * 0.max(h - n);
+                while k <= end_k {
+                    let mut a = if k == -h || (k != h && c[at(k - 1)] < c[at(k + 1)]) {
+                        c[at(k + 1)]
+                    } else {
+                        c[at(k - 1)] + 1
+                    };
+                    let mut b = a - k;
+                    let (s, t) = (a, b);
+
+                    while a < n
+                        && b < m
+                        && e[((1 - o) * n + sign * a + o - 1) as usize]
+                            == f[((1 - o) * m + sign * b + o - 1) as usize]
+                    {
+                        a += 1;
+                        b += 1;
+                    }
+
+                    c[at(k)] = a;
+                    let zk = -(k - w);
+
+                    if l % 2 == o && zk >= -(h - o) && zk <= h - o && c[at(k)] + d[at(zk)] >= n {
+                        let (dist, x, y, u, v) = if o == 1 {
+                            (2 * h - 1, s, t, a, b)
+                        } else {
+                            (2 * h, n - a, m - b, n - s, m - t)
+                        };
+                        let (x, y, u, v) = (x as usize, y as usize, u as usize, v as usize);
+
+                        if dist > 1 || (x != u && y != v) {
+                            diff(&e[..x], &f[..y], i, j, edits);
+                            diff(&e[u..], &f[v..], i + u, j + v, edits);
+                        } else if m > n {
+                            let n = n as usize;
+                            diff(&[], &f[n..], i + n, j + n, edits);
+                        } else if m < n {
+                            let m = m as usize;
+                            diff(&e[m..], &[], i + m, j + m, edits);
+                        }
+                        return;
+                    }
+                    k += 2;
+                }
+            }
+        }
+    } else if n > 0 {
+        edits.extend((0..e.len()).map(|x| Edit::Delete { old: i + x }));
+    } else {
+        edits.extend((0..f.len()).map(|y| Edit::Insert { old: i, new: j + y }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Applies the hunks back to front, like the app does to the editor's buffer.
+    fn apply(old: &str, hunks: &[Hunk]) -> String {
+        let mut text = old.to_string();
+        for hunk in hunks.iter().rev() {
+            let end = hunk.start_byte + hunk.delete_len;
+            text.replace_range(hunk.start_byte..end, &hunk.insert);
+        }
+        text
+    }
+
+    fn assert_turns_into(old: &str, new: &str) -> Vec<Hunk> {
+        let hunks = hunks(old, new);
+        assert_eq!(apply(old, &hunks), new, "{:?} -> {:?}", old, new);
+        hunks
+    }
+
+    #[test]
+    fn empty_texts() {
+        assert!(assert_turns_into("", "").is_empty());
+        assert!(assert_turns_into("same\n", "same\n").is_empty());
+        assert_turns_into("", "all new\n");
+        assert_turns_into("all gone\n", "");
+    }
+
+    #[test]
+    fn pure_insert_is_one_hunk() {
+        let hunks = assert_turns_into("abcd", "abXYZcd");
+        let insert = Hunk {
+            start_byte: 2,
+            delete_len: 0,
+            insert: "XYZ".to_string(),
+        };
+        assert_eq!(hunks, vec![insert]);
+    }
+
+    #[test]
+    fn pure_delete_is_one_hunk() {
+        let hunks = assert_turns_into("abXYZcd", "abcd");
+        let delete = Hunk {
+            start_byte: 2,
+            delete_len: 3,
+            insert: String::new(),
+        };
+        assert_eq!(hunks, vec![delete]);
+    }
+
+    #[test]
+    fn multibyte_characters() {
+        let hunks = assert_turns_into("zażółć gęślą", "zażółć jaźń gęślą");
+        assert_eq!(hunks.len(), 1);
+        // Offsets count bytes, not characters
+        assert_eq!(hunks[0].start_byte, "zażółć ".len());
+        assert_turns_into("😀 ok 😀", "ok 🌍");
+        assert_turns_into("ąę", "ęą");
+    }
+
+    #[test]
+    fn repeated_lines() {
+        assert_turns_into("a\na\na\n", "a\na\n");
+        assert_turns_into("a\na\n", "a\nb\na\nb\na\n");
+        assert_turns_into("x\ny\nx\ny\n", "y\nx\ny\nx\n");
+        assert_turns_into("\n\n\n", "\n\n\n\n\n");
+    }
+
+    #[test]
+    fn every_pair_of_short_texts() {
+        // Small enough to cover every pair, odd and even length differences included
+        let alphabet = ['a', 'b', 'ł'];
+        let mut texts = vec![String::new()];
+        for len in 1..=4 {
+            let mut n: usize = 0;
+            while n < alphabet.len().pow(len) {
+                let mut text = String::new();
+                let mut rest = n;
+                for _ in 0..len {
+                    text.push(alphabet[rest % alphabet.len()]);
+                    rest /= alphabet.len();
+                }
+                texts.push(text);
+                n += 1;
+            }
+        }
+        for old in &texts {
+            for new in &texts {
+                assert_turns_into(old, new);
+            }
+        }
+    }
+}
@@ -9,6 +9,12 @@ pub enum EditorMessage {
     Delete(u32, u32),
     ChooseDocument(PathBuf),
     Flush,
+    /// Text inserted at a line and byte column
+    InsertAt(u32, u32, String),
+    /// Text deleted from (start line, start column) up to (end line, end column)
+    DeleteRange(u32, u32, u32, u32),
+    /// The editor couldn't apply a push, it wants the whole text again
+    Resync,
 }
 
 impl EditorMessage {
@@ -59,7 +65,89 @@ impl EditorMessage {
 
             3 => Ok(EditorMessage::Flush),
 
+            // Insert at line and column
+            4 => {
+                let line = reader.read_u32::<LittleEndian>()?;
+                let col = reader.read_u32::<LittleEndian>()?;
+                let len = reader.read_u32::<LittleEndian>()?;
+
+                let mut buf = vec![0u8; len as usize];
+                reader.read_exact(&mut buf)?;
+
+                let text = String::from_utf8(buf)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8"))?;
+
+                Ok(EditorMessage::InsertAt(line, col, text))
+            }
+
+            // Delete a line and column range
+            5 => {
+                let start_line = reader.read_u32::<LittleEndian>()?;
+                let start_col = reader.read_u32::<LittleEndian>()?;
+                let end_line = reader.read_u32::<LittleEndian>()?;
+                let end_col = reader.read_u32::<LittleEndian>()?;
+
+                Ok(EditorMessage::DeleteRange(
+                    start_line, start_col, end_line, end_col,
+                ))
+            }
+
+            6 => Ok(EditorMessage::Resync),
+
             _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown opcode")),
         }
     }
 }
+
+/// Messages the daemon pushes to the connected editor. Offsets are in bytes
+/// into the editor's buffer, numbers are u32 little endian like EditorMessage.
+/// Everything but Renamed carries the revision it was made at: how many
+/// edits of the editor the daemon had applied since the document was chosen.
+/// Edits the editor sent after that aren't accounted for in the offsets.
+#[derive(Debug)]
+pub enum DaemonMessage {
+    /// Text another participant inserted at a byte offset
+    RemoteInsert(u32, String),
+    /// Byte range another participant deleted
+    RemoteDelete(u32, u32),
+    /// Whole contents of the current document, when it can't be patched
+    Replace(String),
+    /// The current document was moved to a new name
+    Renamed(PathBuf),
+}
+
+impl DaemonMessage {
+    pub fn serialize(&self, rev: u32) -> Vec<u8> {
+        use byteorder::{LittleEndian, WriteBytesExt};
+
+        let mut buf = Vec::new();
+        match self {
+            DaemonMessage::RemoteInsert(index, text) => {
+                buf.push(0);
+                buf.write_u32::<LittleEndian>(rev).unwrap();
+                buf.write_u32::<LittleEndian>(*index).unwrap();
+                buf.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+                buf.extend_from_slice(text.as_bytes());
+            }
+            DaemonMessage::RemoteDelete(index, len) => {
+                buf.push(1);
+                buf.write_u32::<LittleEndian>(rev).unwrap();
+                buf.write_u32::<LittleEndian>(*index).unwrap();
+                buf.write_u32::<LittleEndian>(*len).unwrap();
+            }
+            DaemonMessage::Replace(text) => {
+                buf.push(2);
+                buf.write_u32::<LittleEndian>(rev).unwrap();
+                buf.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+                buf.extend_from_slice(text.as_bytes());
+            }
+            DaemonMessage::Renamed(name) => {
+                let name = name.to_string_lossy();
+                buf.push(3);
+                buf.write_u32::<LittleEndian>(name.len() as u32).unwrap();
+                buf.extend_from_slice(name.as_bytes());
+            }
+        }
+        buf
+    }
+}
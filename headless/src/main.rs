@@ -1,6 +1,5 @@
-use std::env;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::path::Path;
 use std::process;
 use std::sync::mpsc::{self, Sender};
 use std::{fs, thread};
@@ -11,25 +10,37 @@ use algos::sync::SyncRequests;
 use tungstenite::{connect, Message};
 
 use crate::app::{run_app, AppEvent};
+use crate::config::Config;
 use crate::editor_message::EditorMessage;
 use crate::monitor::monitor_updates;
 use crate::oplog::{Oplog, OplogMsg};
-use crate::session::handle_session_communication;
+use crate::session::{handle_session_communication, load_replica_id};
 use crate::state::State;
 use crate::sync::handle_sync_communication;
 
 mod app;
+mod config;
+mod diff;
 mod editor_message;
 mod monitor;
 mod state;
 mod sync;
+mod sync_times;
 mod oplog;
+mod oplog_file;
 mod session;
 
 fn accept_connections(listener: UnixListener, tx: Sender<AppEvent>) {
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
+                // The app writes remote changes back through its own handle
+                match stream.try_clone() {
+                    Ok(writer) => {
+                        let _ = tx.send(AppEvent::ClientConnected(writer));
+                    }
+                    Err(err) => eprintln!("Failed to clone editor stream: {}", err),
+                }
                 let tx = tx.clone();
                 thread::spawn(move || {
                     handle_client(stream, tx);
@@ -58,15 +69,13 @@ fn handle_client(mut stream: UnixStream, tx: Sender<AppEvent>) {
     }
 }
 
-fn read_doc(path: &str) {
-    let path = PathBuf::from(path);
-
+fn read_doc(path: &Path) {
     if !path.exists() {
         eprintln!("File not found: {:?}", path);
         process::exit(1);
     }
 
-    match DocStructure::read_existing(&path, &path) {
+    match DocStructure::read_existing(path, Path::new(""), path) {
         Ok(doc) => {
             print!("{}", doc.get_doc().to_string());
         }
@@ -77,42 +86,48 @@ fn read_doc(path: &str) {
     }
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+fn main() -> anyhow::Result<()> {
+    let config = Config::load()?;
 
     // Handle -r flag: read and print document contents, then exit
-    if args.len() >= 3 && args[1] == "-r" {
-        read_doc(&args[2]);
+    if let Some(path) = &config.read {
+        read_doc(path);
         return Ok(());
     }
 
-    let socket_path = "/tmp/editor_socket.sock";
+    let socket_path = &config.socket_path;
 
     if fs::metadata(socket_path).is_ok() {
         fs::remove_file(socket_path)?;
     }
 
     let listener = UnixListener::bind(socket_path)?;
-    println!("Server listening on {}", socket_path);
+    println!("Server listening on {:?}", socket_path);
 
     let (tx, rx) = mpsc::channel::<AppEvent>();
     let (oplog_tx, oplog_rx) = mpsc::channel::<OplogMsg>();
-    let mut oplog = Oplog::init().unwrap();
 
     let (sync_tx, sync_rx) = mpsc::channel::<SyncRequests>();
     let sync_app_tx = tx.clone();
+    let sync_server = config.server.clone();
     thread::spawn(move || {
-        handle_sync_communication(sync_rx, sync_app_tx);
+        handle_sync_communication(sync_rx, sync_app_tx, sync_server);
     });
 
+    let replica = load_replica_id(&config.data_dir)?;
     let (session_tx, session_rx) = mpsc::channel::<SessionMessage>();
     let session_app_tx = tx.clone();
+    let session_server = config.server.clone();
     thread::spawn(move || {
-        handle_session_communication(session_rx, session_app_tx);
+        handle_session_communication(session_rx, session_app_tx, session_server, replica);
     });
 
 
-    let mut state = State::init(PathBuf::from("./").as_path()).unwrap();
+    // Documents are named by their path relative to the data directory
+    let mut state = State::init(&config.data_dir, config.strategy).unwrap();
+    // The oplog needs to know where the documents are to find their oplog files
+    let names = state.docs.iter().map(|d| (d.id, d.name.clone())).collect();
+    let mut oplog = Oplog::init(&state.base_dir, names).unwrap();
 
     let oplog_sync_tx = sync_tx.clone();
     thread::spawn(move || {
@@ -1,14 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
 
+use algos::structure::is_plaintext_tmp;
 use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
 
 use crate::app::AppEvent;
 
-const WATCH_MASK: WatchMask =
-    WatchMask::from_bits_truncate(WatchMask::MOVE.bits() | WatchMask::CREATE.bits());
+const WATCH_MASK: WatchMask = WatchMask::from_bits_truncate(
+    WatchMask::MOVE.bits()
+        | WatchMask::CREATE.bits()
+        | WatchMask::MODIFY.bits()
+        | WatchMask::CLOSE_WRITE.bits(),
+);
 
 /// Recursively add inotify watches for `dir` and all its subdirectories.
 /// Populates wd_to_dir: WatchDescriptor -> directory path relative to base_dir.
@@ -50,6 +55,11 @@ pub fn monitor_updates(tx: Sender<AppEvent>, base_dir: &Path) {
     println!("Watching {:?} (and subdirs) for activity...", base_dir);
 
     let mut pending_moves: HashMap<u32, PathBuf> = HashMap::new();
+    // Files written to since they were opened. Their contents only get read once the
+    // writer closes them, a truncate followed by a write would look like a wipe otherwise
+    let mut modified: HashSet<PathBuf> = HashSet::new();
+    // Cookies of renames that put a flushed .md in place, the daemon wrote those itself
+    let mut own_writes: HashSet<u32> = HashSet::new();
     let mut buffer = [0u8; 4096];
 
     loop {
@@ -87,13 +97,28 @@ pub fn monitor_updates(tx: Sender<AppEvent>, base_dir: &Path) {
                 continue;
             }
 
+            if event.mask.contains(EventMask::MOVED_FROM) && is_plaintext_tmp(&rel_path) {
+                own_writes.insert(event.cookie);
+                continue;
+            }
+            if event.mask.contains(EventMask::MOVED_TO) && own_writes.remove(&event.cookie) {
+                continue;
+            }
+
             // Only care about .md files (skip hidden .md.structure files and others)
             let is_md = rel_path.extension().and_then(|s| s.to_str()) == Some("md");
             if !is_md {
                 continue;
             }
 
-            if event.mask.contains(EventMask::CREATE) {
+            if event.mask.contains(EventMask::MODIFY) {
+                modified.insert(rel_path);
+            } else if event.mask.contains(EventMask::CLOSE_WRITE) {
+                if modified.remove(&rel_path) {
+                    println!("File modified: {:?}", rel_path);
+                    let _ = tx.send(AppEvent::FileModified(rel_path));
+                }
+            } else if event.mask.contains(EventMask::CREATE) {
                 println!("New file detected: {:?}", rel_path);
                 let _ = tx.send(AppEvent::FileCreated(rel_path));
             } else if event.mask.contains(EventMask::MOVED_FROM) {
@@ -1,7 +1,6 @@
 use std::{
-    collections::{BTreeMap, VecDeque},
-    fs::{self, File},
-    io::{BufReader, BufWriter, Write},
+    collections::{BTreeMap, HashMap, VecDeque, hash_map::Entry},
+    fs,
     net::TcpStream,
     path::{Path, PathBuf},
     sync::mpsc::{Receiver, Sender},
@@ -17,41 +16,264 @@ use algos::{
 use anyhow::Result;
 use tungstenite::{Message, WebSocket, connect, stream::MaybeTlsStream};
 
+use crate::oplog_file::{self, OplogFile};
+
+/// Done records an oplog file may pile up before it gets compacted
+const COMPACT_AFTER: u64 = 1024;
+
+/// Ops of one document the server hasn't seen yet, in the order they were made.
+///
+/// Deleting a character whose insert is still queued drops both, the server
+/// never needs to hear about text that got typed and erased while offline.
+#[derive(Debug, Default)]
+pub struct OpQueue {
+    ops: BTreeMap<i64, DocOp>,
+    next: i64,
+    /// Queued inserts that were never sent anywhere, by PID
+    unsent_inserts: HashMap<Pid, i64>,
+}
+
+impl OpQueue {
+    /// Returns the insert the op cancelled, if it's a delete of an unsent one.
+    pub fn push(&mut self, op: DocOp) -> Option<DocOp> {
+        match &op {
+            DocOp::Delete(pid) => {
+                if let Some(key) = self.unsent_inserts.remove(pid) {
+                    return self.ops.remove(&key);
+                }
+            }
+            DocOp::Insert(pid, _) => {
+                self.unsent_inserts.insert(pid.clone(), self.next);
+            }
+        }
+        self.ops.insert(self.next, op);
+        self.next += 1;
+        None
+    }
+
+    /// Queue an op that may have reached the server already, a later delete
+    /// doesn't cancel it.
+    pub fn push_sent(&mut self, op: DocOp) {
+        self.ops.insert(self.next, op);
+        self.next += 1;
+    }
+
+    /// Queue an op ahead of everything else. It may have reached the server
+    /// already, so a later delete doesn't cancel it.
+    pub fn push_front(&mut self, op: DocOp) {
+        let key = self.ops.keys().next().map_or(0, |k| k - 1);
+        self.ops.insert(key, op);
+    }
+
+    pub fn pop_front(&mut self) -> Option<DocOp> {
+        let (_, op) = self.ops.pop_first()?;
+        if let DocOp::Insert(pid, _) = &op {
+            self.unsent_inserts.remove(pid);
+        }
+        Some(op)
+    }
+
+    pub fn front(&self) -> Option<&DocOp> {
+        self.ops.values().next()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether some op is on a PID made before the document had a site leased.
+    pub fn has_provisional(&self) -> bool {
+        self.ops.values().any(|op| op.pid().is_provisional())
+    }
+
+    /// Moves the ops on provisional PIDs to the PIDs they got re-stamped to.
+    /// Ones whose atom is gone by now are dropped, nobody else ever saw them.
+    pub fn restamp(&mut self, renamed: &HashMap<Pid, Pid>) {
+        let unsent = std::mem::take(&mut self.unsent_inserts);
+        for (key, op) in std::mem::take(&mut self.ops) {
+            let old = op.pid();
+            let pid = if old.is_provisional() {
+                match renamed.get(old) {
+                    Some(new) => new.clone(),
+                    None => continue,
+                }
+            } else {
+                old.clone()
+            };
+            if unsent.get(old) == Some(&key) {
+                self.unsent_inserts.insert(pid.clone(), key);
+            }
+            let op = match op {
+                DocOp::Insert(_, c) => DocOp::Insert(pid, c),
+                DocOp::Delete(_) => DocOp::Delete(pid),
+            };
+            self.ops.insert(key, op);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DocOp> {
+        self.ops.values()
+    }
+}
+
+impl IntoIterator for OpQueue {
+    type Item = DocOp;
+    type IntoIter = std::collections::btree_map::IntoValues<i64, DocOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_values()
+    }
+}
+
 pub struct Oplog {
     pub current_document: u128,
-    pub current_log: VecDeque<DocOp>,
-    pub log: BTreeMap<u128, VecDeque<DocOp>>,
+    pub current_log: OpQueue,
+    pub log: BTreeMap<u128, OpQueue>,
+    /// Ops sent in a `SyncDocUpsert` the server hasn't confirmed yet
+    pub upserted: BTreeMap<u128, VecDeque<DocOp>>,
     pub session_available: bool,
     pub sync_available: bool,
+    /// Ops of the current document sent in the session that the server hasn't acked yet, oldest first
+    pub unacked: VecDeque<(u64, DocOp)>,
+    pub next_seq: u64,
+    /// Data directory the documents are in
+    pub dir: PathBuf,
+    /// Where the documents live relative to `dir`, their oplog files sit next to them
+    pub names: HashMap<u128, PathBuf>,
+    /// Open oplog files of the documents with ops in them
+    files: HashMap<u128, OplogFile>,
 }
 
 pub enum OplogMsg {
     SessionMessage(SessionMessage),
+    Ack { seq: u64 },
+    UpsertAcked { document_id: u128 },
+    /// Ops made to a document other than the one being edited, they go out in an upsert
+    DocOps { document_id: u128, ops: Vec<DocOp> },
+    DocAdded { document_id: u128, name: PathBuf },
+    DocRenamed { document_id: u128, name: PathBuf },
+    /// The document got deleted on the server, which refuses any op on it
+    DocDeleted { document_id: u128 },
+    /// Drop what the server confirmed from the oplog files
+    Flush,
+    /// The document got a site, its provisional PIDs were re-stamped (old, new)
+    SiteLeased { document_id: u128, renamed: Vec<(Pid, Pid)> },
     SyncAvailable,
     SyncDown,
     SessionAvailable,
     SessionDown,
 }
 
-/// Given a base name like `school/math/note.md`, returns `school/math/.note.md.oplog`.
-fn hidden_oplog_path(name: &Path) -> PathBuf {
+/// Given a base name like `school/math/note.md`, returns `school/math/.note.md.oplog` in `dir`.
+fn hidden_oplog_path(dir: &Path, name: &Path) -> PathBuf {
     let parent = name.parent().unwrap_or(Path::new(""));
     let stem = name.file_stem().unwrap_or_default();
     let hidden_name = format!(".{}.md.oplog", stem.to_string_lossy());
-    parent.join(hidden_name)
+    dir.join(parent).join(hidden_name)
+}
+
+fn to_session_message(seq: u64, op: &DocOp) -> SessionMessage {
+    match op {
+        DocOp::Insert(pid, c) => SessionMessage::Insert {
+            site: 0,
+            seq,
+            pid: pid.clone(),
+            c: *c,
+        },
+        DocOp::Delete(pid) => SessionMessage::Delete {
+            site: 0,
+            seq,
+            pid: pid.clone(),
+        },
+    }
+}
+
+fn send_upsert(document_id: u128, ops: &VecDeque<DocOp>, sync_tx: &Sender<SyncRequests>) {
+    let mut inserts = Vec::new();
+    let mut deletes = Vec::new();
+
+    for item in ops {
+        match item {
+            DocOp::Insert(pid, c) => inserts.push((pid.clone(), *c)),
+            DocOp::Delete(pid) => deletes.push(pid.clone()),
+        }
+    }
+
+    let req = SyncRequests::SyncDocUpsert {
+        document_id,
+        name: None,
+        last_sync_time: 0,
+        inserts,
+        deletes,
+    };
+    let _ = sync_tx.send(req);
 }
 
 impl Oplog {
-    pub fn init() -> Result<Self> {
+    /// Picks up the ops a previous run didn't get to send from the oplog files of `names`.
+    pub fn init(dir: &Path, names: HashMap<u128, PathBuf>) -> Result<Self> {
+        let mut log = BTreeMap::new();
+        let mut files = HashMap::new();
+        for (&document_id, name) in &names {
+            let path = hidden_oplog_path(dir, name);
+            let ops = match OplogFile::read_pending(&path) {
+                Ok(ops) => ops,
+                Err(e) => {
+                    eprintln!("Oplog: skipping unreadable {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            // Pairs that cancelled out are done in the file already, and there's no
+            // telling which of the rest went out before we stopped
+            let mut queue = OpQueue::default();
+            for op in ops {
+                queue.push_sent(op);
+            }
+            if queue.is_empty() {
+                oplog_file::remove(&path)?;
+                continue;
+            }
+            println!("Oplog: {} pending ops for {:?}", queue.len(), name);
+            // Starts out compacted, without the done and torn records
+            files.insert(document_id, OplogFile::create(&path, queue.iter())?);
+            log.insert(document_id, queue);
+        }
+
         Ok(Oplog {
             current_document: u128::MAX,
-            current_log: VecDeque::new(),
-            log: BTreeMap::new(),
+            current_log: OpQueue::default(),
+            log,
+            upserted: BTreeMap::new(),
             session_available: false,
             sync_available: false,
+            unacked: VecDeque::new(),
+            next_seq: 1,
+            dir: dir.to_path_buf(),
+            names,
+            files,
         })
     }
 
+    /// Number the op, remember it till the server acks it and send it.
+    fn send_op(&mut self, op: DocOp, session_tx: &Sender<SessionMessage>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let _ = session_tx.send(to_session_message(seq, &op));
+        self.unacked.push_back((seq, op));
+    }
+
+    /// Put the unacked ops back in front of the offline log of the current document,
+    /// they go out again with the next session or sync.
+    fn requeue_unacked(&mut self) {
+        while let Some((_, op)) = self.unacked.pop_back() {
+            self.current_log.push_front(op);
+        }
+    }
+
     pub fn run(
         &mut self,
         rx: Receiver<OplogMsg>,
@@ -64,14 +286,14 @@ impl Oplog {
                     OplogMsg::SessionMessage(msg) => {
                         match msg {
                             SessionMessage::Insert { pid, c, .. } => {
-                                if self.session_available {
-                                    session_tx.send(SessionMessage::Insert { site: 0, pid, c });
-                                } else {
-                                    self.current_log.push_back(DocOp::Insert(pid, c));
-                                }
+                                self.record(DocOp::Insert(pid, c), &session_tx);
+                            }
+                            SessionMessage::Delete { pid, .. } => {
+                                self.record(DocOp::Delete(pid), &session_tx);
                             }
                             SessionMessage::Start { document_id, .. } => {
                                 if document_id != self.current_document {
+                                    self.requeue_unacked();
                                     if self.current_document != u128::MAX
                                         && self.current_log.len() > 0
                                     {
@@ -91,21 +313,102 @@ impl Oplog {
                                     if self.session_available && self.current_document != u128::MAX
                                     {
                                         session_tx.send(msg);
+                                        self.send_current_log(&session_tx);
                                     }
                                 }
                                 // self.log.insert(document_id, VecDeque::new());
                             }
-                            SessionMessage::Delete { site, pid } => {
-                                if self.session_available {
-                                    session_tx.send(SessionMessage::Delete { site, pid });
-                                } else {
-                                    self.current_log.push_back(DocOp::Delete(pid));
+                            SessionMessage::ChangeName { .. } => {
+                                // Not queued, a rename that can't go out now reaches the
+                                // server through the sync connection's DocNameChange
+                                if self.session_available && self.current_document != u128::MAX {
+                                    let _ = session_tx.send(msg);
+                                }
+                            }
+                            SessionMessage::Started { .. } | SessionMessage::Ack { .. } => {}
+                        }
+                    }
+                    OplogMsg::Ack { seq } => {
+                        // Acks are cumulative
+                        let mut acked = Vec::new();
+                        while self.unacked.front().is_some_and(|(s, _)| *s <= seq) {
+                            acked.extend(self.unacked.pop_front().map(|(_, op)| op));
+                        }
+                        self.mark_done(self.current_document, &acked);
+                    }
+                    OplogMsg::UpsertAcked { document_id } => {
+                        if let Some(acked) = self.upserted.remove(&document_id) {
+                            self.mark_done(document_id, &Vec::from(acked));
+                        }
+                    }
+                    OplogMsg::DocOps { document_id, ops } => {
+                        for op in &ops {
+                            if let Err(e) = self.append_op(document_id, op) {
+                                eprintln!("Failed to write oplog: {}", e);
+                            }
+                        }
+                        let queue = self.log.entry(document_id).or_default();
+                        let cancelled: Vec<DocOp> =
+                            ops.into_iter().filter_map(|op| queue.push(op)).collect();
+                        self.mark_cancelled(document_id, &cancelled);
+                        if self.sync_available {
+                            self.upsert_pending(document_id, &sync_tx);
+                        }
+                    }
+                    OplogMsg::DocAdded { document_id, name } => {
+                        self.names.insert(document_id, name);
+                    }
+                    OplogMsg::DocRenamed { document_id, name } => {
+                        if let Some(old) = self.names.insert(document_id, name.clone()) {
+                            let old_path = hidden_oplog_path(&self.dir, &old);
+                            let new_path = hidden_oplog_path(&self.dir, &name);
+                            if old_path.exists() {
+                                if let Err(e) = fs::rename(&old_path, new_path) {
+                                    eprintln!("Failed to move oplog of {:?}: {}", old, e);
                                 }
                             }
-                            SessionMessage::ChangeName { name } => todo!(),
                         }
-                        // let log = self.log.get_mut(&self.current_document).unwrap();
-                        // log.push_back(DocOp::Insert(pid, c));
+                    }
+                    OplogMsg::DocDeleted { document_id } => {
+                        self.upserted.remove(&document_id);
+                        self.log.remove(&document_id);
+                        if document_id == self.current_document {
+                            self.current_document = u128::MAX;
+                            self.current_log = OpQueue::default();
+                            self.unacked.clear();
+                        }
+                        self.files.remove(&document_id);
+                        if let Some(name) = self.names.remove(&document_id)
+                            && let Err(e) = oplog_file::remove(&hidden_oplog_path(&self.dir, &name))
+                        {
+                            eprintln!("Failed to remove oplog of {:?}: {}", name, e);
+                        }
+                    }
+                    OplogMsg::Flush => {
+                        let documents: Vec<u128> = self.files.keys().copied().collect();
+                        for document_id in documents {
+                            self.compact(document_id);
+                        }
+                    }
+                    OplogMsg::SiteLeased {
+                        document_id,
+                        renamed,
+                    } => {
+                        let renamed: HashMap<Pid, Pid> = renamed.into_iter().collect();
+                        if document_id == self.current_document {
+                            self.current_log.restamp(&renamed);
+                        }
+                        if let Some(queue) = self.log.get_mut(&document_id) {
+                            queue.restamp(&renamed);
+                        }
+                        // The file still has the old PIDs
+                        self.compact(document_id);
+                        if document_id == self.current_document && self.session_available {
+                            self.send_current_log(&session_tx);
+                        }
+                        if self.sync_available {
+                            self.upsert_pending(document_id, &sync_tx);
+                        }
                     }
                     OplogMsg::SessionAvailable => {
                         self.session_available = true;
@@ -113,34 +416,39 @@ impl Oplog {
                             session_tx.send(SessionMessage::Start {
                                 document_id: self.current_document,
                                 last_sync_time: 0,
+                                site: 0,
                                 name: None,
+                                replica: 0,
                             });
+                            // Whatever didn't make it through the previous connection goes
+                            // again, followed by what got typed while we were offline
+                            for (seq, op) in &self.unacked {
+                                let _ = session_tx.send(to_session_message(*seq, op));
+                            }
+                            self.send_current_log(&session_tx);
                         }
                     }
                     OplogMsg::SyncAvailable => {
-                        for (did, l) in std::mem::take(&mut self.log) {
-                            let mut inserts = Vec::new();
-                            let mut deletes = Vec::new();
-
-                            for item in l {
-                                match item {
-                                    DocOp::Insert(pid, c) => inserts.push((pid, c)),
-                                    DocOp::Delete(pid) => deletes.push(pid),
-                                    _ => {}
-                                }
-                            }
-
-                            let req = SyncRequests::SyncDocUpsert {
-                                document_id: did,
-                                name: None,
-                                last_sync_time: 0,
-                                inserts: inserts,
-                                deletes: deletes,
-                            };
-                            sync_tx.send(req);
+                        self.sync_available = true;
+                        // Upserts the server never confirmed go again, together with the new ops
+                        // of documents that don't wait for a site
+                        let ready: Vec<u128> = self
+                            .log
+                            .iter()
+                            .filter(|(_, l)| !l.has_provisional())
+                            .map(|(&did, _)| did)
+                            .collect();
+                        for did in ready {
+                            let l = self.log.remove(&did).unwrap_or_default();
+                            self.upserted.entry(did).or_default().extend(l);
+                        }
+                        for (&did, l) in &self.upserted {
+                            send_upsert(did, l, &sync_tx);
                         }
                     }
-                    OplogMsg::SyncDown => todo!(),
+                    OplogMsg::SyncDown => {
+                        self.sync_available = false;
+                    }
                     OplogMsg::SessionDown => {
                         self.session_available = false;
                     }
@@ -148,13 +456,60 @@ impl Oplog {
                 Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
                     // no message → fall through to sending
                 }
-                Err(_) => break,
+                Err(_) => {
+                    // Shutting down, leave the files with nothing but pending ops
+                    let documents: Vec<u128> = self.files.keys().copied().collect();
+                    for document_id in documents {
+                        self.compact(document_id);
+                    }
+                    break;
+                }
             }
 
             // self.flush_to_server(&mut ws);
         }
     }
 
+    /// Store a local op on disk first, then send it or queue it for later.
+    /// Ops on provisional PIDs wait for the site lease, and everything after them too.
+    fn record(&mut self, op: DocOp, session_tx: &Sender<SessionMessage>) {
+        if let Err(e) = self.append_op(self.current_document, &op) {
+            eprintln!("Failed to write oplog: {}", e);
+        }
+        if self.session_available && self.current_log.is_empty() && !op.pid().is_provisional() {
+            self.send_op(op, session_tx);
+        } else if let Some(insert) = self.current_log.push(op) {
+            self.mark_cancelled(self.current_document, &[insert]);
+        }
+    }
+
+    /// Sends the queued ops of the current document up to the first one that waits for a lease.
+    fn send_current_log(&mut self, session_tx: &Sender<SessionMessage>) {
+        while self
+            .current_log
+            .front()
+            .is_some_and(|op| !op.pid().is_provisional())
+        {
+            let op = self.current_log.pop_front().unwrap();
+            self.send_op(op, session_tx);
+        }
+    }
+
+    /// Moves the offline ops of a document into its upsert and sends it, unless
+    /// some of them wait for a site lease.
+    fn upsert_pending(&mut self, document_id: u128, sync_tx: &Sender<SyncRequests>) {
+        let Some(queue) = self.log.get(&document_id) else {
+            return;
+        };
+        if queue.has_provisional() {
+            return;
+        }
+        let queue = self.log.remove(&document_id).unwrap_or_default();
+        let upserted = self.upserted.entry(document_id).or_default();
+        upserted.extend(queue);
+        send_upsert(document_id, upserted, sync_tx);
+    }
+
     // pub fn handle_start(&mut self, document_id: u128) {}
     pub fn flush_to_server(&mut self, ws: &mut WebSocket<MaybeTlsStream<TcpStream>>) {
         if let Some(queue) = self.log.get_mut(&self.current_document) {
@@ -162,10 +517,11 @@ impl Oplog {
                 let msg = match op {
                     DocOp::Insert(pid, c) => SessionMessage::Insert {
                         site: 0,
+                        seq: 0,
                         pid: pid,
                         c: c,
                     },
-                    DocOp::Delete(pid) => SessionMessage::Delete { site: 0, pid: pid },
+                    DocOp::Delete(pid) => SessionMessage::Delete { site: 0, seq: 0, pid: pid },
                 };
 
                 if ws.send(Message::from(msg.serialize())).is_ok() {
@@ -186,67 +542,196 @@ impl Oplog {
     //         }
     //     }
     // }
-    // /// Append current in-memory oplog to the on-disk oplog file, then clear memory.
-    // pub fn flush_oplog(&mut self) -> Result<()> {
-    //     if self.log.is_empty() {
-    //         return Ok(());
-    //     }
-    //     let path = self.get_oplog_path();
-    //     if let Some(parent) = path.parent() {
-    //         fs::create_dir_all(parent)?;
-    //     }
-    //     let file = fs::OpenOptions::new()
-    //         .create(true)
-    //         .append(true)
-    //         .open(&path)?;
-    //     let mut writer = BufWriter::new(file);
-    //     for op in &self.log {
-    //         op.write_to(&mut writer)?;
-    //     }
-    //     writer.flush()?;
-    //     self.log.clear();
-    //     Ok(())
-    // }
-    //
-    // /// Load oplog from disk. Returns empty vec if file doesn't exist or is unreadable.
-    // fn load_oplog(name: &Path) -> Vec<DocOp> {
-    //     let path = hidden_oplog_path(name);
-    //     let file = match File::open(&path) {
-    //         Ok(f) => f,
-    //         Err(_) => return Vec::new(),
-    //     };
-    //     let mut reader = BufReader::new(file);
-    //     let mut ops = Vec::new();
-    //     loop {
-    //         match DocOp::read_from(&mut reader) {
-    //             Ok(op) => ops.push(op),
-    //             Err(_) => break,
-    //         }
-    //     }
-    //     ops
-    // }
+    /// Append a single op to the document's oplog file.
+    fn append_op(&mut self, document_id: u128, op: &DocOp) -> Result<()> {
+        let Some(name) = self.names.get(&document_id) else {
+            return Ok(());
+        };
+        let file = match self.files.entry(document_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(OplogFile::create(&hidden_oplog_path(&self.dir, name), [])?)
+            }
+        };
+        file.append(op)
+    }
 
-    // /// Drain all ops from the oplog, returning them and clearing both memory and disk.
-    // pub fn drain_oplog(&mut self) -> Result<Vec<DocOp>> {
-    //     // First flush any in-memory ops to disk so we capture everything
-    //     self.flush_oplog()?;
-    //     // Reload from disk to get the full set
-    //     let ops = Self::load_oplog(&self.name);
-    //     // Clear the on-disk file
-    //     self.clear_oplog_file()?;
-    //     Ok(ops)
-    // }
-    //
-    // /// Remove the oplog file from disk.
-    // fn clear_oplog_file(&self) -> Result<()> {
-    //     let path = self.get_oplog_path();
-    //     if path.exists() {
-    //         fs::remove_file(&path)?;
-    //     }
-    //     Ok(())
-    // }
-    //
-    // fn get_oplog_path(&self) -> PathBuf {
-    //     hidden_oplog_path(&self.name)
-    // }
+    /// Marks ops the server confirmed as done in the document's oplog file.
+    fn mark_done(&mut self, document_id: u128, ops: &[DocOp]) {
+        let Some(file) = self.files.get_mut(&document_id) else {
+            return;
+        };
+        for op in ops {
+            if let Err(e) = file.done(op) {
+                eprintln!("Failed to mark op done in oplog: {}", e);
+            }
+        }
+        if file.garbage() >= COMPACT_AFTER {
+            self.compact(document_id);
+        }
+    }
+
+    /// An unsent insert and the delete that cancelled it, neither ever goes out.
+    fn mark_cancelled(&mut self, document_id: u128, inserts: &[DocOp]) {
+        let ops: Vec<DocOp> = inserts
+            .iter()
+            .flat_map(|insert| [insert.clone(), DocOp::Delete(insert.pid().clone())])
+            .collect();
+        self.mark_done(document_id, &ops);
+    }
+
+    /// Replace the document's oplog file with the ops the server still has to confirm,
+    /// removing it once there are none left.
+    fn compact(&mut self, document_id: u128) {
+        let Some(name) = self.names.get(&document_id) else {
+            return;
+        };
+        let path = hidden_oplog_path(&self.dir, name);
+
+        let mut pending: Vec<&DocOp> = Vec::new();
+        pending.extend(self.upserted.get(&document_id).into_iter().flatten());
+        pending.extend(
+            self.log
+                .get(&document_id)
+                .into_iter()
+                .flat_map(|q| q.iter()),
+        );
+        if document_id == self.current_document {
+            pending.extend(self.unacked.iter().map(|(_, op)| op));
+            pending.extend(self.current_log.iter());
+        }
+
+        let result = if pending.is_empty() {
+            self.files.remove(&document_id);
+            oplog_file::remove(&path)
+        } else {
+            OplogFile::create(&path, pending).map(|file| {
+                self.files.insert(document_id, file);
+            })
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to compact oplog {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use algos::pos::{Pos, UNLEASED_SITE};
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn pid(ident: u32, site: u32) -> Pid {
+        Pid(vec![Pos::new(ident, site)])
+    }
+
+    #[test]
+    fn restamp_moves_provisional_ops_and_drops_the_gone_ones() {
+        let mut queue = OpQueue::default();
+        queue.push(DocOp::Insert(pid(1, 3), 'a'));
+        queue.push(DocOp::Insert(pid(2, UNLEASED_SITE), 'b'));
+        queue.push(DocOp::Delete(pid(3, UNLEASED_SITE)));
+        assert!(queue.has_provisional());
+
+        let renamed = HashMap::from([(pid(2, UNLEASED_SITE), pid(2, 3))]);
+        queue.restamp(&renamed);
+        assert!(!queue.has_provisional());
+        let ops: Vec<_> = queue.iter().cloned().collect();
+        assert_eq!(
+            ops,
+            vec![DocOp::Insert(pid(1, 3), 'a'), DocOp::Insert(pid(2, 3), 'b')]
+        );
+
+        // The re-stamped insert was never sent, deleting it cancels it as before
+        queue.push(DocOp::Delete(pid(2, 3)));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn deleting_an_unsent_insert_cancels_both() {
+        let mut queue = OpQueue::default();
+        queue.push(DocOp::Insert(pid(1, 3), 'a'));
+        queue.push(DocOp::Insert(pid(2, 3), 'b'));
+        let cancelled = queue.push(DocOp::Delete(pid(1, 3)));
+        assert_eq!(cancelled, Some(DocOp::Insert(pid(1, 3), 'a')));
+        let ops: Vec<_> = queue.iter().cloned().collect();
+        assert_eq!(ops, vec![DocOp::Insert(pid(2, 3), 'b')]);
+    }
+
+    #[test]
+    fn deleting_a_sent_insert_is_queued() {
+        let mut queue = OpQueue::default();
+        queue.push(DocOp::Insert(pid(1, 3), 'a'));
+        let sent = queue.pop_front().unwrap();
+        assert_eq!(queue.push(DocOp::Delete(pid(1, 3))), None);
+        assert_eq!(queue.len(), 1);
+
+        // Back in front after the session dropped unacked, it may have reached the server
+        queue.push_front(sent);
+        assert_eq!(queue.push(DocOp::Delete(pid(1, 3))), None);
+        let ops: Vec<_> = queue.iter().cloned().collect();
+        assert_eq!(
+            ops,
+            vec![
+                DocOp::Insert(pid(1, 3), 'a'),
+                DocOp::Delete(pid(1, 3)),
+                DocOp::Delete(pid(1, 3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn pending_ops_survive_a_reload() {
+        let dir = env::temp_dir().join(format!("notek-oplog-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let names = HashMap::from([(7, PathBuf::from("note.md"))]);
+
+        let mut oplog = Oplog::init(&dir, names.clone()).unwrap();
+        oplog.current_document = 7;
+        let (session_tx, _session_rx) = std::sync::mpsc::channel();
+        oplog.record(DocOp::Insert(pid(1, 3), 'a'), &session_tx);
+        oplog.record(DocOp::Insert(pid(2, 3), 'b'), &session_tx);
+        oplog.record(DocOp::Delete(pid(1, 3)), &session_tx);
+        oplog.record(DocOp::Insert(pid(3, 3), 'c'), &session_tx);
+        drop(oplog);
+
+        // Erased text doesn't come back, the rest waits to be upserted
+        let oplog = Oplog::init(&dir, names).unwrap();
+        let ops: Vec<_> = oplog.log[&7].iter().cloned().collect();
+        assert_eq!(
+            ops,
+            vec![DocOp::Insert(pid(2, 3), 'b'), DocOp::Insert(pid(3, 3), 'c')]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sent_ops_survive_a_reload_uncancelled() {
+        let dir = env::temp_dir().join(format!("notek-oplog-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let names = HashMap::from([(7, PathBuf::from("note.md"))]);
+
+        let mut oplog = Oplog::init(&dir, names.clone()).unwrap();
+        oplog.current_document = 7;
+        oplog.session_available = true;
+        let (session_tx, _session_rx) = std::sync::mpsc::channel();
+        let (insert, delete) = (DocOp::Insert(pid(1, 3), 'a'), DocOp::Delete(pid(1, 3)));
+        oplog.record(insert.clone(), &session_tx);
+        oplog.record(delete.clone(), &session_tx);
+        assert_eq!(oplog.unacked.len(), 2);
+        drop(oplog);
+
+        // The server may have the insert, only the delete erases it there
+        let mut oplog = Oplog::init(&dir, names).unwrap();
+        let ops: Vec<_> = oplog.log[&7].iter().cloned().collect();
+        assert_eq!(ops, vec![insert, delete.clone()]);
+
+        let queue = oplog.log.get_mut(&7).unwrap();
+        assert_eq!(queue.push(delete), None);
+        assert_eq!(queue.len(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,261 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use algos::sync::DocOp;
+use anyhow::{Result, anyhow};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+const OPLOG_MAGIC: [u8; 4] = *b"NTKO";
+const OPLOG_VERSION: u8 = 1;
+/// Where the watermark sits in the header
+const DONE_OFFSET: u64 = 5;
+const HEADER_LEN: u64 = DONE_OFFSET + 8;
+/// Set in the tag of a record that's done while records before it aren't
+const DONE_FLAG: u8 = 0x80;
+
+/// A document's oplog on disk. Ops get appended as they're made and stay
+/// until the file is compacted, the header says how many of them are done.
+///
+/// File format:
+///   [u8; 4] magic - "NTKO"
+///   u8      version - 1
+///   u64     done - records at the start the server confirmed, or that cancelled out
+///   repeated till EOF: an op as written by `DocOp::write_to`, its tag
+///     has the top bit set once it's done
+/// Files from before the header are nothing but records, none of them done.
+#[derive(Debug)]
+pub struct OplogFile {
+    file: File,
+    /// Where the next record goes
+    len: u64,
+    records: u64,
+    done: u64,
+    /// Record number and offset of every op that still has to reach the server
+    pending: HashMap<DocOp, (u64, u64)>,
+    open_records: BTreeSet<u64>,
+}
+
+impl OplogFile {
+    /// The ops a previous run didn't get confirmed, in the order they were made.
+    /// A torn op at the end is what a crash mid-append leaves, it gets dropped.
+    pub fn read_pending(path: &Path) -> Result<Vec<DocOp>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut reader = BufReader::new(file);
+        let mut magic = [0u8; 4];
+        let done = match reader.read_exact(&mut magic) {
+            Ok(()) if magic == OPLOG_MAGIC => {
+                let version = reader.read_u8()?;
+                if version != OPLOG_VERSION {
+                    return Err(anyhow!("Unsupported oplog version {}", version));
+                }
+                reader.read_u64::<LittleEndian>()?
+            }
+            _ => {
+                reader.seek(SeekFrom::Start(0))?;
+                0
+            }
+        };
+
+        let mut ops = Vec::new();
+        let mut records = 0;
+        while let Ok(tag) = reader.read_u8() {
+            let Ok(op) = DocOp::read_from(&mut [tag & !DONE_FLAG].chain(&mut reader)) else {
+                break;
+            };
+            if records >= done && tag & DONE_FLAG == 0 {
+                ops.push(op);
+            }
+            records += 1;
+        }
+        Ok(ops)
+    }
+
+    /// Replaces whatever is at `path` with a file holding `ops`, all pending.
+    pub fn create<'a>(path: &Path, ops: impl IntoIterator<Item = &'a DocOp>) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&OPLOG_MAGIC);
+        buf.push(OPLOG_VERSION);
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        let mut oplog = OplogFile {
+            file: File::create(path.with_extension("oplog.tmp"))?,
+            len: HEADER_LEN,
+            records: 0,
+            done: 0,
+            pending: HashMap::new(),
+            open_records: BTreeSet::new(),
+        };
+        for op in ops {
+            let start = buf.len();
+            op.write_to(&mut buf)?;
+            if let Some((record, offset)) = oplog.track(op.clone(), (buf.len() - start) as u64) {
+                oplog.open_records.remove(&record);
+                buf[offset as usize] |= DONE_FLAG;
+            }
+        }
+        oplog.file.write_all(&buf)?;
+        oplog.file.sync_all()?;
+        fs::rename(path.with_extension("oplog.tmp"), path)?;
+
+        oplog.file = OpenOptions::new().read(true).write(true).open(path)?;
+        oplog.file.seek(SeekFrom::End(0))?;
+        Ok(oplog)
+    }
+
+    pub fn append(&mut self, op: &DocOp) -> Result<()> {
+        let mut buf = Vec::new();
+        op.write_to(&mut buf)?;
+        self.file.write_all(&buf)?;
+        // Like the server's WAL, an op isn't made until it's on disk
+        self.file.sync_data()?;
+        if let Some((record, offset)) = self.track(op.clone(), buf.len() as u64) {
+            self.settle(record, offset)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the record and offset of the op's old record, which the same
+    /// op made again supersedes.
+    fn track(&mut self, op: DocOp, len: u64) -> Option<(u64, u64)> {
+        let old = self.pending.insert(op, (self.records, self.len));
+        self.open_records.insert(self.records);
+        self.records += 1;
+        self.len += len;
+        old
+    }
+
+    /// The server has the op, or it cancelled out before it was ever sent.
+    /// Moves the watermark over every record that's done by now, a record
+    /// past it gets flagged on its own.
+    pub fn done(&mut self, op: &DocOp) -> Result<()> {
+        let Some((record, offset)) = self.pending.remove(op) else {
+            return Ok(());
+        };
+        self.settle(record, offset)
+    }
+
+    fn settle(&mut self, record: u64, offset: u64) -> Result<()> {
+        self.open_records.remove(&record);
+        let done = self.open_records.first().copied().unwrap_or(self.records);
+        if done != self.done {
+            self.file.write_all_at(&done.to_le_bytes(), DONE_OFFSET)?;
+            self.done = done;
+        }
+        if record >= done {
+            let mut tag = [0u8];
+            self.file.read_exact_at(&mut tag, offset)?;
+            self.file.write_all_at(&[tag[0] | DONE_FLAG], offset)?;
+        }
+        Ok(())
+    }
+
+    /// Records that are only taking up space, compacting drops them.
+    pub fn garbage(&self) -> u64 {
+        self.records - self.pending.len() as u64
+    }
+}
+
+/// Removes the oplog at `path`, if there is one.
+pub fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use algos::pid::Pid;
+    use algos::pos::Pos;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn scratch_path() -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("notek-oplog-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(".note.md.oplog")
+    }
+
+    fn insert(ident: u32, c: char) -> DocOp {
+        DocOp::Insert(Pid(vec![Pos::new(ident, 2)]), c)
+    }
+
+    #[test]
+    fn done_ops_are_skipped_on_reload() {
+        let path = scratch_path();
+        let (a, b) = (insert(1, 'a'), insert(2, 'b'));
+        let (c, d) = (insert(3, 'c'), insert(4, 'd'));
+        let mut oplog = OplogFile::create(&path, [&a, &b]).unwrap();
+        oplog.append(&c).unwrap();
+        oplog.append(&d).unwrap();
+
+        // Past the watermark, done records get flagged one by one
+        oplog.done(&c).unwrap();
+        let pending = OplogFile::read_pending(&path).unwrap();
+        assert_eq!(pending, vec![a.clone(), b.clone(), d.clone()]);
+        oplog.done(&b).unwrap();
+        oplog.done(&a).unwrap();
+        assert_eq!(OplogFile::read_pending(&path).unwrap(), vec![d]);
+        assert_eq!(oplog.garbage(), 3);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn an_op_made_again_is_pending_once() {
+        let path = scratch_path();
+        let (a, b) = (insert(1, 'a'), insert(2, 'b'));
+        let mut oplog = OplogFile::create(&path, [&a, &b, &a]).unwrap();
+        oplog.append(&b).unwrap();
+        let pending = OplogFile::read_pending(&path).unwrap();
+        assert_eq!(pending, vec![a.clone(), b.clone()]);
+        assert_eq!(oplog.garbage(), 2);
+
+        oplog.done(&b).unwrap();
+        assert_eq!(OplogFile::read_pending(&path).unwrap(), vec![a]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn a_torn_last_record_is_dropped() {
+        let path = scratch_path();
+        let (a, b) = (insert(1, 'a'), insert(2, 'ł'));
+        let mut oplog = OplogFile::create(&path, [&a, &b]).unwrap();
+        drop(oplog);
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 2).unwrap();
+        assert_eq!(OplogFile::read_pending(&path).unwrap(), vec![a.clone()]);
+
+        // Compacting at startup is what gets rid of the torn bytes
+        oplog = OplogFile::create(&path, [&a]).unwrap();
+        oplog.append(&b).unwrap();
+        assert_eq!(OplogFile::read_pending(&path).unwrap(), vec![a, b]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn reads_files_from_before_the_header() {
+        let path = scratch_path();
+        let (a, b) = (insert(1, 'a'), DocOp::Delete(Pid(vec![Pos::new(1, 2)])));
+        let mut buf = Vec::new();
+        a.write_to(&mut buf).unwrap();
+        b.write_to(&mut buf).unwrap();
+        fs::write(&path, buf).unwrap();
+        assert_eq!(OplogFile::read_pending(&path).unwrap(), vec![a, b]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,61 +1,158 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
 use std::sync::mpsc::{self, TryRecvError};
 use std::thread;
 use std::time::Duration;
 
-use algos::session::SessionMessage;
-use tungstenite::{connect, Message};
+use algos::pos::SiteId;
+use algos::session::{DELETED_REASON, SessionMessage};
+use algos::PROTOCOL_PATH;
+use anyhow::{Result, anyhow};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::client::connect_with_config;
+use tungstenite::{Error, Message};
+use uuid::Uuid;
 
 use crate::app::AppEvent;
+use crate::config::ServerConfig;
 
-const SERVER_URL: &str = "ws://127.0.0.1:9001";
-const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a read from the server may block before we check for outgoing messages again.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
 
-/// Sync thread: maintains a WebSocket connection to the sync server.
+const REPLICA_FILE: &str = ".notek.replica";
+
+/// Identifies this installation to the server, which binds the sites it leases
+/// to it. Made up on the first start and kept in the data directory.
+pub fn load_replica_id(data_dir: &Path) -> Result<u128> {
+    let path = data_dir.join(REPLICA_FILE);
+    match fs::read(&path) {
+        Ok(bytes) => {
+            let bytes: [u8; 16] = bytes
+                .try_into()
+                .map_err(|_| anyhow!("Replica id file {:?} is corrupted", path))?;
+            return Ok(u128::from_le_bytes(bytes));
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let replica = Uuid::new_v4().as_u128();
+    let tmp_path = path.with_extension("replica.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&replica.to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(replica)
+}
+
+/// Session thread: maintains a WebSocket connection for live editing sessions.
 ///
 /// - On startup (and after disconnection), attempts to connect in a loop
 ///   with a delay between attempts.
-/// - Signals `ServerConnected` / `ServerDisconnected` to the app event loop.
-/// - Drains `SyncRequests` from `rx` and sends them over the WebSocket.
-/// - If the WebSocket send fails, signals disconnection and reconnects.
-pub fn handle_session_communication(rx: mpsc::Receiver<SessionMessage>, app_tx: mpsc::Sender<AppEvent>) {
+/// - Signals `SessionConnected` / `SessionDisconnected` to the app event loop.
+/// - Drains `SessionMessage`s from `rx` and sends them over the WebSocket.
+/// - Forwards whatever the server sends back to the app event loop.
+/// - If the WebSocket breaks, signals disconnection and reconnects.
+pub fn handle_session_communication(
+    rx: mpsc::Receiver<SessionMessage>,
+    app_tx: mpsc::Sender<AppEvent>,
+    server: ServerConfig,
+    replica: u128,
+) {
+    // Sites the server leased to us, so that a reconnect asks for the same one
+    let mut sites: HashMap<u128, SiteId> = HashMap::new();
+    // Document of the last start we sent, the one the server closes the session over
+    let mut document: u128 = 0;
+
+    let server_url = format!("{}{}", server.url, PROTOCOL_PATH);
+
     loop {
         // --- connect phase: retry until we get a connection ---
         let mut ws = loop {
-            match connect(SERVER_URL) {
+            match connect_with_config(&server_url, Some(server.websocket), 3) {
                 Ok((ws, _)) => {
-                    println!("Session: connected to {}", SERVER_URL);
+                    println!("Session: connected to {}", server_url);
                     let _ = app_tx.send(AppEvent::SessionConnected);
                     break ws;
                 }
                 Err(e) => {
                     println!(
                         "Session: connection failed ({}), retrying in {:?}...",
-                        e, RETRY_INTERVAL
+                        e, server.retry_interval
                     );
                     // Drain any messages that arrived while we were disconnected
                     // so we don't build up an unbounded backlog.
                     // They will be re-synced on next successful connection anyway.
                     drain(&rx);
-                    thread::sleep(RETRY_INTERVAL);
+                    thread::sleep(server.retry_interval);
                 }
             }
         };
+        if let MaybeTlsStream::Plain(stream) = ws.get_ref() {
+            let _ = stream.set_read_timeout(Some(POLL_INTERVAL));
+        }
+
+        'connected: loop {
+            // --- send phase: forward messages until the channel closes or WS breaks ---
+            loop {
+                match rx.try_recv() {
+                    Ok(mut cmd) => {
+                        if let SessionMessage::Start {
+                            document_id,
+                            site,
+                            replica: from,
+                            ..
+                        } = &mut cmd
+                        {
+                            if *site == 0 {
+                                *site = sites.get(document_id).copied().unwrap_or(0);
+                            }
+                            *from = replica;
+                            document = *document_id;
+                        }
+                        let msg = Message::from(cmd.serialize());
+                        if let Err(e) = ws.send(msg) {
+                            eprintln!("Session: send failed ({}), reconnecting...", e);
+                            let _ = app_tx.send(AppEvent::SessionDisconnected);
+                            break 'connected; // back to connect phase
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        // Channel closed — app is shutting down
+                        let _ = ws.close(None);
+                        return;
+                    }
+                }
+            }
 
-        // --- send phase: forward messages until the channel closes or WS breaks ---
-        loop {
-            match rx.recv() {
-                Ok(cmd) => {
-                    let msg = Message::from(cmd.serialize());
-                    if let Err(e) = ws.send(msg) {
-                        eprintln!("Session: send failed ({}), reconnecting...", e);
-                        let _ = app_tx.send(AppEvent::SessionDisconnected);
-                        break; // back to connect phase
+            // --- receive phase: wait a little for anything the server has for us ---
+            match ws.read() {
+                Ok(Message::Binary(bin)) => {
+                    let msg = match SessionMessage::deserialize(&bin) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            eprintln!("Session: rejecting malformed frame ({})", e);
+                            continue;
+                        }
+                    };
+                    if let SessionMessage::Started { document_id, site } = msg {
+                        sites.insert(document_id, site);
                     }
+                    let _ = app_tx.send(AppEvent::SessionMsg(msg));
                 }
-                Err(_) => {
-                    // Channel closed — app is shutting down
-                    let _ = ws.close(None);
-                    return;
+                Ok(Message::Close(Some(frame))) if frame.reason == DELETED_REASON => {
+                    let _ = app_tx.send(AppEvent::DocDeleted(document));
+                }
+                Ok(_) => {}
+                Err(Error::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => {
+                    eprintln!("Session: read failed ({}), reconnecting...", e);
+                    let _ = app_tx.send(AppEvent::SessionDisconnected);
+                    break; // back to connect phase
                 }
             }
         }
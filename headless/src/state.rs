@@ -5,9 +5,12 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use algos::{doc::Doc, pid::Pid, structure::DocStructure};
+use algos::{doc::Doc, pid::{Pid, Strategy}, pos::SiteId, structure::DocStructure, sync::DocOp};
 use anyhow::{anyhow, Result};
 
+use crate::diff::Hunk;
+use crate::sync_times::SyncTimes;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConnectionStatus {
     Disconnected,
@@ -22,12 +25,16 @@ pub struct State {
     pub by_time: BTreeMap<u64, usize>,
     pub by_id: HashMap<u128, usize>,
     pub by_name: HashMap<PathBuf, usize>,
+    /// Allocation strategy of the documents created from now on
+    pub strategy: Strategy,
+    /// How far the documents were pulled from the server
+    pub sync_times: SyncTimes,
     // pub sync_connection_status: ConnectionStatus,
     // pub session_connection_status: ConnectionStatus,
 }
 
 impl State {
-    pub fn init(dir: &Path) -> Result<Self> {
+    pub fn init(dir: &Path, strategy: Strategy) -> Result<Self> {
         let base_dir = if dir.is_absolute() {
             dir.to_path_buf()
         } else {
@@ -35,6 +42,7 @@ impl State {
         };
 
         let base_dir = std::fs::canonicalize(base_dir)?;
+        let sync_times = SyncTimes::load(&base_dir)?;
 
         let mut s = State {
             docs: Vec::new(),
@@ -43,6 +51,8 @@ impl State {
             by_time: BTreeMap::new(),
             by_id: HashMap::new(),
             by_name: HashMap::new(),
+            strategy,
+            sync_times,
             // sync_connection_status: ConnectionStatus::Disconnected,
             // session_connection_status: ConnectionStatus::Disconnected,
         };
@@ -68,7 +78,7 @@ impl State {
     }
 
     pub fn add_doc(&mut self, name: PathBuf, upsertid: Option<u128>) -> Result<&DocStructure> {
-        let mut s = DocStructure::load_or_create(&name, upsertid)?;
+        let mut s = DocStructure::load_or_create(&self.base_dir, &name, upsertid, self.strategy)?;
         let doc_id = s.id;
         let idx = self.docs.len();
         self.by_id.insert(s.id, idx);
@@ -82,6 +92,25 @@ impl State {
         Ok(self.docs.last().unwrap())
     }
 
+    /// Forgets a document, returning it so its files can be dealt with.
+    pub fn remove_doc(&mut self, document_id: u128) -> Option<DocStructure> {
+        let idx = self.by_id.remove(&document_id)?;
+        let ds = self.docs.swap_remove(idx);
+        self.by_name.remove(&ds.name);
+        self.by_time.remove(&ds.last_modified);
+        if self.current_doc == idx {
+            self.current_doc = usize::MAX;
+        } else if self.current_doc == self.docs.len() {
+            self.current_doc = idx;
+        }
+        if let Some(moved) = self.docs.get(idx) {
+            self.by_id.insert(moved.id, idx);
+            self.by_name.insert(moved.name.clone(), idx);
+            self.by_time.insert(moved.last_modified, idx);
+        }
+        Some(ds)
+    }
+
     pub fn move_doc(&mut self, from: PathBuf, to: PathBuf) {
         if let Some(idx) = self.by_name.remove(&from) {
             if let Err(e) = self.docs[idx].update_name_after_external_rename(&to) {
@@ -107,6 +136,65 @@ impl State {
     pub fn delete_in_current_doc(&mut self, start: u32, len: u32) -> Vec<Pid> {
         self.docs[self.current_doc].delete_byte_range(start as usize, len as usize)
     }
+    /// None if the position is not in the current document.
+    pub fn insert_at_line_col_in_current_doc(
+        &mut self,
+        line: u32,
+        col: u32,
+        text: &str,
+    ) -> Option<Vec<(Pid, char)>> {
+        self.docs[self.current_doc].insert_at_line_col(line as usize, col as usize, text)
+    }
+    /// None if either end is not a position in the current document.
+    pub fn delete_line_col_range_in_current_doc(
+        &mut self,
+        start: (u32, u32),
+        end: (u32, u32),
+    ) -> Option<Vec<Pid>> {
+        self.docs[self.current_doc].delete_line_col_range(
+            start.0 as usize,
+            start.1 as usize,
+            end.0 as usize,
+            end.1 as usize,
+        )
+    }
+
+    /// Applies another participant's insert to the current document, returning its byte offset.
+    pub fn apply_remote_insert(&mut self, pid: Pid, c: char) -> Option<usize> {
+        if self.current_doc == usize::MAX {
+            return None;
+        }
+        self.docs[self.current_doc].apply_remote_insert(pid, c)
+    }
+    /// Applies another participant's delete to the current document, returning the removed byte range.
+    pub fn apply_remote_delete(&mut self, pid: &Pid) -> Option<(usize, usize)> {
+        if self.current_doc == usize::MAX {
+            return None;
+        }
+        self.docs[self.current_doc].apply_remote_delete(pid)
+    }
+
+    /// Applies hunks of a diff against the document's text, last one first so the
+    /// byte offsets of the earlier ones stay valid. Returns the ops it took.
+    pub fn apply_hunks(&mut self, idx: usize, hunks: &[Hunk]) -> Vec<DocOp> {
+        let doc = &mut self.docs[idx];
+        let mut ops = Vec::new();
+        for hunk in hunks.iter().rev() {
+            if hunk.delete_len > 0 {
+                let deleted = doc.delete_byte_range(hunk.start_byte, hunk.delete_len);
+                ops.extend(deleted.into_iter().map(DocOp::Delete));
+            }
+            if !hunk.insert.is_empty() {
+                let inserted = doc.insert_text_at_bytepos(hunk.start_byte, &hunk.insert);
+                ops.extend(inserted.into_iter().map(|(pid, c)| DocOp::Insert(pid, c)));
+            }
+        }
+        ops
+    }
+
+    pub fn is_current_doc(&self, name: &PathBuf) -> bool {
+        self.by_name.get(name) == Some(&self.current_doc)
+    }
 
     pub fn flush_current_doc(&mut self) -> Result<()> {
         let current_doc = &mut self.docs[self.current_doc];
@@ -114,6 +202,25 @@ impl State {
         Ok(())
     }
 
+    /// Takes the site the server leased for a document and moves the atoms
+    /// generated before it onto it, returning the (old, new) PIDs.
+    pub fn lease_doc_site(&mut self, document_id: u128, site: SiteId) -> Vec<(Pid, Pid)> {
+        let Some(&idx) = self.by_id.get(&document_id) else {
+            return Vec::new();
+        };
+        let doc = &mut self.docs[idx];
+        let renamed = doc.lease_site(site);
+        // On disk before the oplog forgets the old PIDs
+        if let Err(e) = doc.flush() {
+            eprintln!("Failed to flush {:?} after leasing a site: {}", doc.name, e);
+        }
+        renamed
+    }
+
+    pub fn get_current_doc_site(&self) -> SiteId {
+        self.docs[self.current_doc].get_doc().site
+    }
+
     pub fn get_current_doc_id(&self) -> u128 {
         self.docs[self.current_doc].id
     }
@@ -1,60 +1,96 @@
+use std::io::ErrorKind;
 use std::sync::mpsc::{self, TryRecvError};
 use std::thread;
 use std::time::Duration;
 
-use algos::sync::SyncRequests;
-use tungstenite::{connect, Message};
+use algos::sync::{SyncRequests, SyncResponses};
+use algos::PROTOCOL_PATH;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::client::connect_with_config;
+use tungstenite::{Error, Message};
 
 use crate::app::AppEvent;
+use crate::config::ServerConfig;
 
-const SERVER_URL: &str = "ws://127.0.0.1:9001";
-const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a read from the server may block before we check for outgoing messages again.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
 
 /// Sync thread: maintains a WebSocket connection to the sync server.
 ///
 /// - On startup (and after disconnection), attempts to connect in a loop
 ///   with a delay between attempts.
 /// - Drains `SyncRequests` from `rx` and sends them over the WebSocket.
-/// - If the WebSocket send fails, signals disconnection and reconnects.
-pub fn handle_sync_communication(rx: mpsc::Receiver<SyncRequests>, app_tx: mpsc::Sender<AppEvent>) {
+/// - Forwards whatever the server sends back to the app event loop.
+/// - If the WebSocket breaks, signals disconnection and reconnects.
+pub fn handle_sync_communication(
+    rx: mpsc::Receiver<SyncRequests>,
+    app_tx: mpsc::Sender<AppEvent>,
+    server: ServerConfig,
+) {
+    let server_url = format!("{}{}", server.url, PROTOCOL_PATH);
+
     loop {
         // --- connect phase: retry until we get a connection ---
         let mut ws = loop {
-            match connect(SERVER_URL) {
+            match connect_with_config(&server_url, Some(server.websocket), 3) {
                 Ok((ws, _)) => {
-                    println!("Sync: connected to {}", SERVER_URL);
+                    println!("Sync: connected to {}", server_url);
                     let _ = app_tx.send(AppEvent::SyncConnected);
                     break ws;
                 }
                 Err(e) => {
                     println!(
                         "Sync: connection failed ({}), retrying in {:?}...",
-                        e, RETRY_INTERVAL
+                        e, server.retry_interval
                     );
                     // Drain any messages that arrived while we were disconnected
                     // so we don't build up an unbounded backlog.
                     // They will be re-synced on next successful connection anyway.
                     drain(&rx);
-                    thread::sleep(RETRY_INTERVAL);
+                    thread::sleep(server.retry_interval);
                 }
             }
         };
+        if let MaybeTlsStream::Plain(stream) = ws.get_ref() {
+            let _ = stream.set_read_timeout(Some(POLL_INTERVAL));
+        }
 
-        // --- send phase: forward messages until the channel closes or WS breaks ---
-        loop {
-            match rx.recv() {
-                Ok(cmd) => {
-                    let msg = Message::from(cmd.serialize());
-                    if let Err(e) = ws.send(msg) {
-                        eprintln!("Sync: send failed ({}), reconnecting...", e);
-                        let _ = app_tx.send(AppEvent::SyncDisconnected);
-                        break; // back to connect phase
+        'connected: loop {
+            // --- send phase: forward messages until the channel closes or WS breaks ---
+            loop {
+                match rx.try_recv() {
+                    Ok(cmd) => {
+                        let msg = Message::from(cmd.serialize());
+                        if let Err(e) = ws.send(msg) {
+                            eprintln!("Sync: send failed ({}), reconnecting...", e);
+                            let _ = app_tx.send(AppEvent::SyncDisconnected);
+                            break 'connected; // back to connect phase
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        // Channel closed — app is shutting down
+                        let _ = ws.close(None);
+                        return;
                     }
                 }
-                Err(_) => {
-                    // Channel closed — app is shutting down
-                    let _ = ws.close(None);
-                    return;
+            }
+
+            // --- receive phase: wait a little for anything the server has for us ---
+            match ws.read() {
+                Ok(Message::Binary(bin)) => match SyncResponses::deserialize(&bin) {
+                    Ok(reply) => {
+                        let _ = app_tx.send(AppEvent::SyncMsg(reply));
+                    }
+                    Err(e) => eprintln!("Sync: rejecting malformed frame ({})", e),
+                },
+                Ok(_) => {}
+                Err(Error::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => {
+                    eprintln!("Sync: read failed ({}), reconnecting...", e);
+                    let _ = app_tx.send(AppEvent::SyncDisconnected);
+                    break; // back to connect phase
                 }
             }
         }
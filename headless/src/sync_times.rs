@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+const SYNC_FILE: &str = ".notek.sync";
+const SYNC_MAGIC: [u8; 4] = *b"NTKT";
+const SYNC_VERSION: u8 = 1;
+
+/// Server times up to which this replica pulled changes, so that the server
+/// only has to send what happened since.
+///
+/// File format:
+///   [u8; 4] magic - "NTKT"
+///   u8      version - 1
+///   u64     newest modification time of the last sync list
+///   repeated till EOF:
+///     u128 document_id
+///     u64  modification time of the document when it was last pulled
+#[derive(Debug)]
+pub struct SyncTimes {
+    path: PathBuf,
+    list: u64,
+    docs: HashMap<u128, u64>,
+}
+
+impl SyncTimes {
+    pub fn load(base_dir: &Path) -> Result<Self> {
+        let path = base_dir.join(SYNC_FILE);
+        let mut times = SyncTimes {
+            path,
+            list: 0,
+            docs: HashMap::new(),
+        };
+
+        let bytes = match fs::read(&times.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(times),
+            Err(e) => return Err(e).context("Failed to open sync times"),
+        };
+        let mut reader = match bytes.strip_prefix(&SYNC_MAGIC) {
+            Some([SYNC_VERSION, rest @ ..]) => rest,
+            Some([version, ..]) => {
+                return Err(anyhow!("Unsupported sync times version {}", version));
+            }
+            _ => return Err(anyhow!("Not a sync times file")),
+        };
+        times.list = reader
+            .read_u64::<LittleEndian>()
+            .context("Truncated sync times file")?;
+        while !reader.is_empty() {
+            let document_id = reader
+                .read_u128::<LittleEndian>()
+                .context("Truncated sync times file")?;
+            let time = reader
+                .read_u64::<LittleEndian>()
+                .context("Truncated sync times file")?;
+            times.docs.insert(document_id, time);
+        }
+        Ok(times)
+    }
+
+    /// Where the next sync list starts.
+    pub fn list(&self) -> u64 {
+        self.list
+    }
+
+    /// Where the next pull of the document starts, 0 if it never got pulled.
+    pub fn doc(&self, document_id: u128) -> u64 {
+        self.docs.get(&document_id).copied().unwrap_or(0)
+    }
+
+    pub fn set_list(&mut self, time: u64) {
+        self.list = time;
+        self.persist_or_complain();
+    }
+
+    pub fn set_doc(&mut self, document_id: u128, time: u64) {
+        self.docs.insert(document_id, time);
+        self.persist_or_complain();
+    }
+
+    pub fn forget_doc(&mut self, document_id: u128) {
+        if self.docs.remove(&document_id).is_some() {
+            self.persist_or_complain();
+        }
+    }
+
+    fn persist_or_complain(&self) {
+        // Losing them only costs a bigger sync next time
+        if let Err(e) = self.persist() {
+            eprintln!("Failed to persist sync times: {}", e);
+        }
+    }
+
+    fn persist(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("sync.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(&SYNC_MAGIC)?;
+        writer.write_all(&[SYNC_VERSION])?;
+        writer.write_all(&self.list.to_le_bytes())?;
+        for (document_id, time) in &self.docs {
+            writer.write_all(&document_id.to_le_bytes())?;
+            writer.write_all(&time.to_le_bytes())?;
+        }
+        writer.flush()?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn times_survive_a_reload() {
+        let dir = env::temp_dir().join(format!("notek-sync-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut times = SyncTimes::load(&dir).unwrap();
+        assert_eq!((times.list(), times.doc(7)), (0, 0));
+        times.set_list(40);
+        times.set_doc(7, 30);
+
+        let times = SyncTimes::load(&dir).unwrap();
+        assert_eq!((times.list(), times.doc(7), times.doc(8)), (40, 30, 0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -23,7 +23,7 @@ suspend fun main() {
     println("Hello")
     var doc = Doc.fromString("local", 0.toUByte());
     var docid = Uuid.random();
-    client.ws(method = HttpMethod.Get, host = "127.0.0.1", port=9001, path = "/") {
+    client.ws(method = HttpMethod.Get, host = "127.0.0.1", port=9001, path = "/v2") {
         // send a message
         val sr = SyncRequests.SyncList(0u)
         val bytes = Buffer();
@@ -50,7 +50,7 @@ suspend fun main() {
             }
         }
     }
-    client.ws(method = HttpMethod.Get, host = "127.0.0.1", port=9001, path = "/") {
+    client.ws(method = HttpMethod.Get, host = "127.0.0.1", port=9001, path = "/v2") {
         val greet = PeerMessage.Start(0u, docid)
         send(greet.serialize())
         val lp = doc.content.keys
@@ -67,7 +67,7 @@ suspend fun main() {
 //    }
 //    println("Hello")
 //    var doc = Doc.fromString("local", 0.toUByte());
-//    client.ws(method = HttpMethod.Get, host = "127.0.0.1", port=9001, path = "/") {
+//    client.ws(method = HttpMethod.Get, host = "127.0.0.1", port=9001, path = "/v2") {
 //        // send a message
 //        val bytes = PeerMessage.Greet.serialize();
 //        send(bytes)
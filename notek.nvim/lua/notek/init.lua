@@ -8,6 +8,13 @@ local M = {}
 ---@type table<integer, boolean>
 local attached = {}
 
+--- Buffer of the document last selected on the connection.
+---@type integer?
+local current_buf = nil
+
+--- Set while applying remote changes so on_bytes doesn't send them back.
+local applying_remote = false
+
 ---@param bufnr integer
 ---@return boolean
 local function is_trackable(bufnr)
@@ -31,9 +38,75 @@ end
 local function select_document(bufnr)
   local conn = socket.get()
   local buf_name = vim.api.nvim_buf_get_name(bufnr)
+  current_buf = bufnr
+  conn.sent = 0
+  conn.resyncing = false
   conn:send(protocol.encode_document_select(buf_name))
 end
 
+---Convert a byte offset into a 0-based (row, col) of the buffer.
+---@param bufnr integer
+---@param offset integer
+---@return integer, integer
+local function byte_to_pos(bufnr, offset)
+  local lo, hi = 0, vim.api.nvim_buf_line_count(bufnr) - 1
+  -- Last line starting at or before offset
+  while lo < hi do
+    local mid = math.floor((lo + hi + 1) / 2)
+    if vim.api.nvim_buf_get_offset(bufnr, mid) <= offset then
+      lo = mid
+    else
+      hi = mid - 1
+    end
+  end
+  return lo, offset - vim.api.nvim_buf_get_offset(bufnr, lo)
+end
+
+---Apply a change pushed by the headless client to the selected buffer.
+---@param msg notek.DaemonMessage
+---@param conn notek.Connection
+local function apply_remote(msg, conn)
+  local bufnr = current_buf
+  if not bufnr or not vim.api.nvim_buf_is_valid(bufnr) then return end
+
+  if msg.rev and msg.rev < conn.sent then
+    -- Made before some of our edits reached the headless client, so the
+    -- offsets are off. Drop it and get the whole text once instead.
+    if msg.kind == "replace" or not conn.resyncing then
+      conn.resyncing = true
+      conn:send(protocol.encode_resync())
+    end
+    return
+  end
+  if msg.kind == "replace" then conn.resyncing = false end
+
+  applying_remote = true
+  local ok, err = pcall(function()
+    if msg.kind == "insert" then
+      local row, col = byte_to_pos(bufnr, msg.start_byte)
+      local lines = vim.split(msg.text, "\n", { plain = true })
+      vim.api.nvim_buf_set_text(bufnr, row, col, row, col, lines)
+    elseif msg.kind == "delete" then
+      local start_row, start_col = byte_to_pos(bufnr, msg.start_byte)
+      local end_row, end_col = byte_to_pos(bufnr, msg.start_byte + msg.len)
+      vim.api.nvim_buf_set_text(bufnr, start_row, start_col, end_row, end_col, {})
+    elseif msg.kind == "replace" then
+      -- The buffer's implicit final newline is part of the text
+      local text = msg.text:gsub("\n$", "")
+      vim.api.nvim_buf_set_lines(bufnr, 0, -1, false, vim.split(text, "\n", { plain = true }))
+    elseif msg.kind == "renamed" then
+      vim.api.nvim_buf_set_name(bufnr, msg.text)
+    end
+  end)
+  applying_remote = false
+
+  if not ok then
+    vim.notify("[notek] failed to apply remote change: " .. err, vim.log.levels.WARN)
+  end
+end
+
+socket.on_message = apply_remote
+
 ---Attach on_bytes to a buffer if not already attached, and select it.
 ---@param bufnr integer
 function M.attach(bufnr)
@@ -61,12 +134,14 @@ function M.attach(bufnr)
       new_end_byte
     )
       if not attached[buf] then return true end -- returning true detaches
+      if applying_remote then return end -- the headless client already has it
 
       local conn = socket.get()
 
       -- Delete event
       if old_end_byte > 0 then
         conn:send(protocol.encode_delete(start_byte, old_end_byte))
+        conn.sent = conn.sent + 1
       end
 
       -- Insert event
@@ -81,6 +156,7 @@ function M.attach(bufnr)
         )
         local text = table.concat(lines, "\n")
         conn:send(protocol.encode_insert(start_byte, text))
+        conn.sent = conn.sent + 1
       end
     end,
 
@@ -5,6 +5,15 @@
 ---   Delete:  opcode=1  | u32 start_byte | u32 len
 ---   Start:   opcode=2  | u32 name_len   | document_name
 ---   Flush:   opcode=3
+---   Resync:  opcode=6
+---
+--- Messages pushed back by the headless client use their own opcodes:
+---   RemoteInsert: opcode=0 | u32 rev | u32 start_byte | u32 text_len | text
+---   RemoteDelete: opcode=1 | u32 rev | u32 start_byte | u32 len
+---   Replace:      opcode=2 | u32 rev | u32 text_len   | text
+---   Renamed:      opcode=3 | u32 name_len | document_name
+--- rev is how many inserts and deletes the headless client had applied since
+--- the document was selected, offsets don't account for any sent after those.
 local bit = require("bit")
 
 local M = {}
@@ -58,4 +67,68 @@ function M.encode_flush()
   return M.u8(3)
 end
 
+---Encode a request for the whole text of the document, sent when a pushed
+---change can't be applied.
+---@return string
+function M.encode_resync()
+  return M.u8(6)
+end
+
+---Decode an unsigned 32-bit little-endian integer.
+---@param data string
+---@param pos integer 1-based index of the first byte
+---@return integer
+function M.read_u32(data, pos)
+  local b1, b2, b3, b4 = data:byte(pos, pos + 3)
+  return b1 + b2 * 0x100 + b3 * 0x10000 + b4 * 0x1000000
+end
+
+---@class notek.DaemonMessage
+---@field kind "insert"|"delete"|"replace"|"renamed"
+---@field rev? integer
+---@field start_byte? integer
+---@field len? integer
+---@field text? string
+
+---Decode the first message pushed by the headless client.
+---Returns nil when `data` doesn't hold a whole message yet.
+---@param data string
+---@return notek.DaemonMessage?, integer? msg and the number of bytes it used
+function M.decode(data)
+  if #data < 1 then return nil end
+  local opcode = data:byte(1)
+
+  if opcode == 0 then
+    if #data < 13 then return nil end
+    local len = M.read_u32(data, 10)
+    if #data < 13 + len then return nil end
+    return {
+      kind = "insert",
+      rev = M.read_u32(data, 2),
+      start_byte = M.read_u32(data, 6),
+      text = data:sub(14, 13 + len),
+    }, 13 + len
+  elseif opcode == 1 then
+    if #data < 13 then return nil end
+    return {
+      kind = "delete",
+      rev = M.read_u32(data, 2),
+      start_byte = M.read_u32(data, 6),
+      len = M.read_u32(data, 10),
+    }, 13
+  elseif opcode == 2 then
+    if #data < 9 then return nil end
+    local len = M.read_u32(data, 6)
+    if #data < 9 + len then return nil end
+    return { kind = "replace", rev = M.read_u32(data, 2), text = data:sub(10, 9 + len) }, 9 + len
+  elseif opcode == 3 then
+    if #data < 5 then return nil end
+    local len = M.read_u32(data, 2)
+    if #data < 5 + len then return nil end
+    return { kind = "renamed", text = data:sub(6, 5 + len) }, 5 + len
+  end
+
+  error("[notek] unknown opcode from headless client: " .. opcode)
+end
+
 return M
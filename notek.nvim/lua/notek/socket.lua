@@ -10,6 +10,9 @@ local M = {}
 ---@field pipe uv_pipe_t
 ---@field connected boolean
 ---@field pending string[] Messages queued before the connection is ready
+---@field inbox string Bytes received that don't form a whole message yet
+---@field sent integer Inserts and deletes sent since the document was selected
+---@field resyncing boolean Whether the whole text was asked for and hasn't come yet
 local Connection = {}
 Connection.__index = Connection
 
@@ -21,6 +24,9 @@ function Connection.new(on_connect)
   self.pipe = vim.uv.new_pipe(false)
   self.connected = false
   self.pending = {}
+  self.inbox = ""
+  self.sent = 0
+  self.resyncing = false
 
   self.pipe:connect(config.values.socket_path, function(err)
     if err then
@@ -42,6 +48,9 @@ function Connection.new(on_connect)
       end)
     end
     self.pending = {}
+    self.pipe:read_start(function(read_err, data)
+      self:receive(read_err, data)
+    end)
     if on_connect then on_connect(nil) end
   end)
 
@@ -68,6 +77,27 @@ function Connection:send(data)
   end)
 end
 
+---Handle bytes pushed by the headless client, dispatching every whole message.
+---@param err? string
+---@param data? string
+function Connection:receive(err, data)
+  if err or not data then
+    -- EOF or error, the headless client went away
+    self.connected = false
+    return
+  end
+
+  self.inbox = self.inbox .. data
+  while true do
+    local msg, used = protocol.decode(self.inbox)
+    if not msg then break end
+    self.inbox = self.inbox:sub(used + 1)
+    vim.schedule(function()
+      if M.on_message then M.on_message(msg, self) end
+    end)
+  end
+end
+
 ---Flush and close the connection.
 function Connection:close()
   if self.pipe and not self.pipe:is_closing() then
@@ -86,6 +116,10 @@ end
 
 M.Connection = Connection
 
+--- Called on the main loop for every message the headless client pushes.
+---@type fun(msg: notek.DaemonMessage, conn: notek.Connection)?
+M.on_message = nil
+
 --- The single shared connection instance.
 ---@type notek.Connection?
 M.shared = nil
@@ -0,0 +1,142 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+/// Settings are looked up in order of precedence: command line flags,
+/// `NOTEK_*` environment variables, the TOML config file, built-in defaults.
+#[derive(Parser, Debug)]
+#[command(name = "server", about = "Sync and session server for notek")]
+struct Cli {
+    /// TOML file with any of the settings below, keys spelled with underscores
+    #[arg(short, long, env = "NOTEK_CONFIG")]
+    config: Option<PathBuf>,
+    /// Directory the documents and the server's own files live in [default: ./]
+    #[arg(long, env = "NOTEK_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Address to accept connections on [default: 0.0.0.0:9001]
+    #[arg(long, env = "NOTEK_LISTEN")]
+    listen: Option<String>,
+    /// Largest WebSocket message accepted from a client, in bytes [default: 64 MiB]
+    #[arg(long, env = "NOTEK_MAX_MESSAGE_SIZE")]
+    max_message_size: Option<usize>,
+    /// Largest WebSocket frame accepted from a client, in bytes [default: 16 MiB]
+    #[arg(long, env = "NOTEK_MAX_FRAME_SIZE")]
+    max_frame_size: Option<usize>,
+    /// How long connections get to close on shutdown, in seconds [default: 5]
+    #[arg(long, env = "NOTEK_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    data_dir: Option<PathBuf>,
+    listen: Option<String>,
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    shutdown_timeout: Option<u64>,
+}
+
+impl FileConfig {
+    fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {:?}", path))
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub listen: String,
+    pub max_message_size: usize,
+    pub max_frame_size: usize,
+    pub shutdown_timeout: Duration,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        Self::from_cli(Cli::parse())
+    }
+
+    fn from_cli(cli: Cli) -> Result<Self> {
+        let file = match &cli.config {
+            Some(path) => FileConfig::read(path)?,
+            None => FileConfig::default(),
+        };
+
+        Ok(Config {
+            data_dir: cli
+                .data_dir
+                .or(file.data_dir)
+                .unwrap_or_else(|| PathBuf::from("./")),
+            listen: cli
+                .listen
+                .or(file.listen)
+                .unwrap_or_else(|| "0.0.0.0:9001".to_string()),
+            max_message_size: cli
+                .max_message_size
+                .or(file.max_message_size)
+                .unwrap_or(64 << 20),
+            max_frame_size: cli
+                .max_frame_size
+                .or(file.max_frame_size)
+                .unwrap_or(16 << 20),
+            shutdown_timeout: Duration::from_secs(
+                cli.shutdown_timeout.or(file.shutdown_timeout).unwrap_or(5),
+            ),
+        })
+    }
+
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig::default()
+            .max_message_size(Some(self.max_message_size))
+            .max_frame_size(Some(self.max_frame_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn flags_beat_env_beats_file_beats_defaults() {
+        let path = env::temp_dir().join(format!("notek-server-{}.toml", Uuid::new_v4()));
+        let toml = r#"
+data_dir = "/file"
+listen = "file:1"
+max_frame_size = 3
+"#;
+        fs::write(&path, toml).unwrap();
+        // SAFETY: no other test in this crate reads NOTEK_* variables
+        unsafe {
+            env::set_var("NOTEK_LISTEN", "env:1");
+            env::set_var("NOTEK_MAX_FRAME_SIZE", "2");
+        }
+        let file = path.to_str().unwrap();
+        let cli = Cli::try_parse_from(["server", "-c", file, "--max-frame-size", "1"]).unwrap();
+        unsafe {
+            env::remove_var("NOTEK_LISTEN");
+            env::remove_var("NOTEK_MAX_FRAME_SIZE");
+        }
+
+        let config = Config::from_cli(cli).unwrap();
+        assert_eq!(config.max_frame_size, 1);
+        assert_eq!(config.listen, "env:1");
+        assert_eq!(config.data_dir, PathBuf::from("/file"));
+        assert_eq!(config.max_message_size, 64 << 20);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(5));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use algos::{pid::Pid, sync::DocOp};
+
+/// How many ops of a single document we remember before dropping the oldest.
+const MAX_HISTORY_OPS: usize = 10_000;
+
+pub fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Ops applied to a document on this server, oldest first, so that a client
+/// can be sent only what it hasn't seen yet.
+#[derive(Debug)]
+pub struct OpHistory {
+    ops: VecDeque<(u64, DocOp)>,
+    /// Nothing older than this is known anymore: either the history started
+    /// here (the doc got loaded or created) or older ops were dropped.
+    compacted_until: u64,
+}
+
+impl OpHistory {
+    pub fn new(started_at: u64) -> Self {
+        OpHistory {
+            ops: VecDeque::new(),
+            compacted_until: started_at,
+        }
+    }
+
+    pub fn record(&mut self, time: u64, op: DocOp) {
+        if self.ops.len() == MAX_HISTORY_OPS {
+            if let Some((dropped, _)) = self.ops.pop_front() {
+                // Syncing from `dropped` itself would miss the dropped op
+                self.compacted_until = dropped + 1;
+            }
+        }
+        self.ops.push_back((time, op));
+    }
+
+    /// Inserts and deletes made since `since`, or `None` when that reaches
+    /// further back than we remember and the client needs a full snapshot.
+    ///
+    /// Ops made in the very same millisecond as `since` are included, applying
+    /// an op twice is harmless.
+    pub fn since(&self, since: u64) -> Option<(Vec<(&Pid, char)>, Vec<&Pid>)> {
+        if since < self.compacted_until {
+            return None;
+        }
+        let start = self.ops.partition_point(|(t, _)| *t < since);
+        let mut inserts = Vec::new();
+        let mut deletes = Vec::new();
+        for (_, op) in self.ops.range(start..) {
+            match op {
+                DocOp::Insert(pid, c) => inserts.push((pid, *c)),
+                DocOp::Delete(pid) => deletes.push(pid),
+            }
+        }
+        Some((inserts, deletes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use algos::{
+        doc::{Doc, DocChar},
+        pos::Pos,
+        sync::{SyncReply, SyncResponses},
+    };
+
+    use super::*;
+
+    fn pid(ident: u32) -> Pid {
+        Pid(vec![Pos::new(ident, 2)])
+    }
+
+    /// Answers a SyncDoc from `since` the way the state manager does and decodes it.
+    fn answer(history: &OpHistory, doc: &Doc, since: u64) -> SyncReply {
+        let name = PathBuf::from("note.md");
+        let response = match history.since(since) {
+            Some((inserts, deletes)) => SyncResponses::SyncDocDelta {
+                document_id: 1,
+                name,
+                inserts,
+                deletes,
+                last_modified: 99,
+            },
+            None => SyncResponses::SyncDoc {
+                document_id: 1,
+                name,
+                doc,
+                last_modified: 99,
+            },
+        };
+        let mut buf = Vec::new();
+        response.serialize_into(&mut buf).unwrap();
+        SyncResponses::deserialize(&buf).unwrap()
+    }
+
+    #[test]
+    fn delta_survives_encoding() {
+        let mut history = OpHistory::new(10);
+        history.record(11, DocOp::Insert(pid(1), 'a'));
+        history.record(12, DocOp::Insert(pid(2), 'ż'));
+        history.record(13, DocOp::Delete(pid(1)));
+
+        let SyncReply::SyncDoc {
+            full,
+            inserts,
+            deletes,
+            last_modified,
+            ..
+        } = answer(&history, &Doc::new(""), 12)
+        else {
+            panic!("not a SyncDoc");
+        };
+        assert!(!full);
+        assert_eq!(inserts, vec![(pid(2), 'ż')]);
+        assert_eq!(deletes, vec![pid(1)]);
+        assert_eq!(last_modified, 99);
+    }
+
+    #[test]
+    fn compacted_history_falls_back_to_a_snapshot() {
+        let mut history = OpHistory::new(0);
+        let mut doc = Doc::new("");
+        for i in 0..=MAX_HISTORY_OPS as u32 {
+            history.record(u64::from(i) + 1, DocOp::Insert(pid(i + 1), 'x'));
+            doc.insert(pid(i + 1), DocChar('x'));
+        }
+        assert!(history.since(1).is_none());
+
+        let SyncReply::SyncDoc {
+            full,
+            inserts,
+            deletes,
+            ..
+        } = answer(&history, &doc, 1)
+        else {
+            panic!("not a SyncDoc");
+        };
+        assert!(full);
+        assert!(deletes.is_empty());
+        let expected: Vec<_> = doc.content.iter().map(|(p, c)| (p.clone(), c.0)).collect();
+        assert_eq!(inserts, expected);
+
+        // Still a delta from where the history reaches
+        let SyncReply::SyncDoc { full, inserts, .. } = answer(&history, &doc, 2) else {
+            panic!("not a SyncDoc");
+        };
+        assert!(!full);
+        assert_eq!(inserts.len(), MAX_HISTORY_OPS);
+    }
+}
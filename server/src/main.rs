@@ -1,37 +1,92 @@
-use std::path::PathBuf;
-
 use futures::StreamExt;
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinSet;
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        Message,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        protocol::WebSocketConfig,
+    },
+};
 
+use crate::config::Config;
 use crate::session::start_handling_session_requests;
+use crate::shutdown::Shutdown;
 use crate::state::{State, StateCommand};
 use crate::sync::start_handling_sync_requests;
+mod config;
+mod history;
+mod room;
 mod session;
+mod shutdown;
+mod sites;
 mod state;
 mod sync;
+mod tombstones;
+mod wal;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:9001").await?;
-    println!("Listening on 0.0.0.0:9001");
+    let config = Config::load()?;
+    let ws_config = config.websocket_config();
+
+    let listener = TcpListener::bind(&config.listen).await?;
+    println!("Listening on {} in {:?}", config.listen, config.data_dir);
 
     let (tx, rx) = mpsc::channel(100); // shared channel to state manager
-    tokio::spawn(async {
-        tokio::signal::ctrl_c().await.unwrap();
-        println!("Shutting down...");
-        std::process::exit(0);
-    });
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
-    tokio::spawn(async {
-        let mut state = State::init(PathBuf::from("./").as_path()).unwrap();
+    // Documents are named by their path relative to the data directory
+    let state = State::init(&config.data_dir)?;
+    let state_manager = tokio::spawn(async {
         state.run_state_manager(rx).await;
     });
 
-    while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(handle_connection(stream, tx.clone()));
+    let mut connections = JoinSet::new();
+    let signalled = shutdown::signalled();
+    tokio::pin!(signalled);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    connections.spawn(handle_connection(
+                        stream,
+                        tx.clone(),
+                        shutdown_rx.clone(),
+                        ws_config,
+                    ));
+                }
+                Err(e) => eprintln!("Failed to accept connection: {}", e),
+            },
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut signalled => break,
+        }
+    }
+
+    println!("Shutting down...");
+    drop(listener);
+    let _ = shutdown_tx.send(true);
+    // Closing sessions flush their documents, wait for them to hand that to the state
+    let drained = tokio::time::timeout(config.shutdown_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        eprintln!("Connections didn't close in time, dropping them");
+        connections.shutdown().await;
+    }
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    if tx.send(StateCommand::FlushAll { respond_to: resp_tx }).await.is_ok() {
+        let _ = resp_rx.await;
     }
+    drop(tx);
+    let _ = state_manager.await;
+    println!("Flushed everything, bye");
 
     Ok(())
 }
@@ -39,27 +94,55 @@ async fn main() -> anyhow::Result<()> {
 async fn handle_connection(
     stream: tokio::net::TcpStream,
     state_tx: mpsc::Sender<StateCommand>,
+    shutdown: Shutdown,
+    ws_config: WebSocketConfig,
 ) -> anyhow::Result<()> {
-    let ws = accept_async(stream).await?;
+    let ws = accept_hdr_async_with_config(stream, check_protocol_path, Some(ws_config)).await?;
     let (mut ws_sink, mut ws_stream) = ws.split();
 
     // Read the first message to determine connection type
     if let Some(msg) = ws_stream.next().await {
         let msg = msg?;
         if let Message::Binary(bin) = msg {
-            match bin[0] {
-                // Sync requests: first byte < 64 (tags 0-4)
-                0..64 => {
-                    start_handling_sync_requests(bin.to_vec(), state_tx, ws_sink, ws_stream)
-                        .await?;
+            match bin.first() {
+                None => eprintln!("Rejecting empty first frame"),
+                // Sync requests: first byte < 64 (tags 0-5)
+                Some(0..64) => {
+                    start_handling_sync_requests(
+                        bin.to_vec(),
+                        state_tx,
+                        ws_sink,
+                        ws_stream,
+                        shutdown,
+                    )
+                    .await?;
                 }
                 // Session requests: first byte >= 64 (tags 64+)
-                _ => {
-                    start_handling_session_requests(bin.to_vec(), state_tx, ws_sink, ws_stream)
-                        .await?;
+                Some(_) => {
+                    start_handling_session_requests(
+                        bin.to_vec(),
+                        state_tx,
+                        ws_sink,
+                        ws_stream,
+                        shutdown,
+                    )
+                    .await?;
                 }
             }
         }
     }
     Ok(())
 }
+
+/// Refuse clients speaking another wire protocol version than ours.
+fn check_protocol_path(req: &Request, resp: Response) -> Result<Response, ErrorResponse> {
+    if req.uri().path() == algos::PROTOCOL_PATH {
+        return Ok(resp);
+    }
+    let mut err = ErrorResponse::new(Some(format!(
+        "Unsupported protocol version, connect to {}",
+        algos::PROTOCOL_PATH
+    )));
+    *err.status_mut() = StatusCode::NOT_FOUND;
+    Err(err)
+}
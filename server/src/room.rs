@@ -0,0 +1,114 @@
+use algos::pos::SiteId;
+use tokio::sync::mpsc;
+
+/// What the state manager hands to a session task.
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// An already serialized session message to forward to the client
+    Frame(Vec<u8>),
+    /// The session can't go on, close it with the reason
+    Disconnect(String),
+}
+
+/// Outgoing half of a session's socket, as seen by the state manager.
+pub type SessionSink = mpsc::UnboundedSender<SessionEvent>;
+
+#[derive(Debug)]
+struct RoomMember {
+    site: SiteId,
+    tx: SessionSink,
+}
+
+/// All sessions currently editing the same document.
+#[derive(Debug, Default)]
+pub struct Room {
+    members: Vec<RoomMember>,
+}
+
+impl Room {
+    pub fn join(&mut self, site: SiteId, tx: SessionSink) {
+        self.members.push(RoomMember { site, tx });
+    }
+
+    pub fn leave(&mut self, site: SiteId) {
+        self.members.retain(|m| m.site != site);
+    }
+
+    pub fn has_site(&self, site: SiteId) -> bool {
+        self.members.iter().any(|m| m.site == site)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Send an already serialized session message to `site` only.
+    pub fn send_to(&self, site: SiteId, msg: &[u8]) {
+        if let Some(m) = self.members.iter().find(|m| m.site == site) {
+            let _ = m.tx.send(SessionEvent::Frame(msg.to_vec()));
+        }
+    }
+
+    /// Send an already serialized session message to everyone but `origin`.
+    /// Members whose session task is gone get dropped on the way.
+    pub fn broadcast(&mut self, origin: SiteId, msg: &[u8]) {
+        self.members
+            .retain(|m| m.site == origin || m.tx.send(SessionEvent::Frame(msg.to_vec())).is_ok());
+    }
+
+    /// Tell `site` its session is over, frames sent to it after this never go out.
+    pub fn disconnect(&self, site: SiteId, reason: &str) {
+        if let Some(m) = self.members.iter().find(|m| m.site == site) {
+            let _ = m.tx.send(SessionEvent::Disconnect(reason.to_string()));
+        }
+    }
+
+    /// Tell every member its session is over, the room is gone after this.
+    pub fn close(self, reason: &str) {
+        for m in self.members {
+            let _ = m.tx.send(SessionEvent::Disconnect(reason.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closing_disconnects_every_member() {
+        let mut room = Room::default();
+        let (tx_a, mut rx_a) = mpsc::unbounded_channel();
+        let (tx_b, mut rx_b) = mpsc::unbounded_channel();
+        room.join(2, tx_a);
+        room.join(3, tx_b);
+
+        room.broadcast(2, b"op");
+        room.close("Gone");
+
+        assert!(matches!(rx_b.try_recv(), Ok(SessionEvent::Frame(f)) if f == b"op"));
+        for rx in [&mut rx_a, &mut rx_b] {
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(SessionEvent::Disconnect(reason)) if reason == "Gone"
+            ));
+        }
+    }
+
+    #[test]
+    fn disconnecting_ends_only_that_member() {
+        let mut room = Room::default();
+        let (tx_a, mut rx_a) = mpsc::unbounded_channel();
+        let (tx_b, mut rx_b) = mpsc::unbounded_channel();
+        room.join(2, tx_a);
+        room.join(3, tx_b);
+
+        room.disconnect(2, "Broken");
+
+        assert!(matches!(
+            rx_a.try_recv(),
+            Ok(SessionEvent::Disconnect(reason)) if reason == "Broken"
+        ));
+        assert!(rx_b.try_recv().is_err());
+    }
+}
@@ -1,50 +1,97 @@
-use algos::{pid::Pid, session::SessionMessage, sync::DocOp};
+use algos::{
+    pid::Pid,
+    pos::SiteId,
+    session::{DELETED_REASON, SessionMessage},
+    sync::DocOp,
+};
 use futures::{SinkExt, StreamExt, stream::{SplitSink, SplitStream}};
-use rand::Rng;
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{
+        Message,
+        protocol::{CloseFrame, frame::coding::CloseCode},
+    },
+};
 
 use anyhow::anyhow;
-use crate::state::StateCommand;
+use crate::room::{SessionEvent, SessionSink};
+use crate::shutdown::{self, Shutdown};
+use crate::state::{DocStatus, StateCommand};
 
 pub async fn start_handling_session_requests(
     first_bin: Vec<u8>,
     state_tx: mpsc::Sender<StateCommand>,
     mut ws_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
     mut ws_stream: SplitStream<WebSocketStream<TcpStream>>,
+    mut shutdown: Shutdown,
 ) -> anyhow::Result<()> {
     if first_bin[0] != 64 {
         return Err(anyhow!("First session message should be a start!"));
     }
-    let mut session = SessionMember::init();
-    session
-        .handle_session_request(first_bin, &state_tx, &mut ws_sink)
-        .await?;
+    // Ops other participants make in the same document arrive here
+    let (room_tx, mut room_rx) = mpsc::unbounded_channel();
+    let mut session = SessionMember::init(room_tx);
 
-    while let Some(msg) = ws_stream.next().await {
-        let msg = msg?;
-        if let Message::Binary(bin) = msg {
-            session
-                .handle_session_request(bin.to_vec(), &state_tx, &mut ws_sink)
-                .await?;
+    let result = async {
+        session
+            .handle_session_request(first_bin, &state_tx, &mut ws_sink)
+            .await?;
+
+        loop {
+            tokio::select! {
+                msg = ws_stream.next() => {
+                    let Some(msg) = msg else { break };
+                    if let Message::Binary(bin) = msg? {
+                        session
+                            .handle_session_request(bin.to_vec(), &state_tx, &mut ws_sink)
+                            .await?;
+                    }
+                }
+                Some(event) = room_rx.recv() => match event {
+                    SessionEvent::Frame(bin) => ws_sink.send(Message::from(bin)).await?,
+                    SessionEvent::Disconnect(reason) => {
+                        ws_sink.send(closing_frame(&reason)).await?;
+                        return Err(anyhow!(reason));
+                    }
+                },
+                _ = shutdown::requested(&mut shutdown) => {
+                    ws_sink.send(shutdown::close_frame()).await?;
+                    break;
+                }
+            }
         }
+        anyhow::Ok(())
     }
+    .await;
+
     println!("Finished");
+    session.leave_room(&state_tx).await;
     session.flush_changes(&state_tx).await;
-    Ok(())
+    result
+}
+
+/// Ends a session the server can't continue, the reason tells the client why.
+fn closing_frame(reason: &str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.into(),
+    }))
 }
 
 pub struct SessionMember {
     document_id: u128,
-    connection_site_id: u8,
+    connection_site_id: SiteId,
+    room_tx: SessionSink,
 }
 
 impl SessionMember {
-    pub fn init() -> Self {
+    pub fn init(room_tx: SessionSink) -> Self {
         SessionMember {
             connection_site_id: 0,
             document_id: 0,
+            room_tx,
         }
     }
     pub async fn handle_session_request(
@@ -53,24 +100,48 @@ impl SessionMember {
         state_tx: &mpsc::Sender<StateCommand>,
         ws_sink: &mut SplitSink<WebSocketStream<TcpStream>, Message>,
     ) -> anyhow::Result<()> {
-        let req = SessionMessage::deserialize(&bin);
+        let req = match SessionMessage::deserialize(&bin) {
+            Ok(req) => req,
+            Err(e) => {
+                eprintln!("Rejecting malformed session frame: {}", e);
+                return Ok(());
+            }
+        };
 
         println!("{:#?}", req);
         match req {
             SessionMessage::Start {
                 document_id,
                 last_sync_time,
+                site,
                 name,
+                replica,
             } => {
                 if self.document_id != 0 {
+                    self.leave_room(state_tx).await;
                     let _ = state_tx
                         .send(StateCommand::FlushChanges {
                             document_id: self.document_id,
                         })
                         .await;
                 }
+                let (resp_tx, resp_rx) = oneshot::channel();
+                state_tx
+                    .send(StateCommand::CheckDoc {
+                        document_id,
+                        respond_to: resp_tx,
+                    })
+                    .await?;
+                let refusal = match resp_rx.await? {
+                    DocStatus::Tombstoned(_) => Some(DELETED_REASON),
+                    DocStatus::Unknown if name.is_none() => Some("No such document"),
+                    _ => None,
+                };
+                if let Some(reason) = refusal {
+                    ws_sink.send(closing_frame(reason)).await?;
+                    return Err(anyhow!("{}: {}", reason, document_id));
+                }
                 self.document_id = document_id;
-                self.connection_site_id = rand::rng().random_range(0..255);
                 if let Some(name) = name {
                     let _ = state_tx
                         .send(StateCommand::UpsertDoc {
@@ -79,22 +150,44 @@ impl SessionMember {
                         })
                         .await;
                 }
+                let (resp_tx, resp_rx) = oneshot::channel();
+                state_tx
+                    .send(StateCommand::JoinRoom {
+                        document_id: self.document_id,
+                        site,
+                        replica,
+                        tx: self.room_tx.clone(),
+                        respond_to: resp_tx,
+                    })
+                    .await?;
+                self.connection_site_id = resp_rx.await?.ok_or_else(|| {
+                    anyhow!("Can't join {}: unknown or out of sites", document_id)
+                })?;
+                let started = SessionMessage::Started {
+                    document_id,
+                    site: self.connection_site_id,
+                };
+                ws_sink.send(Message::from(started.serialize())).await?;
                 println!("started a sesh");
             }
-            SessionMessage::Insert { site, pid, c } => {
+            SessionMessage::Insert { seq, pid, c, .. } => {
                 let op = DocOp::Insert(pid, c);
                 let _ = state_tx
                     .send(StateCommand::UpdateDoc {
                         document_id: self.document_id,
+                        site: self.connection_site_id,
+                        seq,
                         op,
                     })
                     .await;
             }
-            SessionMessage::Delete { site, pid } => {
+            SessionMessage::Delete { seq, pid, .. } => {
                 let op = DocOp::Delete(pid);
                 let _ = state_tx
                     .send(StateCommand::UpdateDoc {
                         document_id: self.document_id,
+                        site: self.connection_site_id,
+                        seq,
                         op,
                     })
                     .await;
@@ -107,9 +200,20 @@ impl SessionMember {
                     })
                     .await;
             }
+            SessionMessage::Started { .. } | SessionMessage::Ack { .. } => {
+                return Err(anyhow!("Started and Ack are only ever sent by the server"));
+            }
         }
         Ok(())
     }
+    pub async fn leave_room(&self, state_tx: &mpsc::Sender<StateCommand>) {
+        let _ = state_tx
+            .send(StateCommand::LeaveRoom {
+                document_id: self.document_id,
+                site: self.connection_site_id,
+            })
+            .await;
+    }
     pub async fn flush_changes(&self, state_tx: &mpsc::Sender<StateCommand>) {
         let _ = state_tx
             .send(StateCommand::FlushChanges {
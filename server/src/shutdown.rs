@@ -0,0 +1,32 @@
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::{
+    Message,
+    protocol::{CloseFrame, frame::coding::CloseCode},
+};
+
+/// Flips to true once the server starts shutting down, every connection holds one.
+pub type Shutdown = watch::Receiver<bool>;
+
+/// Resolves on Ctrl-C or SIGTERM, the latter is what docker stop sends.
+pub async fn signalled() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+/// Resolves once shutdown starts. Dropping the sender counts as well.
+pub async fn requested(shutdown: &mut Shutdown) {
+    let _ = shutdown.wait_for(|&stopping| stopping).await;
+}
+
+/// Tells a client the server is going away, so it reconnects instead of
+/// treating it as a network error.
+pub fn close_frame() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Away,
+        reason: "Server shutting down".into(),
+    }))
+}
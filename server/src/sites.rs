@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use algos::pos::{FIRST_LEASED_SITE, SiteId, is_leased};
+use anyhow::{Context, Result, anyhow};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+const SITES_FILE: &str = ".notek.sites";
+const SITES_MAGIC: [u8; 4] = *b"NTKL";
+const SITES_VERSION: u8 = 3;
+
+/// Site identifiers the server handed out, per document.
+///
+/// Everything at or below the highest leased site has been given to some
+/// replica already, so new replicas always get a fresh one and two editors can
+/// never generate the same PIDs. Each site is bound to the replica it was
+/// leased to, only that replica gets it back.
+///
+/// File format:
+///   [u8; 4] magic - "NTKL"
+///   u8      version - 3
+///   repeated till EOF:
+///     u128 document_id
+///     u32  highest leased site
+///     u32  number of owned sites
+///     ⎧ u32  site
+///     ⎩ u128 replica it was leased to
+/// Version 2 has no owners, files without the magic are version 1, which also
+/// had a u8 site. Sites leased back then can't be reclaimed by anyone.
+#[derive(Debug)]
+pub struct SiteLeases {
+    path: PathBuf,
+    highest: HashMap<u128, SiteId>,
+    /// Replica each site was leased to, per document. Anonymous replicas own nothing.
+    owners: HashMap<u128, HashMap<SiteId, u128>>,
+}
+
+impl SiteLeases {
+    pub fn load(base_dir: &Path) -> Result<Self> {
+        let path = base_dir.join(SITES_FILE);
+        let mut highest = HashMap::new();
+        let mut owners: HashMap<u128, HashMap<SiteId, u128>> = HashMap::new();
+
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let (version, mut reader) = match bytes.strip_prefix(&SITES_MAGIC) {
+                    Some([version, rest @ ..]) => (*version, rest),
+                    Some([]) => return Err(anyhow!("Truncated site leases file")),
+                    None => (1, &bytes[..]),
+                };
+                if version > SITES_VERSION {
+                    return Err(anyhow!("Unsupported site leases version {}", version));
+                }
+                while !reader.is_empty() {
+                    let document_id = reader
+                        .read_u128::<LittleEndian>()
+                        .context("Truncated site leases file")?;
+                    let site = match version {
+                        1 => reader.read_u8().map(SiteId::from),
+                        _ => reader.read_u32::<LittleEndian>(),
+                    }
+                    .context("Truncated site leases file")?;
+                    highest.insert(document_id, site);
+                    if version < 3 {
+                        continue;
+                    }
+                    let owned = reader
+                        .read_u32::<LittleEndian>()
+                        .context("Truncated site leases file")?;
+                    for _ in 0..owned {
+                        let site = reader
+                            .read_u32::<LittleEndian>()
+                            .context("Truncated site leases file")?;
+                        let replica = reader
+                            .read_u128::<LittleEndian>()
+                            .context("Truncated site leases file")?;
+                        owners.entry(document_id).or_default().insert(site, replica);
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Failed to open site leases"),
+        }
+
+        Ok(SiteLeases {
+            path,
+            highest,
+            owners,
+        })
+    }
+
+    /// Give a site to `replica` joining `document_id`.
+    ///
+    /// A replica that already got a site from us asks for it again in
+    /// `requested`; it keeps it unless somebody connected is using it right
+    /// now. Failing that it gets back any other site leased to it that's free.
+    /// Anonymous replicas (0) always get a fresh one. Returns `None` once every
+    /// site of the document has been handed out.
+    pub fn lease(
+        &mut self,
+        document_id: u128,
+        replica: u128,
+        requested: SiteId,
+        in_use: impl Fn(SiteId) -> bool,
+    ) -> Option<SiteId> {
+        if replica != 0 {
+            let owned = self.owners.get(&document_id);
+            let free: Vec<SiteId> = owned
+                .into_iter()
+                .flatten()
+                .filter(|&(&site, &owner)| owner == replica && !in_use(site))
+                .map(|(&site, _)| site)
+                .collect();
+            if free.contains(&requested) {
+                return Some(requested);
+            }
+            if let Some(&site) = free.iter().min() {
+                return Some(site);
+            }
+        }
+
+        let highest = self
+            .highest
+            .get(&document_id)
+            .copied()
+            .unwrap_or(FIRST_LEASED_SITE - 1);
+        let site = highest.checked_add(1).filter(|&site| is_leased(site))?;
+        self.highest.insert(document_id, site);
+        if replica != 0 {
+            self.owners
+                .entry(document_id)
+                .or_default()
+                .insert(site, replica);
+        }
+        if let Err(e) = self.persist() {
+            eprintln!("Failed to persist site leases: {}", e);
+        }
+        Some(site)
+    }
+
+    fn persist(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("sites.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(&SITES_MAGIC)?;
+        writer.write_all(&[SITES_VERSION])?;
+        for (document_id, site) in &self.highest {
+            writer.write_all(&document_id.to_le_bytes())?;
+            writer.write_all(&site.to_le_bytes())?;
+            let owned = self.owners.get(document_id);
+            let count = owned.map_or(0, |owned| owned.len());
+            writer.write_all(&(count as u32).to_le_bytes())?;
+            for (site, replica) in owned.into_iter().flatten() {
+                writer.write_all(&site.to_le_bytes())?;
+                writer.write_all(&replica.to_le_bytes())?;
+            }
+        }
+        // A lease lost in a crash could be handed out a second time
+        writer.into_inner()?.sync_all()?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("notek-sites-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn leases_survive_a_reload() {
+        let dir = scratch_dir();
+        let mut leases = SiteLeases::load(&dir).unwrap();
+        assert_eq!(leases.lease(7, 0, 0, |_| false), Some(FIRST_LEASED_SITE));
+        assert_eq!(
+            leases.lease(7, 5, 0, |_| false),
+            Some(FIRST_LEASED_SITE + 1)
+        );
+
+        let mut leases = SiteLeases::load(&dir).unwrap();
+        assert_eq!(
+            leases.lease(7, 0, 0, |_| false),
+            Some(FIRST_LEASED_SITE + 2)
+        );
+        // The owner is remembered too
+        assert_eq!(
+            leases.lease(7, 5, 0, |_| false),
+            Some(FIRST_LEASED_SITE + 1)
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_owner_gets_a_site_back() {
+        let dir = scratch_dir();
+        let mut leases = SiteLeases::load(&dir).unwrap();
+        let mine = leases.lease(7, 5, 0, |_| false).unwrap();
+
+        // Asking for somebody else's site, or asking anonymously, gets a fresh one
+        assert_eq!(leases.lease(7, 6, mine, |_| false), Some(mine + 1));
+        assert_eq!(leases.lease(7, 0, mine, |_| false), Some(mine + 2));
+        // The owner gets it back even without asking for it
+        assert_eq!(leases.lease(7, 5, mine, |_| false), Some(mine));
+        assert_eq!(leases.lease(7, 5, 0, |_| false), Some(mine));
+        // Unless it's in use right now
+        assert_eq!(leases.lease(7, 5, mine, |s| s == mine), Some(mine + 3));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_version_1_files() {
+        let dir = scratch_dir();
+        let mut v1 = Vec::new();
+        v1.extend_from_slice(&7u128.to_le_bytes());
+        v1.push(200);
+        v1.extend_from_slice(&9u128.to_le_bytes());
+        v1.push(3);
+        fs::write(dir.join(SITES_FILE), v1).unwrap();
+
+        let mut leases = SiteLeases::load(&dir).unwrap();
+        assert_eq!(leases.highest[&7], 200);
+        assert_eq!(leases.highest[&9], 3);
+        // Nobody owns the old sites
+        assert_eq!(leases.lease(9, 5, 3, |_| false), Some(4));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_torn_record() {
+        let dir = scratch_dir();
+        let mut file = SITES_MAGIC.to_vec();
+        file.push(SITES_VERSION);
+        file.extend_from_slice(&7u128.to_le_bytes());
+        file.extend_from_slice(&[1, 0]);
+        fs::write(dir.join(SITES_FILE), file).unwrap();
+
+        assert!(SiteLeases::load(&dir).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,28 @@
 use std::{
-    collections::{BTreeMap, HashMap}, env, fs, path::{Path, PathBuf}
+    collections::{BTreeMap, HashMap, HashSet}, env, fs, path::{Component, Path, PathBuf}
 };
 
-use algos::{doc::Doc, structure::DocStructure, sync::{DocOp, DocSyncInfo, SyncResponses}};
-use anyhow::Result;
+use algos::{doc::Doc, pid::Strategy, pos::SiteId, session::{DELETED_REASON, SessionMessage}, structure::DocStructure, sync::{DocOp, DocSyncInfo, SyncResponses}};
+use anyhow::{Result, anyhow};
 use tokio::sync::{mpsc, oneshot};
 
+use crate::history::{OpHistory, timestamp_ms};
+use crate::room::{Room, SessionSink};
+use crate::sites::SiteLeases;
+use crate::tombstones::Tombstones;
+use crate::wal::Wal;
+
+/// Why a session gets closed when one of its ops couldn't be logged
+const UNLOGGED_REASON: &str = "Failed to log an op";
+
+/// Names come from clients, they have to stay a relative path to a note
+/// inside the data directory, like `school/math/note.md`.
+fn is_note_name(name: &Path) -> bool {
+    name.extension().and_then(|s| s.to_str()) == Some("md")
+        && name
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
 
 #[derive(Debug)]
 pub struct State {
@@ -13,12 +30,23 @@ pub struct State {
     pub base_dir: PathBuf,
     pub by_time: BTreeMap<u64, usize>,
     pub by_id: HashMap<u128, usize>,
+    pub rooms: HashMap<u128, Room>,
+    pub sites: SiteLeases,
+    pub histories: HashMap<u128, OpHistory>,
+    pub tombstones: Tombstones,
+    pub wal: Wal,
+    /// Documents with ops applied since their last flush
+    pub dirty: HashSet<u128>,
+    /// Last modification time handed out, see `tick`
+    pub clock: u64,
 }
 
 #[derive(Debug)]
 pub enum StateCommand {
-    GetSyncFullDoc {
+    GetSyncDoc {
         document_id: u128,
+        // Only ops after this get sent, if the history still reaches that far
+        last_sync_time: u64,
         // The state manager responds already with a serialized buffer
         respond_to: oneshot::Sender<Vec<u8>>,
     },
@@ -29,14 +57,47 @@ pub enum StateCommand {
     },
     UpdateDoc {
         document_id: u128,
+        // Site the op came from, it won't get the op echoed back
+        site: SiteId,
+        // Session sequence number of the op to ack to `site` once applied, 0 for none
+        seq: u64,
         op: DocOp,
     },
+    /// Ops of a sync upsert, responds whether every one of them got logged
+    UpsertOps {
+        document_id: u128,
+        ops: Vec<DocOp>,
+        respond_to: oneshot::Sender<bool>,
+    },
+    JoinRoom {
+        document_id: u128,
+        // Site the client had before, if any. Responds with the leased site,
+        // or None when the document doesn't exist or ran out of them.
+        site: SiteId,
+        // Installation the client runs on, the site gets bound to it
+        replica: u128,
+        tx: SessionSink,
+        respond_to: oneshot::Sender<Option<SiteId>>,
+    },
+    LeaveRoom {
+        document_id: u128,
+        site: SiteId,
+    },
     UpsertDoc {
         document_id: u128,
         name: PathBuf
     },
     DeleteDoc {
         document_id: u128,
+        // Site of the replica deleting it, kept in the tombstone
+        site: SiteId,
+    },
+    RestoreDoc {
+        document_id: u128,
+    },
+    CheckDoc {
+        document_id: u128,
+        respond_to: oneshot::Sender<DocStatus>,
     },
     ChangeName {
         document_id: u128,
@@ -45,6 +106,19 @@ pub enum StateCommand {
     FlushChanges {
         document_id: u128,
     },
+    /// Flush every document with unflushed changes, responds once they're on disk
+    FlushAll {
+        respond_to: oneshot::Sender<()>,
+    },
+}
+
+/// Whether a document can be edited, as far as the state manager knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocStatus {
+    Live,
+    /// Deleted at the given time
+    Tombstoned(u64),
+    Unknown,
 }
 
 impl State {
@@ -59,36 +133,113 @@ impl State {
 
         let mut s = State {
             docs: Vec::new(),
-            base_dir: base_dir,
+            base_dir: base_dir.clone(),
             by_time: BTreeMap::new(),
             by_id: HashMap::new(),
+            rooms: HashMap::new(),
+            sites: SiteLeases::load(&base_dir)?,
+            histories: HashMap::new(),
+            tombstones: Tombstones::load(&base_dir)?,
+            wal: Wal::open(&base_dir)?,
+            dirty: HashSet::new(),
+            clock: 0,
         };
+        s.clock = s.tombstones.latest();
 
-        for entry in fs::read_dir(&s.base_dir)? {
+        s.scan_dir_recursive(&s.base_dir.clone())?;
+        s.replay_wal()?;
+        Ok(s)
+    }
+
+    /// Recursively scan a directory for .md files and add them.
+    fn scan_dir_recursive(&mut self, dir: &Path) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
-            let path = path.strip_prefix(&s.base_dir).unwrap();
 
-            if path.extension().and_then(|s| s.to_str()) == Some("md") {
-                s.add_doc(path.to_path_buf(), None)?;
+            if path.is_dir() {
+                self.scan_dir_recursive(&path)?;
+            } else if path.extension().and_then(|s| s.to_str()) == Some("md") {
+                let rel = path.strip_prefix(&self.base_dir).unwrap();
+                self.add_doc(rel.to_path_buf(), None)?;
             }
         }
-        Ok(s)
+        Ok(())
+    }
+
+    /// Move a document, and its files, to `name`. Folders are created as needed.
+    pub fn rename_doc(&mut self, document_id: u128, name: &Path) -> Result<()> {
+        let Some(&idx) = self.by_id.get(&document_id) else {
+            return Err(anyhow!("Unknown doc {}", document_id));
+        };
+        if self.docs[idx].name == name {
+            return Ok(());
+        }
+        if self.docs.iter().any(|d| d.name == name) {
+            return Err(anyhow!("{:?} is taken by another doc", name));
+        }
+        self.docs[idx].set_name(name)?;
+        self.touch(document_id);
+        Ok(())
+    }
+
+    /// Apply what the WAL has on top of the snapshots, then snapshot those
+    /// documents so their WAL can go.
+    fn replay_wal(&mut self) -> Result<()> {
+        for ds in &mut self.docs {
+            let ops = self.wal.replay(ds.id)?;
+            if ops.is_empty() {
+                continue;
+            }
+            println!("Replaying {} ops of doc {} from the WAL", ops.len(), ds.id);
+            for op in ops {
+                ds.applyOp(op);
+            }
+            ds.flush()?;
+            self.wal.truncate(ds.id)?;
+        }
+        Ok(())
     }
 
     pub fn add_doc(&mut self, name: PathBuf, upsertid: Option<u128>) -> Result<()> {
-        let mut s = DocStructure::load_or_create(&name, upsertid)?;
+        // The server never generates PIDs, the strategy doesn't matter here
+        let mut s =
+            DocStructure::load_or_create(&self.base_dir, &name, upsertid, Strategy::default())?;
         let idx = self.docs.len();
         self.by_id.insert(s.id, idx);
-        if self.by_time.contains_key(&s.last_modified) {
+        while self.by_time.contains_key(&s.last_modified) {
             s.last_modified += 1;
         }
+        self.clock = self.clock.max(s.last_modified);
         self.by_time.insert(s.last_modified, idx);
+        // Whatever happened to the doc before now is only in the snapshot
+        let started_at = self.tick();
+        self.histories.insert(s.id, OpHistory::new(started_at));
         println!("{}", s.get_doc().to_string());
         self.docs.push(s);
         Ok(())
     }
 
+    /// Current time in ms, but always later than anything handed out before,
+    /// so modification times are unique and never go back with the wall clock.
+    pub fn tick(&mut self) -> u64 {
+        self.clock = timestamp_ms().max(self.clock + 1);
+        self.clock
+    }
+
+    /// Mark the document as modified now, keeping `by_time` in order.
+    pub fn touch(&mut self, document_id: u128) -> u64 {
+        let time = self.tick();
+        let Some(&idx) = self.by_id.get(&document_id) else {
+            return time;
+        };
+        let ds = &mut self.docs[idx];
+        self.by_time.remove(&ds.last_modified);
+        ds.last_modified = time;
+        self.by_time.insert(time, idx);
+        time
+    }
+
     // pub fn move_doc(&mut self, from: PathBuf, to: PathBuf) {
     //     if let Some(idx) = self.by_name.remove(&from) {
     //         if let Err(e) = self.docs[idx].update_name_after_external_rename(&to) {
@@ -97,29 +248,115 @@ impl State {
     //         self.by_name.insert(to, idx);
     //     }
     // }
-    pub fn get_doc(&self, document_id: u128) -> &Doc {
-        self.docs[self.by_id[&document_id]].get_doc()
+    /// Snapshot the document and drop the WAL the snapshot makes redundant.
+    fn flush_doc(&mut self, document_id: u128) {
+        let Some(&idx) = self.by_id.get(&document_id) else {
+            return;
+        };
+        if let Err(e) = self.docs[idx].flush() {
+            eprintln!("Failed to flush doc {}: {}", document_id, e);
+            return;
+        }
+        self.dirty.remove(&document_id);
+        if let Err(e) = self.wal.truncate(document_id) {
+            eprintln!("Failed to truncate WAL of doc {}: {}", document_id, e);
+        }
+    }
+
+    pub fn get_doc(&self, document_id: u128) -> Option<&Doc> {
+        Some(self.docs[*self.by_id.get(&document_id)?].get_doc())
         // self.docs.values().find(|d| d.id == document_id).unwrap().get_doc()
     }
 
-    pub fn get_structure(&mut self, document_id: u128) -> &mut DocStructure {
-        &mut self.docs[*self.by_id.get(&document_id).unwrap()]
+    pub fn get_structure(&mut self, document_id: u128) -> Option<&mut DocStructure> {
+        self.docs.get_mut(*self.by_id.get(&document_id)?)
         // self.docs.values().find(|d| d.id == document_id).unwrap().get_doc()
     }
 
+    /// Logs an op from `site` to the WAL, applies it and passes it on to the
+    /// rest of the room. Ops that can't be logged aren't applied, so they can't
+    /// get lost on a crash after being acked. Returns whether the op was applied.
+    fn apply_op(&mut self, document_id: u128, site: SiteId, op: DocOp) -> Result<bool> {
+        // Deleted in the meantime, the room and its sessions are gone with it
+        let Some(&idx) = self.by_id.get(&document_id) else {
+            eprintln!("Dropping op for unknown doc {}", document_id);
+            return Ok(false);
+        };
+        // Every replica without a lease stamps the same site, such
+        // PIDs get re-stamped before they're supposed to leave it
+        if op.pid().is_provisional() {
+            eprintln!("Dropping op with a provisional PID for doc {}", document_id);
+            return Ok(false);
+        }
+        let msg = match &op {
+            DocOp::Insert(pid, c) => SessionMessage::Insert {
+                site,
+                seq: 0,
+                pid: pid.clone(),
+                c: *c,
+            },
+            DocOp::Delete(pid) => SessionMessage::Delete {
+                site,
+                seq: 0,
+                pid: pid.clone(),
+            },
+        };
+        // Durable before it's acked, the snapshot only comes with the next flush
+        self.wal.append(document_id, &op)?;
+        self.dirty.insert(document_id);
+        let time = self.touch(document_id);
+        self.histories
+            .entry(document_id)
+            .or_insert_with(|| OpHistory::new(time))
+            .record(time, op.clone());
+        let ds = &mut self.docs[idx];
+        ds.applyOp(op);
+        println!("{:#?}", ds);
+        if let Some(room) = self.rooms.get_mut(&document_id) {
+            room.broadcast(site, &msg.serialize());
+        }
+        Ok(true)
+    }
+
     pub async fn run_state_manager(mut self, mut rx: mpsc::Receiver<StateCommand>) {
         while let Some(cmd) = rx.recv().await {
             println!("the cmd {:#?}", cmd);
             match cmd {
-                StateCommand::GetSyncFullDoc {
+                StateCommand::GetSyncDoc {
                     document_id,
+                    last_sync_time,
                     respond_to,
                 } => {
-                    let structure = self.get_structure(document_id);
-                    let r = SyncResponses::SyncDoc {
-                        document_id: document_id,
-                        name: structure.name.clone(),
-                        doc: structure.get_doc(),
+                    let r = match self.by_id.get(&document_id) {
+                        Some(&idx) => {
+                            let structure = &self.docs[idx];
+                            let delta = self
+                                .histories
+                                .get(&document_id)
+                                .and_then(|h| h.since(last_sync_time));
+                            match delta {
+                                Some((inserts, deletes)) => SyncResponses::SyncDocDelta {
+                                    document_id,
+                                    name: structure.name.clone(),
+                                    inserts,
+                                    deletes,
+                                    last_modified: structure.last_modified,
+                                },
+                                None => SyncResponses::SyncDoc {
+                                    document_id,
+                                    name: structure.name.clone(),
+                                    doc: structure.get_doc(),
+                                    last_modified: structure.last_modified,
+                                },
+                            }
+                        }
+                        None => match self.tombstones.get(document_id) {
+                            Some(t) => SyncResponses::Tombstoned {
+                                document_id,
+                                deleted_at: t.deleted_at,
+                            },
+                            None => SyncResponses::NoSuchDoc { document_id },
+                        },
                     };
                     let mut buf = Vec::new();
                     if let Err(e) = r.serialize_into(&mut buf) {
@@ -131,12 +368,17 @@ impl State {
                     last_sync_time,
                     respond_to,
                 } => {
-                    let docs = self
+                    let mut docs: Vec<DocSyncInfo> = self
                         .by_time
-                        .iter()
-                        // .filter(|&(&t, _)| t >= last_sync_time)
+                        .range(last_sync_time..)
                         .map(|(&t, &i)| DocSyncInfo::new(t, self.docs[i].id))
+                        .chain(
+                            self.tombstones
+                                .since(last_sync_time)
+                                .map(|(id, t)| DocSyncInfo::deleted(t.deleted_at, id)),
+                        )
                         .collect();
+                    docs.sort_by_key(|d| d.last_mod_time);
                     let r = SyncResponses::SyncList(docs);
                     println!("the synclist {:#?}", r);
                     let mut buf = Vec::new();
@@ -145,38 +387,141 @@ impl State {
                     }
                     let _ = respond_to.send(buf);
                 }
-                StateCommand::UpdateDoc { document_id, op } => {
-                    let ds = &mut self.docs[self.by_id[&document_id]];
-                    ds.applyOp(op);
-                    println!("{:#?}", ds)
+                StateCommand::UpdateDoc {
+                    document_id,
+                    site,
+                    seq,
+                    op,
+                } => match self.apply_op(document_id, site, op) {
+                    Ok(true) => {
+                        // Unacked ops stay with the client, which sends them again later
+                        if seq != 0
+                            && let Some(room) = self.rooms.get(&document_id)
+                        {
+                            room.send_to(site, &SessionMessage::Ack { seq }.serialize());
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        // Acks are cumulative, acking anything after this op would
+                        // ack the op too. The session ends before any of that goes out.
+                        eprintln!("Failed to log op for doc {}: {}", document_id, e);
+                        if let Some(room) = self.rooms.get(&document_id) {
+                            room.disconnect(site, UNLOGGED_REASON);
+                        }
+                    }
+                },
+                StateCommand::UpsertOps {
+                    document_id,
+                    ops,
+                    respond_to,
+                } => {
+                    let mut logged = true;
+                    for op in ops {
+                        if let Err(e) = self.apply_op(document_id, 0, op) {
+                            eprintln!("Failed to log upsert of doc {}: {}", document_id, e);
+                            logged = false;
+                            break;
+                        }
+                    }
+                    let _ = respond_to.send(logged);
+                }
+                StateCommand::JoinRoom {
+                    document_id,
+                    site,
+                    replica,
+                    tx,
+                    respond_to,
+                } => {
+                    if !self.by_id.contains_key(&document_id) {
+                        let _ = respond_to.send(None);
+                        continue;
+                    }
+                    let room = self.rooms.entry(document_id).or_default();
+                    let leased = self
+                        .sites
+                        .lease(document_id, replica, site, |s| room.has_site(s));
+                    if let Some(site) = leased {
+                        room.join(site, tx);
+                    } else if room.is_empty() {
+                        self.rooms.remove(&document_id);
+                    }
+                    let _ = respond_to.send(leased);
+                }
+                StateCommand::LeaveRoom { document_id, site } => {
+                    if let Some(room) = self.rooms.get_mut(&document_id) {
+                        room.leave(site);
+                        if room.is_empty() {
+                            self.rooms.remove(&document_id);
+                        }
+                    }
                 }
                 StateCommand::UpsertDoc { name, document_id } => {
-                    if self.by_id.get(&document_id).is_none() {
+                    if !is_note_name(&name) {
+                        eprintln!("Refusing upsert of doc {} as {:?}", document_id, name);
+                    } else if self.tombstones.get(document_id).is_some() {
+                        eprintln!("Refusing upsert of deleted doc {}", document_id);
+                    } else if self.by_id.get(&document_id).is_none() {
                         if let Err(e) = self.add_doc(name, Some(document_id)) {
                             eprintln!("Failed to upsert doc {}: {}", document_id, e);
+                            continue;
                         }
-                    } else {
-                        if let Err(e) = self.get_structure(document_id).update_name_after_external_rename(name.as_path()) {
-                            eprintln!("Failed to rename doc {}: {}", document_id, e);
-                        }
+                        self.touch(document_id);
+                    } else if let Err(e) = self.rename_doc(document_id, &name) {
+                        eprintln!("Failed to rename doc {}: {}", document_id, e);
                     }
                 }
                 StateCommand::ChangeName { document_id, name } => {
-                    if let Err(e) = self.get_structure(document_id).update_name_after_external_rename(name.as_path()) {
+                    if !is_note_name(&name) {
+                        eprintln!("Refusing to rename doc {} to {:?}", document_id, name);
+                    } else if let Err(e) = self.rename_doc(document_id, &name) {
                         eprintln!("Failed to change name for doc {}: {}", document_id, e);
                     }
                 }
                 StateCommand::FlushChanges { document_id } => {
                     println!("flushed!");
-                    if let Err(e) = self.get_structure(document_id).flush() {
-                        eprintln!("Failed to flush doc {}: {}", document_id, e);
+                    self.flush_doc(document_id);
+                }
+                StateCommand::FlushAll { respond_to } => {
+                    let dirty: Vec<u128> = self.dirty.iter().copied().collect();
+                    for document_id in dirty {
+                        self.flush_doc(document_id);
                     }
+                    let _ = respond_to.send(());
+                }
+                StateCommand::RestoreDoc { document_id } => {
+                    if !self.tombstones.restore(document_id) {
+                        eprintln!("Doc {} to restore wasn't deleted", document_id);
+                    }
+                }
+                StateCommand::CheckDoc {
+                    document_id,
+                    respond_to,
+                } => {
+                    let status = if self.by_id.contains_key(&document_id) {
+                        DocStatus::Live
+                    } else if let Some(t) = self.tombstones.get(document_id) {
+                        DocStatus::Tombstoned(t.deleted_at)
+                    } else {
+                        DocStatus::Unknown
+                    };
+                    let _ = respond_to.send(status);
                 }
-                StateCommand::DeleteDoc { document_id } => {
+                StateCommand::DeleteDoc { document_id, site } => {
                     if let Some(&idx) = self.by_id.get(&document_id) {
                         let removed_doc = self.docs.swap_remove(idx);
                         self.by_id.remove(&document_id);
+                        self.histories.remove(&document_id);
+                        self.dirty.remove(&document_id);
                         self.by_time.remove(&removed_doc.last_modified);
+                        let time = self.tick();
+                        self.tombstones.bury(document_id, time, site);
+                        if let Some(room) = self.rooms.remove(&document_id) {
+                            room.close(DELETED_REASON);
+                        }
+                        if let Err(e) = self.wal.truncate(document_id) {
+                            eprintln!("Failed to remove WAL of doc {}: {}", document_id, e);
+                        }
                         if let Err(e) = removed_doc.delete_files() {
                             eprintln!("Failed to delete files for doc {}: {}", document_id, e);
                         }
@@ -192,3 +537,30 @@ impl State {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn documents_stay_in_the_data_dir() {
+        let dir = env::temp_dir().join(format!("notek-state-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("school")).unwrap();
+        fs::write(dir.join("school/note.md"), "abc").unwrap();
+
+        let mut state = State::init(&dir).unwrap();
+        assert_eq!(state.docs.len(), 1);
+        assert_eq!(state.docs[0].name, PathBuf::from("school/note.md"));
+        assert!(dir.join("school/.note.md.structure").exists());
+
+        let document_id = state.docs[0].id;
+        let renamed = state.rename_doc(document_id, Path::new("math/note.md"));
+        renamed.unwrap();
+        assert_eq!(fs::read_to_string(dir.join("math/note.md")).unwrap(), "abc");
+        assert!(dir.join("math/.note.md.structure").exists());
+        assert!(!dir.join("school/note.md").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,24 +1,35 @@
-use algos::sync::{DocOp, SyncRequests};
+use algos::sync::{DocOp, SyncRequests, SyncResponses};
 use futures::{SinkExt, StreamExt, stream::{SplitSink, SplitStream}};
+use anyhow::anyhow;
 use std::io::Cursor;
 use tokio::net::TcpStream;
 use tokio::sync::{mpsc, oneshot};
 use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
 
-use crate::state::StateCommand;
+use crate::shutdown::{self, Shutdown};
+use crate::state::{DocStatus, StateCommand};
 
 pub async fn start_handling_sync_requests(
     first_bin: Vec<u8>,
     state_tx: mpsc::Sender<StateCommand>,
     mut ws_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
     mut ws_stream: SplitStream<WebSocketStream<TcpStream>>,
+    mut shutdown: Shutdown,
 ) -> anyhow::Result<()> {
     handle_sync_request(first_bin, &state_tx, &mut ws_sink).await?;
 
-    while let Some(msg) = ws_stream.next().await {
-        let msg = msg?;
-        if let Message::Binary(bin) = msg {
-            handle_sync_request(bin.to_vec(), &state_tx, &mut ws_sink).await?;
+    loop {
+        tokio::select! {
+            msg = ws_stream.next() => {
+                let Some(msg) = msg else { break };
+                if let Message::Binary(bin) = msg? {
+                    handle_sync_request(bin.to_vec(), &state_tx, &mut ws_sink).await?;
+                }
+            }
+            _ = shutdown::requested(&mut shutdown) => {
+                ws_sink.send(shutdown::close_frame()).await?;
+                break;
+            }
         }
     }
     Ok(())
@@ -29,7 +40,13 @@ async fn handle_sync_request(
     state_tx: &mpsc::Sender<StateCommand>,
     ws_sink: &mut SplitSink<WebSocketStream<TcpStream>, Message>,
 ) -> anyhow::Result<()> {
-    let req = SyncRequests::deserialize(Cursor::new(&bin))?;
+    let req = match SyncRequests::deserialize(Cursor::new(&bin)) {
+        Ok(req) => req,
+        Err(e) => {
+            eprintln!("Rejecting malformed sync frame: {}", e);
+            return Ok(());
+        }
+    };
 
     println!("{:#?}", req);
     match req {
@@ -50,8 +67,9 @@ async fn handle_sync_request(
         } => {
             let (resp_tx, resp_rx) = oneshot::channel();
             state_tx
-                .send(StateCommand::GetSyncFullDoc {
+                .send(StateCommand::GetSyncDoc {
                     document_id,
+                    last_sync_time,
                     respond_to: resp_tx,
                 })
                 .await?;
@@ -65,6 +83,15 @@ async fn handle_sync_request(
             inserts,
             deletes,
         } => {
+            // A deleted document comes back only through an explicit restore,
+            // and ops without a name can't create one
+            if let Some(r) = refuse_upsert(state_tx, document_id, name.is_some()).await? {
+                let mut buf = Vec::new();
+                r.serialize_into(&mut buf)?;
+                ws_sink.send(Message::from(buf)).await?;
+                return Ok(());
+            }
+
             // Upsert the document (create if missing, or update name)
             if let Some(name) = name {
                 state_tx
@@ -75,30 +102,38 @@ async fn handle_sync_request(
                     .await?;
             }
 
-            // Apply all inserts
-            for (pid, ch) in inserts {
-                state_tx
-                    .send(StateCommand::UpdateDoc {
-                        document_id,
-                        op: DocOp::Insert(pid, ch),
-                    })
-                    .await?;
-            }
-
-            // Apply all deletes
-            for pid in deletes {
-                state_tx
-                    .send(StateCommand::UpdateDoc {
-                        document_id,
-                        op: DocOp::Delete(pid),
-                    })
-                    .await?;
+            // Apply all inserts, then all deletes
+            let ops = inserts
+                .into_iter()
+                .map(|(pid, ch)| DocOp::Insert(pid, ch))
+                .chain(deletes.into_iter().map(DocOp::Delete))
+                .collect();
+            let (resp_tx, resp_rx) = oneshot::channel();
+            state_tx
+                .send(StateCommand::UpsertOps {
+                    document_id,
+                    ops,
+                    respond_to: resp_tx,
+                })
+                .await?;
+            // Without an ack the client keeps its ops and upserts them on reconnecting
+            if !resp_rx.await? {
+                return Err(anyhow!("Failed to log upsert of doc {}", document_id));
             }
 
             // Flush after applying all changes
             state_tx
                 .send(StateCommand::FlushChanges { document_id })
                 .await?;
+
+            // The client keeps its offline ops until it hears this, so it
+            // must not hear it if the document got deleted or never created
+            let r = refuse_upsert(state_tx, document_id, false)
+                .await?
+                .unwrap_or(SyncResponses::UpsertAcked { document_id });
+            let mut buf = Vec::new();
+            r.serialize_into(&mut buf)?;
+            ws_sink.send(Message::from(buf)).await?;
         }
         SyncRequests::DocNameChange { document_id, name } => {
             state_tx
@@ -108,11 +143,40 @@ async fn handle_sync_request(
                 })
                 .await?;
         }
-        SyncRequests::DeleteDoc { document_id } => {
+        SyncRequests::DeleteDoc { document_id, site } => {
             state_tx
-                .send(StateCommand::DeleteDoc { document_id })
+                .send(StateCommand::DeleteDoc { document_id, site })
+                .await?;
+        }
+        SyncRequests::RestoreDoc { document_id } => {
+            state_tx
+                .send(StateCommand::RestoreDoc { document_id })
                 .await?;
         }
     }
     Ok(())
 }
+
+/// The response refusing an upsert of `document_id`, None if it can go ahead.
+async fn refuse_upsert(
+    state_tx: &mpsc::Sender<StateCommand>,
+    document_id: u128,
+    creates: bool,
+) -> anyhow::Result<Option<SyncResponses<'static>>> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    state_tx
+        .send(StateCommand::CheckDoc {
+            document_id,
+            respond_to: resp_tx,
+        })
+        .await?;
+    Ok(match resp_rx.await? {
+        DocStatus::Live => None,
+        DocStatus::Tombstoned(deleted_at) => Some(SyncResponses::Tombstoned {
+            document_id,
+            deleted_at,
+        }),
+        DocStatus::Unknown if creates => None,
+        DocStatus::Unknown => Some(SyncResponses::NoSuchDoc { document_id }),
+    })
+}
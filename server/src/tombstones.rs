@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use algos::pos::SiteId;
+use anyhow::{Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+const TOMBSTONES_FILE: &str = ".notek.tombstones";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tombstone {
+    pub deleted_at: u64,
+    /// Site of the replica that deleted the document, 0 if it wasn't sent
+    pub site: SiteId,
+}
+
+/// Documents that got deleted, kept so that devices which were offline at the
+/// time learn about it instead of uploading the note again.
+///
+/// File format, repeated till EOF:
+///   u128 document_id
+///   u64  deleted_at
+///   u32  site
+#[derive(Debug)]
+pub struct Tombstones {
+    path: PathBuf,
+    by_id: HashMap<u128, Tombstone>,
+}
+
+impl Tombstones {
+    pub fn load(base_dir: &Path) -> Result<Self> {
+        let path = base_dir.join(TOMBSTONES_FILE);
+        let mut by_id = HashMap::new();
+
+        match File::open(&path) {
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                loop {
+                    let document_id = match reader.read_u128::<LittleEndian>() {
+                        Ok(id) => id,
+                        Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                        Err(e) => return Err(e).context("Failed to read tombstones"),
+                    };
+                    let deleted_at = reader.read_u64::<LittleEndian>().context("Truncated tombstones file")?;
+                    let site = reader.read_u32::<LittleEndian>().context("Truncated tombstones file")?;
+                    by_id.insert(document_id, Tombstone { deleted_at, site });
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Failed to open tombstones"),
+        }
+
+        Ok(Tombstones { path, by_id })
+    }
+
+    pub fn get(&self, document_id: u128) -> Option<&Tombstone> {
+        self.by_id.get(&document_id)
+    }
+
+    /// Latest deletion time of all the tombstones, for the modification clock.
+    pub fn latest(&self) -> u64 {
+        self.by_id.values().map(|t| t.deleted_at).max().unwrap_or(0)
+    }
+
+    /// Tombstones of documents deleted at or after `since`.
+    pub fn since(&self, since: u64) -> impl Iterator<Item = (u128, &Tombstone)> {
+        self.by_id
+            .iter()
+            .filter(move |(_, t)| t.deleted_at >= since)
+            .map(|(&id, t)| (id, t))
+    }
+
+    pub fn bury(&mut self, document_id: u128, deleted_at: u64, site: SiteId) {
+        self.by_id.insert(document_id, Tombstone { deleted_at, site });
+        if let Err(e) = self.persist() {
+            eprintln!("Failed to persist tombstones: {}", e);
+        }
+    }
+
+    /// Forget the document was deleted, returns whether it was.
+    pub fn restore(&mut self, document_id: u128) -> bool {
+        let restored = self.by_id.remove(&document_id).is_some();
+        if restored {
+            if let Err(e) = self.persist() {
+                eprintln!("Failed to persist tombstones: {}", e);
+            }
+        }
+        restored
+    }
+
+    fn persist(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("tombstones.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for (document_id, t) in &self.by_id {
+            writer.write_all(&document_id.to_le_bytes())?;
+            writer.write_all(&t.deleted_at.to_le_bytes())?;
+            writer.write_all(&t.site.to_le_bytes())?;
+        }
+        writer.flush()?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
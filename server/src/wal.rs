@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use algos::sync::DocOp;
+use anyhow::{Context, Result};
+
+const WAL_DIR: &str = ".notek.wal";
+
+/// Ops applied to documents since their last snapshot, so a crash doesn't lose
+/// what was only in memory. Every op is appended before it gets acked, the log
+/// of a document is emptied once its structure file has been flushed.
+///
+/// One file per document in `.notek.wal/`, named by the hex document id.
+/// File format, repeated till EOF:
+///   DocOp, as written by `DocOp::write_to`
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    /// Logs appended to since startup, kept open
+    files: HashMap<u128, File>,
+}
+
+impl Wal {
+    pub fn open(base_dir: &Path) -> Result<Self> {
+        let dir = base_dir.join(WAL_DIR);
+        fs::create_dir_all(&dir).context("Failed to create the WAL directory")?;
+        Ok(Wal {
+            dir,
+            files: HashMap::new(),
+        })
+    }
+
+    fn path(&self, document_id: u128) -> PathBuf {
+        self.dir.join(format!("{:032x}", document_id))
+    }
+
+    /// Ops logged for the document. A torn op at the end is what a crash
+    /// mid-append leaves, that one was never acked so it's dropped.
+    pub fn replay(&self, document_id: u128) -> Result<Vec<DocOp>> {
+        let file = match File::open(self.path(document_id)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("Failed to open WAL"),
+        };
+        let mut reader = BufReader::new(file);
+        let mut ops = Vec::new();
+        while let Ok(op) = DocOp::read_from(&mut reader) {
+            ops.push(op);
+        }
+        Ok(ops)
+    }
+
+    pub fn append(&mut self, document_id: u128, op: &DocOp) -> Result<()> {
+        let file = match self.files.get_mut(&document_id) {
+            Some(file) => file,
+            None => {
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.path(document_id))
+                    .context("Failed to open WAL")?;
+                self.files.entry(document_id).or_insert(file)
+            }
+        };
+        // One write per op, so a crash can only tear the last one
+        let mut buf = Vec::new();
+        op.write_to(&mut buf)?;
+        file.write_all(&buf).context("Failed to append to WAL")?;
+        // The op gets acked right after, it has to be on disk by then
+        file.sync_data().context("Failed to sync WAL")?;
+        Ok(())
+    }
+
+    /// Forget the document's ops, they are in its snapshot now.
+    pub fn truncate(&mut self, document_id: u128) -> Result<()> {
+        self.files.remove(&document_id);
+        match fs::remove_file(self.path(document_id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e).context("Failed to truncate WAL"),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use algos::pid::Pid;
+    use algos::pos::Pos;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn a_torn_last_record_is_dropped_on_replay() {
+        let dir = env::temp_dir().join(format!("notek-wal-{}", Uuid::new_v4()));
+        let mut wal = Wal::open(&dir).unwrap();
+        let insert = DocOp::Insert(Pid(vec![Pos::new(7, 2)]), 'ż');
+        let delete = DocOp::Delete(Pid(vec![Pos::new(7, 2)]));
+        wal.append(1, &insert).unwrap();
+        wal.append(1, &delete).unwrap();
+        assert_eq!(wal.replay(1).unwrap(), vec![insert.clone(), delete]);
+
+        // A crash in the middle of the second append
+        let len = fs::metadata(wal.path(1)).unwrap().len();
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(wal.path(1))
+            .unwrap();
+        file.set_len(len - 1).unwrap();
+        assert_eq!(wal.replay(1).unwrap(), vec![insert]);
+
+        wal.truncate(1).unwrap();
+        assert!(wal.replay(1).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -4,7 +4,7 @@ import time
 import uuid
 import websockets
 
-HOST = "ws://localhost:9001"
+HOST = "ws://localhost:9001/v2"
 
 SESSION_START = 64
 SESSION_INSERT = 65
@@ -1,11 +1,11 @@
-use algos::{doc::Doc, pid::Pid};
+use algos::{doc::Doc, pid::Pid, pos::SiteId};
 use crossterm::event::{KeyCode, KeyEvent};
 use tokio::sync::mpsc::{Sender, UnboundedSender};
 
 use crate::remote::RemoteEvent;
 
 pub enum AppEvent {
-    NewSession(u8, Doc),
+    NewSession(SiteId, Doc),
     CursorInsert(char),
     CursorDelete,
     CursorMove(isize),
@@ -44,7 +44,7 @@ pub fn interpret_key(key: KeyEvent) -> AppEvent {
     }
 }
 
-pub fn handle_event(ev : AppEvent, doc: &mut Doc, cursor: &mut Pid, rm_tx: &UnboundedSender<RemoteEvent>, site: &mut u8) -> bool {
+pub fn handle_event(ev : AppEvent, doc: &mut Doc, cursor: &mut Pid, rm_tx: &UnboundedSender<RemoteEvent>, site: &mut SiteId) -> bool {
     match ev {
         AppEvent::CursorInsert(c) => 
                 {
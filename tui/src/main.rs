@@ -20,7 +20,7 @@ mod remote;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let (ws, _) = connect_async("ws://127.0.0.1:9001").await?;
+    let (ws, _) = connect_async(format!("ws://127.0.0.1:9001{}", algos::PROTOCOL_PATH)).await?;
 
     let (mut ws_sink, mut ws_stream) = ws.split();
     // Send a message
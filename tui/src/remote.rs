@@ -1,6 +1,7 @@
 
 use algos::msg::PeerMessage;
 use algos::pid::Pid;
+use algos::pos::SiteId;
 use futures::{Sink, Stream};
 use futures::SinkExt;
 use tokio_tungstenite::tungstenite::{self, protocol::Message};
@@ -9,13 +10,19 @@ use crate::events::AppEvent;
 use futures::StreamExt;
 
 pub enum RemoteEvent {
-    InsertAt(u8, Pid, char),
-    DeleteAt(u8, Pid),
+    InsertAt(SiteId, Pid, char),
+    DeleteAt(SiteId, Pid),
 }
 
 // Handle an incoming WebSocket message and send an internal event
 pub async fn handle_incoming(bin: &[u8], ev_tx: &tokio::sync::mpsc::UnboundedSender<AppEvent>) {
-    let msg: PeerMessage = PeerMessage::deserialize(bin);
+    let msg: PeerMessage = match PeerMessage::deserialize(bin) {
+        Ok(msg) => msg,
+        Err(e) => {
+            eprintln!("Rejecting malformed peer frame: {}", e);
+            return;
+        }
+    };
     match msg {
         PeerMessage::Insert{site, pid, c} => {
                     let _ = ev_tx.send(AppEvent::InsertAt(pid, c));